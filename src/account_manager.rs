@@ -1,27 +1,47 @@
 use crate::balance;
 use crate::binance;
 use crate::config;
+use crate::execution;
 use crate::order;
+use crate::order_tracker;
+use crate::orderbook;
 use crate::position;
+use crate::pricing;
+use crate::sizing;
+use crate::state;
+use crate::stream_hub;
+use crate::supervisor;
 use crate::tradingpair;
 use crate::utils;
 
 use chrono;
 use log::{debug, error, info};
 use math::round;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
+use std::net::{Shutdown, TcpStream};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Barrier, Condvar, Mutex};
+use std::time::Instant;
 use std::{thread, time::Duration};
 use websocket::{stream::sync::NetworkStream, sync::Client, ClientBuilder, OwnedMessage};
 
 use balance::Balance;
 use binance::Binance;
 use config::ExchangeConfig;
+use execution::{ExecAlgo, ParentOrder};
+use order_tracker::{ExecutionReport, OrderTracker};
+use orderbook::OrderBooks;
 use position::{Position, PositionType};
+use pricing::{FillPolicy, LimitPrice, TimeoutAction};
+use sizing::PositionSizing;
+use state::SymbolState;
+use stream_hub::StreamHub;
+use supervisor::{LockExt, Supervisor};
 use tradingpair::TradingPair;
 
 #[derive(Debug, Clone)]
@@ -36,6 +56,8 @@ pub enum OrderQuantity {
     Exact(f64),
     #[allow(dead_code)]
     PercentageAmount(u8),
+    // Tranche n of a position built from this many equal buys.
+    Tranche(u8, u8),
     #[allow(dead_code)]
     Percentage25,
     #[allow(dead_code)]
@@ -45,25 +67,156 @@ pub enum OrderQuantity {
     Percentage100,
 }
 
+// Number of closed trades kept for position sizing and risk limits.
+static MAX_CLOSED_TRADES: usize = 1000;
+
+// How often the listen key is kept alive, Binance expires it after 60 minutes.
+static LISTEN_KEY_KEEPALIVE: Duration = Duration::from_secs(30 * 60);
+
+// Result of selling some or all of a position.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClosedTrade {
+    // Unix time in seconds.
+    pub time: i64,
+    // Realised PnL in the quote asset, after commission.
+    pub pnl: f64,
+    // PnL as a fraction of the cost of the quantity sold.
+    pub ret: f64,
+}
+
+// Parameters for the exit orders placed once a buy has filled.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct ExitParams {
+    // % Away from the average fill price to trigger the stop loss at.
+    pub stop_percent: Option<f64>,
+    // % Below the stop trigger price to set the stop loss limit price at.
+    pub stop_limit_percent: Option<f64>,
+    // Seconds a triggered stop loss may remain unfilled before it is replaced
+    // with a market sell.
+    pub stop_fill_timeout: Option<u64>,
+    // % Gain to place the take profit at.
+    pub take_profit_percent: Option<f64>,
+}
+
+// Exit parameters of each symbol, as sent with its latest order, falling back to those
+// configured. Every pair shares the account manager so they can't be held in common.
+#[derive(Debug, Clone)]
+struct SymbolExitParams {
+    configured: ExitParams,
+    by_symbol: HashMap<String, ExitParams>,
+}
+
+impl SymbolExitParams {
+    fn new(configured: ExitParams) -> SymbolExitParams {
+        SymbolExitParams {
+            configured: configured,
+            by_symbol: HashMap::new(),
+        }
+    }
+
+    fn get(&self, symbol: &str) -> ExitParams {
+        *self.by_symbol.get(symbol).unwrap_or(&self.configured)
+    }
+
+    fn set(&mut self, symbol: &str, exit_params: ExitParams) {
+        self.by_symbol.insert(symbol.to_string(), exit_params);
+    }
+}
+
 #[derive(Debug, Clone)]
 struct OrderMsg {
     tp: TradingPair,
     order_type: OrderType,
     position: PositionType,
     quantity: OrderQuantity,
-    limit_price: Option<f64>,
-    stop_percent: Option<f64>,
+    limit_price: Option<LimitPrice>,
+    // Entries expected to fill further than this from the mid price are not placed.
+    max_slippage_bps: Option<f64>,
+    exit_params: ExitParams,
+    sizing: PositionSizing,
+    // Most we may hold of the symbol, in the quote asset.
+    max_notional: Option<f64>,
+    // Set when this order replaces an unfilled limit order being chased.
+    chase: Option<Chase>,
+    // Id of the parent order this is part of, if worked by an execution algorithm.
+    parent: Option<u64>,
+    // Stop the order thread after cancelling our open orders, if position is Short
+    // our positions are also sold.
     quit: bool,
 }
 
+// Where a chased limit order started.
+#[derive(Debug, Clone, Copy)]
+struct Chase {
+    first_price: f64,
+    placed_at: Instant,
+    // Id of the order being replaced.
+    replaces: u64,
+}
+
+// A limit order we placed which is waiting to fill, watched by the chase thread.
+#[derive(Debug, Clone)]
+struct WorkingOrder {
+    client_order_id: String,
+    order_id: u64,
+    msg: OrderMsg,
+    price: f64,
+    qty: f64,
+    filled: f64,
+    chase: Chase,
+    repriced_at: Instant,
+}
+
+// A parent order being worked by an execution algorithm, children are copies of the
+// order message it came from.
+#[derive(Debug, Clone)]
+struct AlgoOrder {
+    parent: ParentOrder,
+    msg: OrderMsg,
+}
+
+// What to do at startup with holdings of a configured pair which we did not open, for
+// example the base asset was bought by hand or by a previous install.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExistingPositionPolicy {
+    // Treat the holding as our own position, protected by our exit orders.
+    Adopt,
+    // Leave the holding, and any orders on it, alone and never sell it.
+    Ignore,
+    // Cancel any open orders and sell the holding at market.
+    Flatten,
+}
+
+// A stop loss which has triggered and is resting on the book waiting to fill.
+#[derive(Debug)]
+struct TriggeredStop {
+    symbol: String,
+    triggered_at: Instant,
+    timeout: Duration,
+    // Quantity of the stop and how much of it has filled, from its execution reports.
+    qty: f64,
+    filled: f64,
+    qty_dps: i8,
+}
+
+// One account manager is shared by all pairs, clone it to hand it to another thread.
+#[derive(Clone)]
 pub struct AccountManager {
     tx_channel: mpsc::Sender<OrderMsg>,
     positions: Arc<Mutex<HashMap<String, Position>>>,
+    ad: Arc<Mutex<HashMap<String, Balance>>>,
+    closed_trades: Arc<Mutex<Vec<ClosedTrade>>>,
+    pairs: Vec<TradingPair>,
+    // Set to stop the event, stop watchdog, keepalive, chase and execution threads.
+    stop: Arc<AtomicBool>,
+    // The user data stream socket, shut down to wake the event thread.
+    user_stream: Arc<Mutex<Option<TcpStream>>>,
+    threads: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
 }
 
 impl AccountManager {
     pub fn get_position(&self, symbol: &str) -> Option<(PositionType, f64, f64)> {
-        let pos = self.positions.lock().unwrap();
+        let pos = self.positions.locked();
         match pos.get(symbol) {
             Some(p) => {
                 return Some((p.r#type, p.qty, p.price));
@@ -74,7 +227,100 @@ impl AccountManager {
         }
     }
 
-    pub fn exit(&self) {}
+    // Full details of the position held, including any scaling in or out.
+    pub fn get_position_detail(&self, symbol: &str) -> Option<Position> {
+        self.positions.locked().get(symbol).cloned()
+    }
+
+    pub fn open_positions(&self) -> usize {
+        self.positions.locked().len()
+    }
+
+    // Realised PnL of trades closed at or after 'since', unix time in seconds.
+    pub fn realised_pnl_since(&self, since: i64) -> f64 {
+        self.closed_trades
+            .locked()
+            .iter()
+            .filter(|t| t.time >= since)
+            .map(|t| t.pnl)
+            .sum()
+    }
+
+    // Trades closed recently, oldest first.
+    pub fn closed_trades(&self) -> Vec<ClosedTrade> {
+        self.closed_trades.locked().clone()
+    }
+
+    // Our balance of the quote asset plus what is tied up in positions bought with
+    // it, valued at the latest price in 'marks', or the price paid if we have none.
+    pub fn equity(&self, asset: &str, marks: &HashMap<String, f64>) -> f64 {
+        let balance = match self.ad.locked().get(asset) {
+            Some(b) => b.free + b.locked,
+            None => 0.0,
+        };
+        let in_use: f64 = self
+            .positions
+            .locked()
+            .iter()
+            .filter(|(symbol, _)| symbol.ends_with(asset))
+            .map(|(symbol, p)| p.qty * marks.get(symbol).unwrap_or(&p.price))
+            .sum();
+        balance + in_use
+    }
+
+    // Shut down, our open orders are cancelled and if 'flatten' is set our positions
+    // are sold at market. Returns once the user data stream is closed.
+    pub fn exit(&self, flatten: bool) {
+        let tp = match self.pairs.first() {
+            Some(tp) => tp.clone(),
+            None => return,
+        };
+
+        info!(
+            "shutting down account manager{}",
+            if flatten { ", selling positions" } else { "" }
+        );
+
+        self.submit_order(OrderMsg {
+            tp: tp,
+            order_type: OrderType::Market,
+            position: if flatten {
+                PositionType::Short
+            } else {
+                PositionType::None
+            },
+            quantity: OrderQuantity::Percentage100,
+            limit_price: None,
+            max_slippage_bps: None,
+            exit_params: ExitParams::default(),
+            sizing: PositionSizing::All,
+            max_notional: None,
+            chase: None,
+            parent: None,
+            quit: true,
+        });
+
+        // The order thread is first in the list.
+        let mut threads = self.threads.locked();
+        if !threads.is_empty() {
+            threads.remove(0).join().unwrap_or_default();
+        }
+
+        // Give the event thread a chance to record the sells.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while flatten && !self.positions.locked().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(250));
+        }
+
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(stream) = self.user_stream.locked().as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        for h in threads.drain(..) {
+            h.join().unwrap_or_default();
+        }
+    }
 }
 
 // Compute the cost of a trade in USDT.
@@ -114,19 +360,49 @@ fn compute_commision_usdt(
 fn order_thread(
     ec: ExchangeConfig,
     ad: Arc<Mutex<HashMap<String, Balance>>>,
-    rx_channel: mpsc::Receiver<OrderMsg>,
+    rx_channel: Arc<Mutex<mpsc::Receiver<OrderMsg>>>,
     event_cv: Arc<(Mutex<bool>, Condvar)>,
-    stop_percent: Arc<Mutex<Option<f64>>>,
+    exit_params: Arc<Mutex<SymbolExitParams>>,
+    reserved: Arc<Mutex<HashMap<String, f64>>>,
+    positions: Arc<Mutex<HashMap<String, Position>>>,
+    budgets: HashMap<String, f64>,
+    closed_trades: Arc<Mutex<Vec<ClosedTrade>>>,
+    pairs: Vec<TradingPair>,
+    books: OrderBooks,
+    working: Arc<Mutex<HashMap<String, WorkingOrder>>>,
+    fill_policy: FillPolicy,
+    parents: Arc<Mutex<HashMap<u64, AlgoOrder>>>,
+    algo: ExecAlgo,
     _margin: bool,
 ) {
     let bex = Binance::new(ec);
+    let rx_channel = rx_channel.locked();
+    // Full size of positions being built in tranches, fixed by the first of them.
+    let mut tranche_targets: HashMap<String, f64> = HashMap::new();
+    // Parent of the last order, if it was a child.
+    let mut child_of: Option<u64> = None;
 
     loop {
+        // The last child has been placed or given up on, either way the execution
+        // thread may work out what is due again.
+        if let Some(id) = child_of.take() {
+            if let Some(a) = parents.locked().get_mut(&id) {
+                a.parent.pending = None;
+            }
+        }
+
         debug!("waiting for message");
-        let msg = match rx_channel.recv() {
+        let mut msg = match rx_channel.recv() {
             Ok(msg) => {
                 if msg.quit {
                     info!("quit signal received, exiting");
+                    close_positions(
+                        &bex,
+                        &pairs,
+                        &positions,
+                        msg.position == PositionType::Short,
+                    );
+                    break;
                 }
                 msg
             }
@@ -135,14 +411,37 @@ fn order_thread(
                 continue;
             }
         };
+        child_of = msg.parent;
+
+        // Is this a new order to be worked as child orders over time.
+        let sliced = algo.slices_order() && msg.parent.is_none() && msg.chase.is_none();
 
         // If there are open orders on this symbol then cancel them
         // and re-queue this order from the event thread after the orders
-        // have been cancelled.
-        if let Ok(orders) = bex.get_open_orders(msg.tp.symbol()) {
+        // have been cancelled. A buy worked over time keeps the exits of the
+        // position until its first child.
+        let buy_sliced = sliced && msg.position == PositionType::Long;
+        if let (false, Ok(orders)) = (buy_sliced, bex.get_open_orders(msg.tp.symbol())) {
+            // Nothing to do if the order being chased has filled in the meantime.
+            if let Some(chase) = msg.chase {
+                let open = orders
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|o| o["orderId"].as_u64() == Some(chase.replaces));
+                if !open {
+                    info!(
+                        "order {} on {} no longer open, not replaced",
+                        chase.replaces,
+                        msg.tp.symbol()
+                    );
+                    continue;
+                }
+            }
+
             if orders.as_array().unwrap().len() > 0 {
                 let (lock, cvar) = &*event_cv;
-                let mut waiting = lock.lock().unwrap();
+                let mut waiting = lock.locked();
                 *waiting = true;
                 match bex.cancel_all_orders(msg.tp.symbol()) {
                     Ok(_) => {
@@ -151,7 +450,7 @@ fn order_thread(
                         while *waiting && retry < 4 {
                             waiting = cvar
                                 .wait_timeout(waiting, Duration::from_secs(5))
-                                .unwrap()
+                                .unwrap_or_else(|e| e.into_inner())
                                 .0;
                             retry += 1;
                         }
@@ -178,7 +477,7 @@ fn order_thread(
         } else {
             msg.tp.sell_currency()
         };
-        let (free, locked) = match ad.lock().unwrap().get_mut(asset) {
+        let (free, locked) = match ad.locked().get_mut(asset) {
             Some(balance) => (balance.free, balance.locked),
             None => {
                 info!("no local balance for {:?}", asset);
@@ -186,50 +485,164 @@ fn order_thread(
             }
         };
 
+        // Holdings we found at startup and were told to ignore are not ours to trade.
+        let free = match reserved.locked().get(asset) {
+            Some(r) => (free - r).max(0.0),
+            None => free,
+        };
+
+        // Our equity is the free quote asset plus what is tied up in positions bought
+        // with it, buys are limited to this pair's share of it.
+        let (equity, held) = {
+            let pm = positions.locked();
+            let in_use: f64 = pm
+                .iter()
+                .filter(|(symbol, _)| symbol.ends_with(asset))
+                .map(|(_, p)| p.qty * p.price)
+                .sum();
+            let held = match pm.get(msg.tp.symbol()) {
+                Some(p) => p.qty * p.price,
+                None => 0.0,
+            };
+            (free + in_use, held)
+        };
+        let free = match budgets.get(msg.tp.symbol()) {
+            Some(share) if msg.position == PositionType::Long => {
+                let budget = ((share * equity) - held).max(0.0);
+                debug!(
+                    "budget for {}: {:.2} of {:.2} {}",
+                    msg.tp.symbol(),
+                    budget,
+                    equity,
+                    asset
+                );
+                free.min(budget)
+            }
+            _ => free,
+        };
+
         debug!(
             "balance for {:?}: free: {:?} locked: {:?}",
             asset, free, locked
         );
 
         // Check the current or request price to see if we can actually trade
-        // this quantity.
-        let current_price = match msg.order_type {
-            OrderType::Limit => msg.limit_price.unwrap(),
-            OrderType::Market => match bex.get_price(msg.tp.symbol()) {
-                Ok(p) => p.price.parse::<f64>().unwrap(),
-                Err(code) => {
-                    error!("failed to get price of {:?}: {:?}", msg.tp, code);
+        // this quantity. Market orders use the top of the live book if we have one.
+        let top_of_book = books.get(msg.tp.symbol()).and_then(|b| {
+            if msg.position == PositionType::Long {
+                b.best_ask()
+            } else {
+                b.best_bid()
+            }
+        });
+        let book = books.get(msg.tp.symbol());
+        let limit_price = match msg.limit_price {
+            Some(LimitPrice::Fixed(price)) => Some(price),
+            Some(mode) => match book.as_ref().and_then(|b| {
+                pricing::book_price(
+                    mode,
+                    b,
+                    msg.position == PositionType::Long,
+                    msg.tp.get_tick_size(),
+                    msg.tp.get_price_dps(),
+                )
+            }) {
+                Some(price) => Some(price),
+                None => {
+                    error!(
+                        "no order book to price {:?} limit order on {}",
+                        mode,
+                        msg.tp.symbol()
+                    );
                     continue;
                 }
             },
+            None => None,
+        };
+        let current_price = match msg.order_type {
+            OrderType::Limit => limit_price.unwrap(),
+            OrderType::Market => match top_of_book {
+                Some((price, _)) => price,
+                None => match bex.get_price(msg.tp.symbol()) {
+                    Ok(p) => p.price.parse::<f64>().unwrap(),
+                    Err(code) => {
+                        error!("failed to get price of {:?}: {:?}", msg.tp, code);
+                        continue;
+                    }
+                },
+            },
         };
 
         // Get the amount of the asset we want to trade.
         let max_qty = if msg.position == PositionType::Long {
+            // How much do we want to spend?
+            let spend = match sizing::quote_amount(
+                &bex,
+                &msg.sizing,
+                msg.tp.symbol(),
+                equity,
+                current_price,
+                msg.exit_params.stop_percent,
+                &closed_trades
+                    .locked()
+                    .iter()
+                    .map(|t| t.ret)
+                    .collect::<Vec<f64>>(),
+            ) {
+                Some(spend) => spend,
+                None => {
+                    error!(
+                        "unable to size {} position using {:?}",
+                        msg.tp.symbol(),
+                        msg.sizing
+                    );
+                    continue;
+                }
+            };
+
+            // Don't go over the most we may hold of the symbol.
+            let spend = match msg.max_notional {
+                Some(max_notional) => spend.min((max_notional - held).max(0.0)),
+                None => spend,
+            };
+
             // How many can we buy?
-            free / current_price
+            spend.min(free) / current_price
         } else {
             // What do we have to sell?
             free
         };
         let requested_qty = round::floor(
-            if msg.position == PositionType::Long {
-                // What percentage of our spend assets do we want to use?
-                match msg.quantity {
-                    OrderQuantity::Exact(q) => q,
-                    OrderQuantity::PercentageAmount(q) => {
-                        assert!(q <= 100);
-                        max_qty * (q as f64 / 100.0)
+            // What percentage of our spend assets, or of what we hold, do we want to
+            // use? Sells are for everything unless we are scaling out.
+            match msg.quantity {
+                OrderQuantity::Exact(q) => q.min(max_qty),
+                OrderQuantity::PercentageAmount(q) => {
+                    assert!(q <= 100);
+                    max_qty * (q as f64 / 100.0)
+                }
+                OrderQuantity::Tranche(n, of) => {
+                    let symbol = msg.tp.symbol();
+                    let held_qty = positions.locked().get(symbol).map(|p| p.qty).unwrap_or(0.0);
+                    if n <= 1 {
+                        tranche_targets.insert(symbol.to_string(), max_qty);
                     }
-                    OrderQuantity::Percentage100 => max_qty,
-                    OrderQuantity::Percentage75 => max_qty * (3.0 / 4.0),
-                    OrderQuantity::Percentage50 => max_qty * (1.0 / 2.0),
-                    OrderQuantity::Percentage25 => max_qty * (1.0 / 4.0),
+                    // The tranches so far were equal if we lost the target on a restart.
+                    let target = *tranche_targets
+                        .entry(symbol.to_string())
+                        .or_insert(held_qty * (of as f64 / (n.max(2) - 1) as f64));
+                    // The last tranche takes whatever rounding left over.
+                    let want = if n >= of {
+                        (target - held_qty).max(0.0)
+                    } else {
+                        target / of as f64
+                    };
+                    want.min(max_qty)
                 }
-            } else {
-                // Always sell all.
-                // TODO: If we sell first then we'll ignore the percentage stuff, so our first
-                max_qty
+                OrderQuantity::Percentage100 => max_qty,
+                OrderQuantity::Percentage75 => max_qty * (3.0 / 4.0),
+                OrderQuantity::Percentage50 => max_qty * (1.0 / 2.0),
+                OrderQuantity::Percentage25 => max_qty * (1.0 / 4.0),
             },
             msg.tp.get_qty_dps(),
         );
@@ -244,9 +657,98 @@ fn order_thread(
             continue;
         }
 
-        let mut stop_pct = stop_percent.lock().unwrap();
-        if msg.stop_percent.is_some() {
-            *stop_pct = Some(msg.stop_percent.unwrap());
+        // Check how far from the mid price we expect to fill given the depth of the
+        // book. Exits always go ahead.
+        if let (Some(book), Some(limit), Some(max)) = (&book, limit_price, msg.max_slippage_bps) {
+            let buy = msg.position == PositionType::Long;
+            if let Some(slippage) = pricing::slippage_bps(book, buy, requested_qty, limit) {
+                debug!(
+                    "{} expected slippage {:.1}bps at {}",
+                    msg.tp.symbol(),
+                    slippage,
+                    limit
+                );
+                if slippage > max {
+                    if buy {
+                        info!(
+                            "{} entry not placed, expected slippage {:.1}bps > {:.1}bps",
+                            msg.tp.symbol(),
+                            slippage,
+                            max
+                        );
+                        continue;
+                    }
+                    info!(
+                        "{} exit expected slippage {:.1}bps > {:.1}bps",
+                        msg.tp.symbol(),
+                        slippage,
+                        max
+                    );
+                }
+            }
+        }
+
+        exit_params.locked().set(msg.tp.symbol(), msg.exit_params);
+
+        let side = if msg.position == PositionType::Long {
+            "BUY"
+        } else {
+            "SELL"
+        };
+
+        // Hand the order to the execution thread to be worked as child orders.
+        if sliced {
+            let parent = ParentOrder::new(
+                msg.tp.symbol(),
+                msg.position == PositionType::Long,
+                requested_qty,
+                algo,
+            );
+            info!(
+                "working {} {} {} as parent order {} using {:?}",
+                side,
+                requested_qty,
+                msg.tp.symbol(),
+                parent.id,
+                algo
+            );
+            parents.locked().insert(
+                parent.id,
+                AlgoOrder {
+                    parent: parent,
+                    msg: msg.clone(),
+                },
+            );
+            continue;
+        }
+
+        // Limit orders may only show part of their quantity on the book.
+        let iceberg_qty = match (algo, limit_price) {
+            (ExecAlgo::Iceberg { parts }, Some(_)) => execution::iceberg_qty(
+                requested_qty,
+                parts,
+                msg.tp.get_iceberg_parts(),
+                msg.tp.get_qty_dps(),
+            ),
+            _ => None,
+        };
+        if iceberg_qty.is_some() && msg.parent.is_none() {
+            let mut parent = ParentOrder::new(
+                msg.tp.symbol(),
+                msg.position == PositionType::Long,
+                requested_qty,
+                algo,
+            );
+            parent.pending = Some(requested_qty);
+            msg.parent = Some(parent.id);
+            child_of = msg.parent;
+            parents.locked().insert(
+                parent.id,
+                AlgoOrder {
+                    parent: parent,
+                    msg: msg.clone(),
+                },
+            );
         }
 
         match order::place_order_quantity(
@@ -254,19 +756,42 @@ fn order_thread(
             msg.position,
             &msg.tp,
             requested_qty,
-            msg.limit_price,
+            limit_price,
+            iceberg_qty,
         ) {
             Ok(ack) => {
                 info!(
                     "submitted {} order with id {} for {}",
-                    if msg.position == PositionType::Long {
-                        "BUY"
-                    } else {
-                        "SELL"
-                    },
-                    ack.orderId,
-                    ack.symbol
+                    side, ack.orderId, ack.symbol
                 );
+
+                // Add up the fills of the children of a parent order.
+                if let Some(id) = msg.parent {
+                    if let Some(a) = parents.locked().get_mut(&id) {
+                        a.parent.add_child(&ack.clientOrderId, requested_qty);
+                    }
+                }
+
+                // Watch limit orders until they fill.
+                if let (Some(price), true) = (limit_price, fill_policy.active()) {
+                    working.locked().insert(
+                        ack.clientOrderId.to_string(),
+                        WorkingOrder {
+                            client_order_id: ack.clientOrderId.to_string(),
+                            order_id: ack.orderId as u64,
+                            msg: msg.clone(),
+                            price: price,
+                            qty: requested_qty,
+                            filled: 0.0,
+                            chase: msg.chase.unwrap_or(Chase {
+                                first_price: price,
+                                placed_at: Instant::now(),
+                                replaces: 0,
+                            }),
+                            repriced_at: Instant::now(),
+                        },
+                    );
+                }
             }
             Err(code) => {
                 error!("failed to place order: {:?} {:?}", code, msg);
@@ -275,30 +800,822 @@ fn order_thread(
     }
 }
 
-// Submit a stop loss sell order at the current price - 'stop_percent' or the current price.
-fn submit_stop_order(
+// Cancel the open orders we placed and, if 'flatten' is set, sell our positions at
+// market. Used when shutting down.
+fn close_positions(
     bex: &Binance,
+    pairs: &[TradingPair],
+    positions: &Arc<Mutex<HashMap<String, Position>>>,
+    flatten: bool,
+) {
+    for tp in pairs {
+        let orders = match bex.get_open_orders(tp.symbol()) {
+            Ok(orders) => orders,
+            Err(code) => {
+                error!("failed to get open orders on {}: {}", tp.symbol(), code);
+                continue;
+            }
+        };
+
+        // Orders placed by hand, or by anything else, are left alone.
+        for o in orders.as_array().unwrap() {
+            let ours = o["clientOrderId"]
+                .as_str()
+                .map(|cid| cid.starts_with("ct_"))
+                .unwrap_or(false);
+            if !ours {
+                continue;
+            }
+
+            let order_id = o["orderId"].as_u64().unwrap();
+            match bex.cancel_order(tp.symbol(), order_id) {
+                Ok(_) => info!("cancelled order {} on {}", order_id, tp.symbol()),
+                Err(code) => error!(
+                    "failed to cancel order {} on {}: {}",
+                    order_id,
+                    tp.symbol(),
+                    code
+                ),
+            }
+        }
+    }
+
+    if !flatten {
+        return;
+    }
+
+    // Balances come from the exchange, the cancellations may not have been reported
+    // on the user data stream yet.
+    let account = match bex.get_account_data() {
+        Ok(account) => account,
+        Err(code) => {
+            error!("failed to get account data, can't sell positions: {}", code);
+            return;
+        }
+    };
+
+    let held = positions.locked().clone();
+    for tp in pairs {
+        let pos = match held.get(tp.symbol()) {
+            Some(pos) => pos,
+            None => continue,
+        };
+
+        let free = account
+            .balances
+            .iter()
+            .find(|b| b.asset.eq(tp.sell_currency()))
+            .map(|b| b.free)
+            .unwrap_or(0.0);
+        let qty = round::floor(pos.qty.min(free), tp.get_qty_dps());
+        if qty * pos.price < tp.get_min_notional() {
+            info!("{} position of {} is too small to sell", tp.symbol(), qty);
+            continue;
+        }
+
+        match order::place_market_sell(bex, tp.symbol(), qty) {
+            Ok(ack) => info!(
+                "submitted market sell {} for {} of {}",
+                ack.orderId,
+                qty,
+                tp.symbol()
+            ),
+            Err(code) => error!("failed to sell {} of {}: {}", qty, tp.symbol(), code),
+        }
+    }
+}
+
+// Compute the stop loss trigger and limit prices, the trigger is a percentage delta from
+// the price we paid and the limit is optionally a further percentage below the trigger.
+fn stop_prices(
     stop_percent: f64,
+    stop_limit_percent: Option<f64>,
     price_paid: f64,
     price_dps: u8,
-    qty: f64,
-    symbol: &str,
-) {
-    // Stop trigger price is a percentage delta from the price we paid.
+) -> (f64, f64) {
     let stop_trigger_price = round::floor(
         price_paid - ((price_paid * stop_percent) / 100.0),
         price_dps as i8,
     );
-    let stop_limit_price = stop_trigger_price;
+    let stop_limit_price = match stop_limit_percent {
+        Some(slp) => round::floor(
+            stop_trigger_price - ((stop_trigger_price * slp) / 100.0),
+            price_dps as i8,
+        ),
+        None => stop_trigger_price,
+    };
+
+    (stop_trigger_price, stop_limit_price)
+}
+
+// Submit a stop loss sell order at the current price - 'stop_percent' or the current price.
+fn submit_stop_order(
+    bex: &Binance,
+    stop_percent: f64,
+    stop_limit_percent: Option<f64>,
+    price_paid: f64,
+    price_dps: u8,
+    qty: f64,
+    symbol: &str,
+) -> Vec<u64> {
+    let (stop_trigger_price, stop_limit_price) =
+        stop_prices(stop_percent, stop_limit_percent, price_paid, price_dps);
     match order::place_stop_limit(&bex, symbol, qty, stop_trigger_price, stop_limit_price) {
         Ok(ack) => {
             info!(
                 "submitted stop loss order of {} {} @ {:.*} with id {} for {}",
                 qty, symbol, price_dps as usize, stop_trigger_price, ack.orderId, ack.symbol
             );
+            vec![ack.orderId as u64]
         }
         Err(code) => {
             error!("failed to submit stop loss: {}", code);
+            Vec::new()
+        }
+    }
+}
+
+// Submit an OCO sell order list made up of a take profit at the price we paid + 'take_profit_percent'
+// and a stop loss at the price we paid - 'stop_percent'. Falls back to a plain stop loss if the
+// exchange rejects the order list, for example because the take profit price would immediately
+// match.
+fn submit_oco_order(
+    bex: &Binance,
+    stop_percent: f64,
+    stop_limit_percent: Option<f64>,
+    take_profit_percent: f64,
+    price_paid: f64,
+    price_dps: u8,
+    qty: f64,
+    symbol: &str,
+) -> Vec<u64> {
+    let (stop_trigger_price, stop_limit_price) =
+        stop_prices(stop_percent, stop_limit_percent, price_paid, price_dps);
+    let take_profit_price = round::ceil(
+        price_paid + ((price_paid * take_profit_percent) / 100.0),
+        price_dps as i8,
+    );
+    match order::place_oco_sell(
+        &bex,
+        symbol,
+        qty,
+        take_profit_price,
+        stop_trigger_price,
+        stop_limit_price,
+    ) {
+        Ok(ack) => {
+            info!(
+                "submitted oco order list {} of {} {}, take profit @ {:.*}, stop loss @ {:.*}",
+                ack.orderListId,
+                qty,
+                ack.symbol,
+                price_dps as usize,
+                take_profit_price,
+                price_dps as usize,
+                stop_trigger_price,
+            );
+            ack.orders.iter().map(|o| o.orderId as u64).collect()
+        }
+        Err(code) => {
+            error!(
+                "failed to submit oco order: {}, falling back to stop loss",
+                code
+            );
+            submit_stop_order(
+                bex,
+                stop_percent,
+                stop_limit_percent,
+                price_paid,
+                price_dps,
+                qty,
+                symbol,
+            )
+        }
+    }
+}
+
+// Place whichever exit orders are configured for a position we hold, returns the ids of
+// the orders placed.
+fn submit_exit_orders(
+    bex: &Binance,
+    ep: &ExitParams,
+    price_paid: f64,
+    price_dps: u8,
+    qty: f64,
+    symbol: &str,
+) -> Vec<u64> {
+    if ep.stop_percent.is_some() && ep.take_profit_percent.is_some() {
+        submit_oco_order(
+            bex,
+            ep.stop_percent.unwrap(),
+            ep.stop_limit_percent,
+            ep.take_profit_percent.unwrap(),
+            price_paid,
+            price_dps,
+            qty,
+            symbol,
+        )
+    } else if ep.stop_percent.is_some() {
+        submit_stop_order(
+            bex,
+            ep.stop_percent.unwrap(),
+            ep.stop_limit_percent,
+            price_paid,
+            price_dps,
+            qty,
+            symbol,
+        )
+    } else {
+        Vec::new()
+    }
+}
+
+// Bring the saved state of a symbol up to date with the exchange, while we were not
+// running the exit orders may have filled or been cancelled and the position may have
+// been sold.
+fn reconcile_state(
+    bex: &Binance,
+    tp: &TradingPair,
+    st: &mut SymbolState,
+    ad: &HashMap<String, Balance>,
+    policy: ExistingPositionPolicy,
+    reserved: &mut HashMap<String, f64>,
+    tradelog: &mut File,
+) {
+    let symbol = tp.symbol();
+
+    // Forget about any exit orders which are no longer open.
+    let open_orders = match bex.get_open_orders(symbol) {
+        Ok(orders) => {
+            let orders = orders.as_array().unwrap().clone();
+            let open: HashSet<u64> = orders
+                .iter()
+                .map(|o| o["orderId"].as_u64().unwrap_or(0))
+                .collect();
+            st.open_orders.retain(|id| open.contains(id));
+            orders
+        }
+        Err(code) => {
+            error!("failed to get open orders for {}: {}", symbol, code);
+            Vec::new()
+        }
+    };
+
+    // Account for any trades made while we were not running.
+    if let Some(last_trade_id) = st.last_trade_id {
+        match bex.get_my_trades(symbol, Some(last_trade_id + 1)) {
+            Ok(trades) => {
+                let mut sold_qty = 0.0;
+                let mut sold_quote = 0.0;
+                let mut commission_usdt = 0.0;
+                for trade in trades.iter() {
+                    let price = trade.price.parse::<f64>().unwrap();
+                    let qty = trade.qty.parse::<f64>().unwrap();
+                    commission_usdt += compute_commision_usdt(
+                        bex,
+                        &trade.commissionAsset,
+                        trade.commission.parse::<f64>().unwrap(),
+                        price,
+                        symbol,
+                    );
+
+                    if trade.isBuyer {
+                        // Bought more, fold it into the average entry price.
+                        let pos =
+                            st.position
+                                .get_or_insert(Position::new(PositionType::Long, 0.0, 0.0));
+                        pos.price = ((pos.price * pos.qty) + (price * qty)) / (pos.qty + qty);
+                        pos.qty += qty;
+                        pos.peak_qty = pos.peak_qty.max(pos.qty);
+                    } else {
+                        sold_qty += qty;
+                        sold_quote += trade.quoteQty.parse::<f64>().unwrap();
+                    }
+
+                    st.last_trade_id = Some(trade.id);
+                }
+
+                st.cuml_commission += commission_usdt;
+
+                if sold_qty > 0.0 {
+                    if let Some(pos) = st.position.as_mut() {
+                        let pnl = sold_quote - (sold_qty * pos.price) - commission_usdt;
+                        st.cuml_pnl += pnl;
+                        pos.qty -= sold_qty;
+                        let msg = format!(
+                            "symbol:{},result:{},pnl:{:.2},cuml_pnl:{:.2},sold_while_offline:{}",
+                            symbol,
+                            if pnl > 0.0 { "WIN" } else { "LOSS" },
+                            pnl,
+                            st.cuml_pnl,
+                            sold_qty,
+                        );
+                        info!("{}", msg);
+                        writeln!(tradelog, "{}", msg).unwrap();
+                    }
+                }
+            }
+            Err(code) => {
+                error!("failed to get trade history for {}: {}", symbol, code);
+            }
+        }
+    }
+
+    // We can't hold more than the balance says we have.
+    if let Some(pos) = st.position.as_mut() {
+        let held = match ad.get(tp.sell_currency()) {
+            Some(b) => b.free + b.locked,
+            None => 0.0,
+        };
+
+        if held < pos.qty {
+            info!(
+                "{} saved position of {} but only {} held",
+                symbol, pos.qty, held
+            );
+            pos.qty = round::floor(held, tp.get_qty_dps());
+        }
+
+        if pos.qty * pos.price < tp.get_min_notional() {
+            info!("{} saved position is closed", symbol);
+            st.position = None;
+        }
+    }
+
+    if st.position.is_none() {
+        reconcile_existing_holdings(bex, tp, st, ad, &open_orders, policy, reserved);
+    }
+
+    // Put back the exit orders if they have gone.
+    if let Some(pos) = st.position.as_ref() {
+        info!(
+            "{} restored position of {} @ {}, exit orders: {:?}",
+            symbol, pos.qty, pos.price, st.open_orders
+        );
+
+        if st.open_orders.is_empty() {
+            let price_dps = st.price_dps.unwrap_or(tp.get_price_dps() as u8);
+            st.open_orders =
+                submit_exit_orders(bex, &st.exit_params, pos.price, price_dps, pos.qty, symbol);
+        }
+    } else {
+        st.open_orders.clear();
+    }
+}
+
+// Average price paid for the most recent 'qty' bought according to our trade history.
+fn entry_price_from_history(bex: &Binance, symbol: &str, qty: f64) -> Option<f64> {
+    let trades = match bex.get_my_trades(symbol, None) {
+        Ok(trades) => trades,
+        Err(code) => {
+            error!("failed to get trade history for {}: {}", symbol, code);
+            return None;
+        }
+    };
+
+    let mut remaining = qty;
+    let mut cost = 0.0;
+    for trade in trades.iter().rev().filter(|t| t.isBuyer) {
+        if remaining <= 0.0 {
+            break;
+        }
+
+        let trade_qty = trade.qty.parse::<f64>().unwrap().min(remaining);
+        cost += trade_qty * trade.price.parse::<f64>().unwrap();
+        remaining -= trade_qty;
+    }
+
+    if remaining < qty {
+        Some(cost / (qty - remaining))
+    } else {
+        None
+    }
+}
+
+// Deal with holdings of the base asset, and orders on the symbol, which exist before we
+// have taken any position of our own.
+fn reconcile_existing_holdings(
+    bex: &Binance,
+    tp: &TradingPair,
+    st: &mut SymbolState,
+    ad: &HashMap<String, Balance>,
+    open_orders: &Vec<serde_json::Value>,
+    policy: ExistingPositionPolicy,
+    reserved: &mut HashMap<String, f64>,
+) {
+    let symbol = tp.symbol();
+    let held = match ad.get(tp.sell_currency()) {
+        Some(b) => b.free + b.locked,
+        None => 0.0,
+    };
+    let held = round::floor(held, tp.get_qty_dps());
+
+    // Ignore dust we couldn't trade anyway.
+    let price = match bex.get_price(symbol) {
+        Ok(p) => p.price.parse::<f64>().unwrap(),
+        Err(code) => {
+            error!("failed to get price of {}: {}", symbol, code);
+            return;
+        }
+    };
+    if held * price < tp.get_min_notional() && open_orders.is_empty() {
+        return;
+    }
+
+    info!(
+        "{} found existing holding of {} {} and {} open orders, policy: {:?}",
+        symbol,
+        held,
+        tp.sell_currency(),
+        open_orders.len(),
+        policy
+    );
+
+    match policy {
+        ExistingPositionPolicy::Adopt => {
+            if held * price < tp.get_min_notional() {
+                return;
+            }
+
+            let entry_price = entry_price_from_history(bex, symbol, held).unwrap_or(price);
+            st.position = Some(Position::new(PositionType::Long, held, entry_price));
+
+            // Any sell orders already resting on the holding become our exit orders.
+            st.open_orders = open_orders
+                .iter()
+                .filter(|o| o["side"].as_str().unwrap_or("").eq("SELL"))
+                .map(|o| o["orderId"].as_u64().unwrap_or(0))
+                .collect();
+
+            info!(
+                "{} adopted position of {} @ {}, exit orders: {:?}",
+                symbol, held, entry_price, st.open_orders
+            );
+        }
+
+        ExistingPositionPolicy::Ignore => {
+            reserved.insert(tp.sell_currency().to_string(), held);
+        }
+
+        ExistingPositionPolicy::Flatten => {
+            if !open_orders.is_empty() {
+                if let Err(code) = bex.cancel_all_orders(symbol) {
+                    error!("failed to cancel open orders on {}: {}", symbol, code);
+                    return;
+                }
+            }
+
+            if held * price >= tp.get_min_notional() {
+                match order::place_market_sell(bex, symbol, held) {
+                    Ok(ack) => {
+                        info!(
+                            "submitted market sell of {} with id {} for {}",
+                            held, ack.orderId, ack.symbol
+                        );
+                    }
+                    Err(code) => {
+                        error!("failed to flatten {} {}: {}", held, symbol, code);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Watch the limit orders we placed until they fill. Every 'reprice_secs' an unfilled
+// order is replaced with one priced nearer the market, and once 'timeout_secs' have
+// passed since it was first placed it is cancelled or what is left is traded at
+// market. Replacements go through the order thread which cancels the order first.
+fn chase_thread(
+    ec: ExchangeConfig,
+    order_tx: mpsc::Sender<OrderMsg>,
+    working: Arc<Mutex<HashMap<String, WorkingOrder>>>,
+    books: OrderBooks,
+    policy: FillPolicy,
+    stop: Arc<AtomicBool>,
+) {
+    let bex = Binance::new(ec);
+
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_secs(1));
+
+        let due: Vec<WorkingOrder> = {
+            let mut wm = working.locked();
+            let ids: Vec<String> = wm
+                .iter()
+                .filter(|(_, wo)| {
+                    policy
+                        .timeout_secs
+                        .map(|t| wo.chase.placed_at.elapsed() >= Duration::from_secs(t))
+                        .unwrap_or(false)
+                        || policy
+                            .reprice_secs
+                            .map(|r| wo.repriced_at.elapsed() >= Duration::from_secs(r))
+                            .unwrap_or(false)
+                })
+                .map(|(id, _)| id.to_string())
+                .collect();
+            ids.iter().map(|id| wm.remove(id).unwrap()).collect()
+        };
+
+        for wo in due {
+            let tp = &wo.msg.tp;
+            let buy = wo.msg.position == PositionType::Long;
+            let remaining = round::floor(wo.qty - wo.filled, tp.get_qty_dps());
+            let timed_out = policy
+                .timeout_secs
+                .map(|t| wo.chase.placed_at.elapsed() >= Duration::from_secs(t))
+                .unwrap_or(false);
+
+            if timed_out {
+                if policy.on_timeout == TimeoutAction::Market
+                    && remaining * wo.price >= tp.get_min_notional()
+                {
+                    info!(
+                        "limit order {} on {} unfilled, trading {} at market",
+                        wo.order_id,
+                        tp.symbol(),
+                        remaining
+                    );
+                    let mut msg = wo.msg.clone();
+                    msg.order_type = OrderType::Market;
+                    msg.quantity = OrderQuantity::Exact(remaining);
+                    msg.limit_price = None;
+                    msg.max_slippage_bps = None;
+                    msg.chase = Some(Chase {
+                        replaces: wo.order_id,
+                        ..wo.chase
+                    });
+                    if let Err(e) = order_tx.send(msg) {
+                        error!("failed to send order to the order thread: {}", e);
+                    }
+                } else {
+                    info!(
+                        "limit order {} on {} unfilled, cancelling",
+                        wo.order_id,
+                        tp.symbol()
+                    );
+                    if let Err(code) = bex.cancel_order(tp.symbol(), wo.order_id) {
+                        // Most likely filled in the meantime.
+                        error!(
+                            "failed to cancel order {} on {}: {}",
+                            wo.order_id,
+                            tp.symbol(),
+                            code
+                        );
+                    }
+                }
+                continue;
+            }
+
+            // Where is the market now, the book if we have one otherwise the last price.
+            let book = books.get(tp.symbol());
+            let target = match (&book, wo.msg.limit_price) {
+                (Some(b), Some(mode)) if mode.needs_book() => {
+                    pricing::book_price(mode, b, buy, tp.get_tick_size(), tp.get_price_dps())
+                }
+                (Some(b), _) => (if buy { b.best_ask() } else { b.best_bid() }).map(|(p, _)| p),
+                (None, _) => bex
+                    .get_price(tp.symbol())
+                    .ok()
+                    .and_then(|p| p.price.parse::<f64>().ok()),
+            };
+
+            let new_price = target.and_then(|target| {
+                pricing::chase_price(
+                    buy,
+                    wo.price,
+                    wo.chase.first_price,
+                    target,
+                    policy.max_chase_bps,
+                    tp.get_tick_size(),
+                    tp.get_price_dps(),
+                )
+            });
+
+            match new_price {
+                Some(price) if remaining * price >= tp.get_min_notional() => {
+                    info!(
+                        "repricing limit order {} on {} from {} to {}, {} remaining",
+                        wo.order_id,
+                        tp.symbol(),
+                        wo.price,
+                        price,
+                        remaining
+                    );
+                    let mut msg = wo.msg.clone();
+                    msg.quantity = OrderQuantity::Exact(remaining);
+                    msg.limit_price = Some(LimitPrice::Fixed(price));
+                    msg.max_slippage_bps = None;
+                    msg.chase = Some(Chase {
+                        replaces: wo.order_id,
+                        ..wo.chase
+                    });
+                    if let Err(e) = order_tx.send(msg) {
+                        error!("failed to send order to the order thread: {}", e);
+                    }
+                }
+                _ => {
+                    // Leave it where it is until next time.
+                    debug!(
+                        "limit order {} on {} left at {}",
+                        wo.order_id,
+                        tp.symbol(),
+                        wo.price
+                    );
+                    let mut wo = wo.clone();
+                    wo.repriced_at = Instant::now();
+                    working.locked().insert(wo.client_order_id.to_string(), wo);
+                }
+            }
+        }
+    }
+}
+
+// Work parent orders as child market orders sent to the order thread, finishing them
+// once they have filled or run out of time.
+fn execution_thread(
+    ec: ExchangeConfig,
+    order_tx: mpsc::Sender<OrderMsg>,
+    parents: Arc<Mutex<HashMap<u64, AlgoOrder>>>,
+    books: OrderBooks,
+    hub: StreamHub,
+    stop: Arc<AtomicBool>,
+) {
+    let bex = Binance::new(ec);
+    // Trades on the market, for parents worked as a percentage of volume.
+    let mut trades: HashMap<u64, mpsc::Receiver<serde_json::Value>> = HashMap::new();
+
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_secs(1));
+
+        let active: Vec<AlgoOrder> = parents.locked().values().cloned().collect();
+        trades.retain(|id, _| active.iter().any(|a| a.parent.id == *id));
+
+        for a in active {
+            let p = &a.parent;
+            let tp = &a.msg.tp;
+            let elapsed = p.started.elapsed();
+
+            let mut volume = 0.0;
+            let mut last_price = None;
+            if let ExecAlgo::Pov { .. } = p.algo {
+                let rx = trades.entry(p.id).or_insert_with(|| {
+                    hub.subscribe(&format!("{}@aggTrade", tp.symbol().to_lowercase()))
+                });
+                while let Ok(t) = rx.try_recv() {
+                    let field = |k: &str| {
+                        t[k].as_str()
+                            .and_then(|v| v.parse::<f64>().ok())
+                            .unwrap_or(0.0)
+                    };
+                    volume += field("q");
+                    last_price = Some(field("p"));
+                }
+            }
+
+            let (target, expired) = match p.algo {
+                ExecAlgo::Twap { secs, slices } => (
+                    execution::twap_target(p.qty, secs, slices, elapsed),
+                    elapsed >= Duration::from_secs(secs),
+                ),
+                ExecAlgo::Pov { percent, max_secs } => (
+                    execution::pov_target(p.qty, percent, p.market_volume + volume),
+                    elapsed >= Duration::from_secs(max_secs),
+                ),
+                // Placed as one order by the order thread, done once it has finished.
+                _ => (0.0, true),
+            };
+
+            // Roughly what the children will cost, to keep them above the minimum.
+            let price = match last_price.or_else(|| books.get(tp.symbol()).and_then(|b| b.mid())) {
+                Some(price) => Some(price),
+                None => bex
+                    .get_price(tp.symbol())
+                    .ok()
+                    .and_then(|p| p.price.parse::<f64>().ok()),
+            };
+            let min_qty = match price {
+                Some(price) => tp.get_min_notional() / price,
+                None => {
+                    error!("no price for {} to work parent order {}", tp.symbol(), p.id);
+                    continue;
+                }
+            };
+
+            let due = if expired {
+                0.0
+            } else {
+                p.due(target, tp.get_qty_dps())
+            };
+            let complete = p.qty - p.filled() < min_qty;
+
+            let mut pm = parents.locked();
+            let ap = match pm.get_mut(&p.id) {
+                Some(ap) => &mut ap.parent,
+                None => continue,
+            };
+            ap.market_volume += volume;
+
+            if ap.children_done() && (complete || expired) {
+                info!(
+                    "parent {} order {} on {} finished, {} of {} filled ({:.1}%) at average price {:?}",
+                    if ap.buy { "BUY" } else { "SELL" },
+                    ap.id,
+                    ap.symbol,
+                    ap.filled(),
+                    ap.qty,
+                    ap.progress(),
+                    ap.avg_price()
+                );
+                pm.remove(&p.id);
+                continue;
+            }
+
+            if due >= min_qty && ap.pending.is_none() {
+                debug!(
+                    "parent order {} on {} sending child of {}",
+                    ap.id, ap.symbol, due
+                );
+                ap.pending = Some(due);
+                let mut msg = a.msg.clone();
+                msg.order_type = OrderType::Market;
+                msg.quantity = OrderQuantity::Exact(due);
+                msg.limit_price = None;
+                msg.parent = Some(ap.id);
+                if let Err(e) = order_tx.send(msg) {
+                    error!("failed to send order to the order thread: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Watch stop losses which have triggered but not filled, for example when the price gaps
+// through the stop limit price. Once the timeout expires the stop is cancelled and whatever
+// quantity remains is sold at market.
+fn stop_watchdog_thread(
+    ec: ExchangeConfig,
+    triggered: Arc<Mutex<HashMap<u64, TriggeredStop>>>,
+    stop: Arc<AtomicBool>,
+) {
+    let bex = Binance::new(ec);
+
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_secs(1));
+
+        let expired: Vec<(u64, TriggeredStop)> = {
+            let mut ts = triggered.locked();
+            let ids: Vec<u64> = ts
+                .iter()
+                .filter(|(_, stop)| stop.triggered_at.elapsed() >= stop.timeout)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter().map(|id| (*id, ts.remove(id).unwrap())).collect()
+        };
+
+        for (order_id, ts) in expired {
+            let symbol = ts.symbol;
+            info!(
+                "stop loss {} on {} triggered but unfilled, replacing with a market sell",
+                order_id, symbol
+            );
+
+            // When the stop is an OCO leg the whole order list is cancelled and the
+            // response doesn't describe the stop, what is left comes from its execution
+            // reports instead.
+            if let Err(code) = bex.cancel_order(&symbol, order_id) {
+                // Most likely filled in the meantime.
+                error!(
+                    "failed to cancel stop loss {} on {}: {}",
+                    order_id, symbol, code
+                );
+                continue;
+            }
+
+            let remaining_qty = round::floor(ts.qty - ts.filled, ts.qty_dps);
+            if remaining_qty <= 0.0 {
+                error!(
+                    "stop loss {} on {} cancelled with nothing left to sell, qty: {}, filled: {}",
+                    order_id, symbol, ts.qty, ts.filled
+                );
+                continue;
+            }
+
+            match order::place_market_sell(&bex, &symbol, remaining_qty) {
+                Ok(ack) => {
+                    info!(
+                        "submitted market sell of {} with id {} for {}",
+                        remaining_qty, ack.orderId, ack.symbol
+                    );
+                }
+                Err(code) => {
+                    error!(
+                        "failed to place market sell of {} {}: {}",
+                        remaining_qty, symbol, code
+                    );
+                }
+            }
         }
     }
 }
@@ -323,6 +1640,88 @@ fn connect_stream(lk: &str) -> Option<Client<Box<dyn NetworkStream + std::marker
     Some(conn)
 }
 
+// Replace the listen key and reconnect to the user data stream. The old key is
+// deleted first, which fails if it has already expired.
+fn renew_user_stream(
+    bex: &Binance,
+    lk: &mut String,
+    listen_key: &Mutex<String>,
+    user_stream: &Mutex<Option<TcpStream>>,
+) -> Option<Client<Box<dyn NetworkStream + std::marker::Send>>> {
+    let mut cur_try = 0;
+    while cur_try < 5 {
+        cur_try += 1;
+        match bex.delete_listen_key(lk.clone()) {
+            Ok(_) => {
+                debug!("deleted listen key {}", lk);
+            }
+            Err(code) => {
+                error!("failed to delete listen key {}: {}", lk, code);
+            }
+        }
+
+        *lk = match bex.create_listen_key() {
+            Ok(lk) => lk,
+            Err(code) => {
+                error!("could not create listen key: {:?}", code);
+                thread::sleep(Duration::from_secs(5 * cur_try));
+                continue;
+            }
+        };
+        *listen_key.locked() = lk.clone();
+
+        match connect_stream(lk) {
+            Some(conn) => {
+                *user_stream.locked() = conn.stream_ref().as_tcp().try_clone().ok();
+                return Some(conn);
+            }
+            None => {
+                thread::sleep(Duration::from_secs(5 * cur_try));
+            }
+        }
+    }
+
+    None
+}
+
+// Keep the listen key alive whatever the traffic on the user data stream, Binance
+// expires a key 60 minutes after its last keepalive. If the keepalive fails the
+// stream is shut down so the event thread renews the key and reconnects.
+fn keepalive_thread(
+    ec: ExchangeConfig,
+    listen_key: Arc<Mutex<String>>,
+    user_stream: Arc<Mutex<Option<TcpStream>>>,
+    stop: Arc<AtomicBool>,
+) {
+    let bex = Binance::new(ec);
+    let mut last_keepalive = Instant::now();
+
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_secs(1));
+        if last_keepalive.elapsed() < LISTEN_KEY_KEEPALIVE {
+            continue;
+        }
+        last_keepalive = Instant::now();
+
+        let lk = listen_key.locked().clone();
+        if lk.is_empty() {
+            continue;
+        }
+
+        match bex.ping_listen_key(lk.clone()) {
+            Ok(_) => {
+                debug!("kept listen key {} alive", lk);
+            }
+            Err(code) => {
+                error!("failed to keep listen key {} alive: {}", lk, code);
+                if let Some(stream) = user_stream.locked().as_ref() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+        }
+    }
+}
+
 // Thread which handles events on the websocket, those events can be:
 //
 // Balance updates.
@@ -333,11 +1732,22 @@ fn event_thread(
     ad: Arc<Mutex<HashMap<String, Balance>>>,
     positions: Arc<Mutex<HashMap<String, Position>>>,
     _order_tx: mpsc::Sender<OrderMsg>,
-    ready_barrier: Arc<Barrier>,
+    ready_barrier: &mut Option<Arc<Barrier>>,
     event_cv: Arc<(Mutex<bool>, Condvar)>,
-    stop_percent: Arc<Mutex<Option<f64>>>,
+    exit_params: Arc<Mutex<SymbolExitParams>>,
+    triggered_stops: Arc<Mutex<HashMap<u64, TriggeredStop>>>,
     log_dir: String,
-) {
+    state_dir: String,
+    pairs: Vec<TradingPair>,
+    policy: ExistingPositionPolicy,
+    reserved: Arc<Mutex<HashMap<String, f64>>>,
+    closed_trades: Arc<Mutex<Vec<ClosedTrade>>>,
+    stop: Arc<AtomicBool>,
+    user_stream: Arc<Mutex<Option<TcpStream>>>,
+    listen_key: Arc<Mutex<String>>,
+    working_orders: Arc<Mutex<HashMap<String, WorkingOrder>>>,
+    parents: Arc<Mutex<HashMap<u64, AlgoOrder>>>,
+) -> Result<(), String> {
     let bex = Binance::new(ec);
 
     // Populate local view of balances, this is updated when events occur.
@@ -366,7 +1776,7 @@ fn event_thread(
                 balance.asset, balance.free, balance.locked
             )
             .unwrap();
-            let mut ad = ad.lock().unwrap();
+            let mut ad = ad.locked();
             ad.insert(balance.asset.to_string(), balance);
         }
     }
@@ -378,26 +1788,83 @@ fn event_thread(
         }
     };
 
-    let mut conn = connect_stream(&lk).unwrap();
+    *listen_key.locked() = lk.clone();
 
-    // Wait till we are connected before we allow anything else to happen.
-    ready_barrier.wait();
+    let mut conn = connect_stream(&lk).unwrap();
+    *user_stream.locked() = conn.stream_ref().as_tcp().try_clone().ok();
 
     let mut running = true;
     let mut cancelled_order = false;
-    let mut trade_buy_price: Option<f64> = None;
-    let mut ave_trade_buy_price: Option<f64> = None;
-    let mut trade_sell_price: Option<f64> = None;
-    let mut trade_commission_usdt: Option<f64> = None;
-    let mut total_buy_quantity: Option<f64> = None;
-    let mut price_dps: Option<u8> = None;
     let mut cuml_pnl: f64 = 0.0;
     let mut cuml_commission: f64 = 0.0;
-    let mut fills = 0;
-    let mut buy_is_filled = false;
-    let mut buy_symbol = String::from("NOSYMBOL");
+    let mut tracker = OrderTracker::new();
+    // Filled buys waiting on the account update before their exit orders are placed,
+    // (symbol, quantity, average fill price).
+    let mut pending_exits: Vec<(String, f64, f64)> = Vec::new();
+    let pair_map: HashMap<String, TradingPair> = pairs
+        .iter()
+        .map(|tp| (tp.symbol().to_string(), tp.clone()))
+        .collect();
+
+    // Pick up where we left off, anything we were holding when we stopped is restored
+    // once it has been checked against the exchange.
+    let mut states: HashMap<String, SymbolState> = HashMap::new();
+    for tp in pairs.iter() {
+        let mut st = state::load(&state_dir, tp.symbol()).unwrap_or_default();
+        if st.position.is_none() {
+            // Exit orders for a position we adopt are the configured ones.
+            st.exit_params = exit_params.locked().get(tp.symbol());
+        }
+        reconcile_state(
+            &bex,
+            tp,
+            &mut st,
+            &ad.locked(),
+            policy,
+            &mut reserved.locked(),
+            &mut tradelog,
+        );
+
+        // Orders still working when we stopped, or when this thread failed, carry on
+        // from their fills so far.
+        match bex.get_open_orders(tp.symbol()) {
+            Ok(orders) => {
+                for o in orders.as_array().unwrap_or(&Vec::new()) {
+                    if tracker.track_open(o).is_none() {
+                        error!("failed to track open order: {}", o);
+                    }
+                }
+            }
+            Err(code) => error!("failed to get open orders for {}: {}", tp.symbol(), code),
+        }
 
-    while running {
+        if let Some(pos) = st.position.as_ref() {
+            positions
+                .locked()
+                .insert(tp.symbol().to_string(), pos.clone());
+            exit_params.locked().set(tp.symbol(), st.exit_params);
+        }
+
+        if st.last_trade_id.is_none() {
+            // Start accounting from the most recent trade.
+            if let Ok(trades) = bex.get_my_trades(tp.symbol(), None) {
+                st.last_trade_id = trades.iter().map(|t| t.id).max();
+            }
+        }
+
+        cuml_pnl += st.cuml_pnl;
+        cuml_commission += st.cuml_commission;
+        state::save(&state_dir, tp.symbol(), &st);
+        states.insert(tp.symbol().to_string(), st);
+    }
+
+    // Wait till we are connected before we allow anything else to happen, only the
+    // first time we run, after a restart nobody is waiting.
+    if let Some(barrier) = ready_barrier.take() {
+        barrier.wait();
+    }
+
+    while running && !stop.load(Ordering::SeqCst) {
         // TODO: Need timeout on this.
         match conn.recv_message() {
             Ok(om) => {
@@ -416,7 +1883,7 @@ fn event_thread(
                                         .parse::<f64>()
                                         .unwrap_or(0.0);
                                     debug!("balance update: {:?} {:?}", asset, delta);
-                                    let mut ad_w = ad.lock().unwrap();
+                                    let mut ad_w = ad.locked();
                                     let entry = ad_w.get_mut(asset);
                                     if entry.is_some() {
                                         let mut b = entry.unwrap();
@@ -440,7 +1907,7 @@ fn event_thread(
                                             b["f"].as_str().unwrap().parse::<f64>().unwrap();
                                         let new_locked =
                                             b["l"].as_str().unwrap().parse::<f64>().unwrap();
-                                        let mut ad_w = ad.lock().unwrap();
+                                        let mut ad_w = ad.locked();
                                         ad_w.insert(
                                             asset.to_string(),
                                             Balance {
@@ -456,24 +1923,22 @@ fn event_thread(
                                         );
                                         info!("{}", msg);
                                         writeln!(&mut tradelog, "{}", msg).unwrap();
+                                    }
 
-                                        if buy_is_filled {
-                                            buy_is_filled = false;
-                                            // After the account update we might need to place a
-                                            // stop loss.
-                                            let stp = stop_percent.lock().unwrap();
-                                            if stp.is_some() {
-                                                let stp = stp.unwrap();
-                                                submit_stop_order(
-                                                    &bex,
-                                                    stp,
-                                                    ave_trade_buy_price.unwrap(),
-                                                    price_dps.unwrap(),
-                                                    total_buy_quantity.unwrap(),
-                                                    &buy_symbol,
-                                                );
-                                            }
-                                        }
+                                    // After the account update we might need to place a stop
+                                    // loss, or a stop loss and take profit pair.
+                                    for (symbol, qty, price) in pending_exits.drain(..) {
+                                        let st = states.entry(symbol.clone()).or_default();
+                                        let ids = submit_exit_orders(
+                                            &bex,
+                                            &st.exit_params,
+                                            price,
+                                            st.price_dps.unwrap(),
+                                            qty,
+                                            &symbol,
+                                        );
+                                        st.open_orders.extend(ids);
+                                        state::save(&state_dir, &symbol, st);
                                     }
 
                                     if cancelled_order {
@@ -481,158 +1946,342 @@ fn event_thread(
                                         // unblock the order thread so it can complete the
                                         // current order using latest balance data.
                                         let (lock, cvar) = &*event_cv;
-                                        let mut waiting_on_cancel = lock.lock().unwrap();
+                                        let mut waiting_on_cancel = lock.locked();
                                         *waiting_on_cancel = false;
                                         cvar.notify_one();
                                         cancelled_order = false;
                                     }
                                 }
                                 "executionReport" => {
-                                    let symbol = &payload["s"].as_str().unwrap().to_string();
-                                    let id = &payload["i"].as_u64().unwrap().to_string();
-                                    let side = &payload["S"].as_str().unwrap().to_string();
-                                    let ot = &payload["o"].as_str().unwrap().to_string();
-                                    let tenforce = &payload["f"].as_str().unwrap().to_string();
-                                    let status = &payload["X"].as_str().unwrap().to_string();
-                                    let filled_qty = &payload["l"].as_str().unwrap().to_string();
-                                    let cuml_filled_qty =
-                                        &payload["z"].as_str().unwrap().to_string();
-                                    let price = &payload["L"].as_str().unwrap().to_string();
-                                    let commission = &payload["n"].as_str().unwrap().to_string();
-                                    let commission_asset =
-                                        &payload["N"].as_str().unwrap_or("NONE").to_string();
-
-                                    let msg = format!("order:{},symbol:{},status:{},side:{},type:{},time_enforce:{},qty:{},price:{},commision_asset:{},commision:{}",
-                                        id, symbol, status, side, ot, tenforce, filled_qty, price, commission_asset, commission);
+                                    let er = match ExecutionReport::from_json(&payload) {
+                                        Some(er) => er,
+                                        None => {
+                                            error!("failed to parse execution report: {:?}", s);
+                                            continue;
+                                        }
+                                    };
+                                    let symbol = &er.symbol;
+
+                                    let msg = format!("order:{},client_order:{},order_list:{},symbol:{},status:{},side:{},type:{},time_enforce:{},qty:{},price:{},commision_asset:{},commision:{}",
+                                        er.order_id, er.client_order_id, er.order_list_id, symbol, er.status, er.side, er.order_type, er.time_in_force, er.last_filled_qty, er.last_filled_price, er.commission_asset, er.commission);
                                     info!("{}", msg);
                                     writeln!(&mut tradelog, "{}", msg).unwrap();
 
-                                    if status.eq("CANCELED") {
+                                    let working =
+                                        er.status.eq("NEW") || er.status.eq("PARTIALLY_FILLED");
+
+                                    if er.order_type.eq("STOP_LOSS_LIMIT") {
+                                        let mut ts = triggered_stops.locked();
+                                        // Is the order on the book, i.e. has the stop triggered.
+                                        if er.on_book && working {
+                                            let timeout =
+                                                exit_params.locked().get(symbol).stop_fill_timeout;
+                                            if let Some(timeout) = timeout {
+                                                if !ts.contains_key(&er.order_id) {
+                                                    info!(
+                                                        "stop loss {} on {} triggered, waiting {}s for fill",
+                                                        er.order_id, symbol, timeout
+                                                    );
+                                                    ts.insert(
+                                                        er.order_id,
+                                                        TriggeredStop {
+                                                            symbol: symbol.to_string(),
+                                                            triggered_at: Instant::now(),
+                                                            timeout: Duration::from_secs(timeout),
+                                                            qty: er.orig_qty,
+                                                            filled: tracker
+                                                                .get(&er.client_order_id)
+                                                                .map(|o| o.filled_qty)
+                                                                .unwrap_or(0.0)
+                                                                + er.last_filled_qty,
+                                                            qty_dps: utils::decimal_places(
+                                                                payload["q"]
+                                                                    .as_str()
+                                                                    .unwrap_or("0"),
+                                                            )
+                                                                as i8,
+                                                        },
+                                                    );
+                                                } else if let Some(t) = ts.get_mut(&er.order_id) {
+                                                    t.filled += er.last_filled_qty;
+                                                }
+                                            }
+                                        } else if !working {
+                                            // Filled, cancelled, expired, nothing more to watch.
+                                            ts.remove(&er.order_id);
+                                        }
+                                    }
+
+                                    // Keep the saved state in step with the order.
+                                    {
+                                        let st = states.entry(symbol.to_string()).or_default();
+                                        if !working {
+                                            st.open_orders.retain(|oid| *oid != er.order_id);
+                                        }
+
+                                        // Trade id is -1 unless this report is for a fill.
+                                        if er.trade_id >= 0 {
+                                            st.last_trade_id = Some(er.trade_id as u64);
+                                        }
+                                    }
+
+                                    if er.status.eq("CANCELED") {
                                         cancelled_order = true;
-                                        fills = 0;
+                                    }
 
-                                        // Remove from the positions hashmap.
-                                        let mut pm = positions.lock().unwrap();
-                                        pm.remove(&buy_symbol);
+                                    let commission_usdt = if er.commission > 0.0 {
+                                        compute_commision_usdt(
+                                            &bex,
+                                            &er.commission_asset,
+                                            er.commission,
+                                            er.last_filled_price,
+                                            symbol,
+                                        )
+                                    } else {
+                                        0.0
+                                    };
+                                    cuml_commission += commission_usdt;
+
+                                    let order = match tracker.update(&er, commission_usdt) {
+                                        Some(order) => order,
+                                        None => continue,
+                                    };
+
+                                    // Let the chase thread know how much of a working
+                                    // limit order is left.
+                                    {
+                                        let mut wm = working_orders.locked();
+                                        if order.status.is_final() {
+                                            wm.remove(&order.client_order_id);
+                                        } else if let Some(wo) = wm.get_mut(&order.client_order_id)
+                                        {
+                                            wo.filled = order.filled_qty;
+                                        }
+                                    }
 
-                                        if !ot.eq("STOP_LOSS_LIMIT") {
-                                            trade_buy_price = None;
-                                            trade_sell_price = None;
-                                            ave_trade_buy_price = None;
-                                            trade_commission_usdt = None;
+                                    // Report the progress of the parent order this is a
+                                    // child of. Later children add to the position rather
+                                    // than count as a new tranche or exit.
+                                    let continues_parent = {
+                                        let mut pm = parents.locked();
+                                        match pm
+                                            .values_mut()
+                                            .find(|a| a.parent.has_child(&order.client_order_id))
+                                        {
+                                            Some(a) => {
+                                                let p = &mut a.parent;
+                                                p.update_child(
+                                                    &order.client_order_id,
+                                                    order.filled_qty,
+                                                    order.filled_quote,
+                                                    order.status.is_final(),
+                                                );
+                                                if er.last_filled_qty > 0.0 {
+                                                    let msg = format!(
+                                                        "parent:{},symbol:{},filled:{},qty:{},progress:{:.1}%,ave_price:{}",
+                                                        p.id,
+                                                        symbol,
+                                                        p.filled(),
+                                                        p.qty,
+                                                        p.progress(),
+                                                        p.avg_price().unwrap_or(0.0),
+                                                    );
+                                                    info!("{}", msg);
+                                                    writeln!(&mut tradelog, "{}", msg).unwrap();
+                                                }
+                                                p.filled_by_others(&order.client_order_id) > 0.0
+                                            }
+                                            None => false,
                                         }
-                                    } else if status.eq("FILLED") {
-                                        fills += 1;
-
-                                        let commission = commission.parse::<f64>().unwrap();
-
-                                        trade_commission_usdt = Some(
-                                            trade_commission_usdt.unwrap_or(0.0)
-                                                + compute_commision_usdt(
-                                                    &bex,
-                                                    &commission_asset,
-                                                    commission,
-                                                    price.parse::<f64>().unwrap(),
-                                                    &symbol,
+                                    };
+
+                                    // Position & PnL only change once the order is done, a
+                                    // cancelled order may still have been partially filled.
+                                    if order.status.is_final() && order.filled_qty > 0.0 {
+                                        let ave_price = order.ave_price().unwrap();
+                                        let st = states.entry(symbol.to_string()).or_default();
+                                        let mut pm = positions.locked();
+
+                                        if order.is_buy() {
+                                            let price_dps = match pair_map.get(symbol) {
+                                                Some(tp) => tp.get_price_dps() as u8,
+                                                None => utils::decimal_places(
+                                                    &er.last_filled_price.to_string(),
                                                 ),
-                                        );
-
-                                        cuml_commission += trade_commission_usdt.unwrap();
-
-                                        if side.eq("BUY") {
-                                            // Record buy completly filled, save some things here so that we
-                                            // can submit a stop loss when our account update comes in.
-                                            price_dps = Some(utils::decimal_places(price));
-                                            let price = price.parse::<f64>().unwrap();
-                                            trade_buy_price =
-                                                Some(price + trade_buy_price.unwrap_or(0.0));
-                                            ave_trade_buy_price =
-                                                Some(trade_buy_price.unwrap() / fills as f64);
-                                            total_buy_quantity =
-                                                Some(cuml_filled_qty.parse::<f64>().unwrap());
-                                            buy_symbol = String::from(symbol);
-                                            fills = 0;
-                                            trade_buy_price = None;
-                                            buy_is_filled = true;
-
-                                            // Insert into the positions hashmap.
-                                            let mut pm = positions.lock().unwrap();
-                                            assert!(!pm.contains_key(&buy_symbol));
-                                            pm.insert(
-                                                String::from(&buy_symbol),
-                                                Position {
-                                                    price: ave_trade_buy_price.unwrap(),
-                                                    qty: total_buy_quantity.unwrap(),
-                                                    r#type: PositionType::Long,
-                                                },
-                                            );
-                                        } else {
+                                            };
+
+                                            // Scaling in, add to what we already hold.
+                                            let pos = match pm.get(symbol) {
+                                                Some(held) => {
+                                                    let mut pos = held.clone();
+                                                    pos.qty += order.filled_qty;
+                                                    pos.price = ((held.price * held.qty)
+                                                        + order.filled_quote)
+                                                        / pos.qty;
+                                                    if !continues_parent {
+                                                        pos.tranches += 1;
+                                                    }
+                                                    pos.peak_qty = pos.peak_qty.max(pos.qty);
+                                                    info!(
+                                                        "{} tranche {} filled, qty: {}, average entry: {}",
+                                                        symbol, pos.tranches, pos.qty, pos.price
+                                                    );
+                                                    pos
+                                                }
+                                                None => Position::new(
+                                                    PositionType::Long,
+                                                    order.filled_qty,
+                                                    ave_price,
+                                                ),
+                                            };
+                                            pm.insert(symbol.to_string(), pos.clone());
+
+                                            st.price_dps = Some(price_dps);
+                                            st.exit_params = exit_params.locked().get(symbol);
+                                            st.entry_commission += order.commission_usdt;
+
+                                            // Exit orders were cancelled before the buy, they
+                                            // are placed again for the whole position.
+                                            pending_exits.push((
+                                                symbol.to_string(),
+                                                pos.qty,
+                                                pos.price,
+                                            ));
+                                            st.position = Some(pos);
+                                        } else if let Some(pos) = pm.get(symbol).cloned() {
                                             // SELL.
-                                            let price = price.parse::<f64>().unwrap();
-                                            trade_sell_price =
-                                                Some(price + trade_sell_price.unwrap_or(0.0));
-                                            let asp = trade_sell_price.unwrap() / fills as f64;
-
-                                            // Remove from the positions hashmap.
-                                            let mut pm = positions.lock().unwrap();
-                                            pm.remove(&buy_symbol);
-
-                                            if ave_trade_buy_price.is_some() {
-                                                let abp = ave_trade_buy_price.unwrap();
-                                                let price_delta = asp - abp; // May be negative.
-                                                let price_delta_pct = (price_delta / abp) * 100.0;
-                                                let qty = cuml_filled_qty.parse::<f64>().unwrap();
-                                                let commission = trade_commission_usdt.unwrap();
-                                                let pnl = (qty * price_delta) - commission;
-                                                cuml_pnl += pnl;
-                                                let msg = format!(
-                                                    "symbol:{},result:{},pnl:{:.2},cuml_pnl:{:.2},price_delta_pct:{:.*}%,price_delta:{:.*},commision_usdt:{:.2},cuml_pl_usdt:{:.2},cuml_commision_usdt:{:.2}",
-                                                    symbol,
-                                                    if abp < asp { "WIN" } else { "LOSS" },
-                                                    pnl,
-                                                    cuml_pnl,
-                                                    price_dps.unwrap() as usize,
-                                                    price_delta_pct,
-                                                    price_dps.unwrap() as usize,
-                                                    price_delta,
-                                                    trade_commission_usdt.unwrap(),
-                                                    cuml_pnl,
-                                                    cuml_commission,
-                                                );
-                                                info!("{}", msg);
-                                                writeln!(&mut tradelog, "{}", msg).unwrap();
+                                            let price_dps = st.price_dps.unwrap_or(8) as usize;
+                                            let qty = order.filled_qty.min(pos.qty);
+                                            let abp = pos.price;
+                                            let asp = ave_price;
+                                            let price_delta = asp - abp; // May be negative.
+                                            let price_delta_pct = (price_delta / abp) * 100.0;
+
+                                            // Entry commission is charged against the part of
+                                            // the position sold.
+                                            let entry_commission =
+                                                st.entry_commission * (qty / pos.qty);
+                                            let commission =
+                                                entry_commission + order.commission_usdt;
+                                            let pnl = (qty * price_delta) - commission;
+                                            cuml_pnl += pnl;
+
+                                            let msg = format!(
+                                                "symbol:{},result:{},pnl:{:.2},cuml_pnl:{:.2},price_delta_pct:{:.*}%,price_delta:{:.*},commision_usdt:{:.2},cuml_pl_usdt:{:.2},cuml_commision_usdt:{:.2}",
+                                                symbol,
+                                                if abp < asp { "WIN" } else { "LOSS" },
+                                                pnl,
+                                                cuml_pnl,
+                                                price_dps,
+                                                price_delta_pct,
+                                                price_dps,
+                                                price_delta,
+                                                commission,
+                                                cuml_pnl,
+                                                cuml_commission,
+                                            );
+                                            info!("{}", msg);
+                                            writeln!(&mut tradelog, "{}", msg).unwrap();
+
+                                            // Keep recent results for sizing and risk limits.
+                                            {
+                                                let mut ct = closed_trades.locked();
+                                                ct.push(ClosedTrade {
+                                                    time: chrono::offset::Utc::now().timestamp(),
+                                                    pnl: pnl,
+                                                    ret: pnl / (qty * abp),
+                                                });
+                                                if ct.len() > MAX_CLOSED_TRADES {
+                                                    ct.remove(0);
+                                                }
                                             }
 
-                                            fills = 0;
-                                            trade_sell_price = None;
-                                            trade_commission_usdt = None;
+                                            st.cuml_pnl += pnl;
+                                            st.cuml_commission += commission;
+                                            st.entry_commission -= entry_commission;
+
+                                            // Whatever is left below the minimum order size
+                                            // can't be sold, the position is closed.
+                                            let remaining = pos.qty - qty;
+                                            let min_notional = pair_map
+                                                .get(symbol)
+                                                .map(|tp| tp.get_min_notional())
+                                                .unwrap_or(0.0);
+                                            if remaining <= 0.0 || remaining * asp < min_notional {
+                                                pm.remove(symbol);
+                                                st.position = None;
+                                                st.entry_commission = 0.0;
+                                            } else {
+                                                let mut pos = pos.clone();
+                                                pos.qty = remaining;
+
+                                                // A partial exit, unless a stop loss only
+                                                // part filled in which case the stop watchdog
+                                                // sells the rest.
+                                                if !order.order_type.eq("STOP_LOSS_LIMIT") {
+                                                    if !continues_parent {
+                                                        pos.targets_hit += 1;
+                                                    }
+                                                    info!(
+                                                        "{} partial exit {} filled, {} remaining",
+                                                        symbol, pos.targets_hit, remaining
+                                                    );
+                                                    pending_exits.push((
+                                                        symbol.to_string(),
+                                                        pos.qty,
+                                                        pos.price,
+                                                    ));
+                                                }
+
+                                                pm.insert(symbol.to_string(), pos.clone());
+                                                st.position = Some(pos);
+                                            }
                                         }
-                                    } else if status.eq("PARTIALLY_FILLED") {
-                                        fills += 1;
-                                        let price = price.parse::<f64>().unwrap();
-
-                                        let commission = commission.parse::<f64>().unwrap();
-
-                                        trade_commission_usdt = Some(
-                                            trade_commission_usdt.unwrap_or(0.0)
-                                                + compute_commision_usdt(
-                                                    &bex,
-                                                    &commission_asset,
-                                                    commission,
-                                                    price,
-                                                    &symbol,
-                                                ),
-                                        );
+                                    }
+
+                                    if let Some(st) = states.get(symbol) {
+                                        state::save(&state_dir, symbol, st);
+                                    }
+                                }
+                                "listenKeyExpired" => {
+                                    info!("listen key {} expired, renewing", lk);
+                                    match renew_user_stream(
+                                        &bex,
+                                        &mut lk,
+                                        &listen_key,
+                                        &user_stream,
+                                    ) {
+                                        Some(c) => conn = c,
+                                        None => running = false,
+                                    }
+                                }
 
-                                        cuml_commission += trade_commission_usdt.unwrap();
+                                "listStatus" => {
+                                    // Status of an OCO order list, the individual legs are
+                                    // reported via executionReport events.
+                                    let symbol = payload["s"].as_str().unwrap_or("NOSYMBOL");
+                                    let list_id = payload["g"].as_i64().unwrap_or(-1);
+                                    let list_status_type = payload["l"].as_str().unwrap_or("");
+                                    let list_order_status = payload["L"].as_str().unwrap_or("");
+                                    let reject_reason = payload["r"].as_str().unwrap_or("NONE");
+                                    let order_ids: Vec<String> = match payload["O"].as_array() {
+                                        Some(orders) => orders
+                                            .iter()
+                                            .map(|o| o["i"].as_u64().unwrap_or(0).to_string())
+                                            .collect(),
+                                        None => Vec::new(),
+                                    };
+
+                                    let msg = format!("order_list:{},symbol:{},status:{},list_status:{},orders:{},reject_reason:{}",
+                                        list_id, symbol, list_status_type, list_order_status, order_ids.join(":"), reject_reason);
+                                    info!("{}", msg);
+                                    writeln!(&mut tradelog, "{}", msg).unwrap();
 
-                                        if side.eq("BUY") {
-                                            trade_buy_price =
-                                                Some(price + trade_buy_price.unwrap_or(0.0));
-                                        } else {
-                                            trade_sell_price =
-                                                Some(price + trade_sell_price.unwrap_or(0.0));
-                                        }
+                                    if list_status_type.eq("ALL_DONE") {
+                                        info!(
+                                            "oco order list {} on {} is complete",
+                                            list_id, symbol
+                                        );
                                     }
                                 }
                                 _ => {
@@ -662,68 +2311,91 @@ fn event_thread(
 
                     OwnedMessage::Close(e) => {
                         info!("userdata stream disconnected {:?}", e);
-                        let mut cur_try = 0;
-                        running = false;
-
-                        while cur_try < 5 {
-                            cur_try += 1;
-                            match bex.delete_listen_key(lk.clone()) {
-                                Ok(_) => {
-                                    debug!("deleted listen key {}", lk);
-                                }
-                                Err(code) => {
-                                    error!("failed to delete listen key {}: {}", lk, code);
-                                    continue;
-                                }
-                            }
-
-                            lk = match bex.create_listen_key() {
-                                Ok(lk) => lk,
-                                Err(code) => {
-                                    error!("could not create listen key: {:?}", code);
-                                    continue;
-                                }
-                            };
-
-                            conn = match connect_stream(&lk) {
-                                Some(c) => c,
-                                None => {
-                                    continue;
-                                }
-                            };
+                        if stop.load(Ordering::SeqCst) {
+                            break;
+                        }
 
-                            continue;
+                        match renew_user_stream(&bex, &mut lk, &listen_key, &user_stream) {
+                            Some(c) => conn = c,
+                            None => running = false,
                         }
                     }
                 }
-
-                // Keep our connection alive.
-                if let Err(code) = bex.ping_listen_key(lk.clone()) {
-                    error!("failed to ping listen key stream: {:?}", code);
-                }
             }
 
             Err(e) => {
+                if stop.load(Ordering::SeqCst) {
+                    // Woken up to shut down.
+                    break;
+                }
+
                 match e {
-                    websocket::WebSocketError::NoDataAvailable => {
-                        // Assume a timeout, ping the server.
-                        info!("NoDataAvailable timeout, ping the server");
-                        if let Err(code) = bex.ping_listen_key(lk.clone()) {
-                            error!("failed to ping listen key stream: {:?}", code);
-                        }
+                    websocket::WebSocketError::IoError(ref ioe)
+                        if ioe.kind() == std::io::ErrorKind::WouldBlock
+                            || ioe.kind() == std::io::ErrorKind::TimedOut =>
+                    {
+                        // Read timeout, the keepalive thread keeps the key alive.
+                        debug!("userdata stream read timeout");
                     }
                     _ => {
                         error!("error receiving data from the websocket: {:?}", e);
+                        match renew_user_stream(&bex, &mut lk, &listen_key, &user_stream) {
+                            Some(c) => conn = c,
+                            None => running = false,
+                        }
                     }
                 }
             }
         }
     }
+
+    if stop.load(Ordering::SeqCst) {
+        let _ = conn.shutdown();
+        match bex.delete_listen_key(lk.clone()) {
+            Ok(_) => info!("deleted listen key {}", lk),
+            Err(code) => error!("failed to delete listen key {}: {}", lk, code),
+        }
+
+        for (symbol, st) in states.iter() {
+            state::save(&state_dir, symbol, st);
+        }
+
+        let msg = format!(
+            "cuml_pnl:{:.2},cuml_commision_usdt:{:.2}",
+            cuml_pnl, cuml_commission
+        );
+        info!("{}", msg);
+        writeln!(&mut tradelog, "{}", msg).unwrap();
+        if let Err(e) = tradelog.sync_all() {
+            error!("failed to flush trade log: {}", e);
+        }
+
+        info!("user data stream closed");
+        return Ok(());
+    }
+
+    Err(String::from("lost the user data stream"))
 }
 
 impl AccountManager {
-    pub fn new(ec: ExchangeConfig, margin: bool, log_dir: String) -> AccountManager {
+    pub fn new(
+        ec: ExchangeConfig,
+        margin: bool,
+        log_dir: String,
+        state_dir: String,
+        pairs: Vec<TradingPair>,
+        exit_params: ExitParams,
+        policy: ExistingPositionPolicy,
+        budgets: HashMap<String, f64>,
+        supervisor: &Supervisor,
+        books: OrderBooks,
+        fill_policy: FillPolicy,
+        algo: ExecAlgo,
+        hub: StreamHub,
+        closed_trades: Vec<ClosedTrade>,
+    ) -> AccountManager {
         let (order_tx, order_rx) = mpsc::channel::<OrderMsg>();
+        let order_rx = Arc::new(Mutex::new(order_rx));
         let ad = Arc::new(Mutex::new(HashMap::new()));
         let positions = Arc::new(Mutex::new(HashMap::new()));
 
@@ -734,41 +2406,151 @@ impl AccountManager {
         let ad_orders = Arc::clone(&ad);
 
         let positions_events = Arc::clone(&positions);
+        let positions_orders = Arc::clone(&positions);
 
         let events_tx = order_tx.clone();
 
         let ready_barrier = Arc::new(Barrier::new(2));
-        let event_thread_ready_barrier = Arc::clone(&ready_barrier);
+        let mut event_thread_ready_barrier = Some(Arc::clone(&ready_barrier));
 
         let order_completed_cv = Arc::new((Mutex::new(true), Condvar::new()));
         let event_thread_order_completed_cv = Arc::clone(&order_completed_cv);
 
-        let stop_percent_ot = Arc::new(Mutex::new(None));
-        let stop_percent_et = Arc::clone(&stop_percent_ot);
+        let exit_params_ot = Arc::new(Mutex::new(SymbolExitParams::new(exit_params)));
+        let exit_params_et = Arc::clone(&exit_params_ot);
+
+        let reserved_ot = Arc::new(Mutex::new(HashMap::new()));
+        let reserved_et = Arc::clone(&reserved_ot);
+
+        let closed_trades = Arc::new(Mutex::new(closed_trades));
+        let closed_trades_ot = Arc::clone(&closed_trades);
+        let closed_trades_et = Arc::clone(&closed_trades);
+
+        let triggered_stops_et = Arc::new(Mutex::new(HashMap::new()));
+        let triggered_stops_wd = Arc::clone(&triggered_stops_et);
+        let ec3 = ec.clone();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_et = Arc::clone(&stop);
+        let stop_wd = Arc::clone(&stop);
+
+        let user_stream = Arc::new(Mutex::new(None));
+        let user_stream_et = Arc::clone(&user_stream);
+        let user_stream_ka = Arc::clone(&user_stream);
+
+        let working = Arc::new(Mutex::new(HashMap::new()));
+        let working_et = Arc::clone(&working);
+        let working_ot = Arc::clone(&working);
+        let chase_tx = order_tx.clone();
+        let books_ch = books.clone();
+        let stop_ch = Arc::clone(&stop);
+        let ec5 = ec.clone();
+
+        let parents = Arc::new(Mutex::new(HashMap::new()));
+        let parents_et = Arc::clone(&parents);
+        let parents_ot = Arc::clone(&parents);
+        let exec_tx = order_tx.clone();
+        let books_ex = books.clone();
+        let stop_ex = Arc::clone(&stop);
+        let ec6 = ec.clone();
+
+        let listen_key = Arc::new(Mutex::new(String::new()));
+        let listen_key_ka = Arc::clone(&listen_key);
+        let stop_ka = Arc::clone(&stop);
+        let ec4 = ec.clone();
+
+        let pairs_am = pairs.clone();
+        let pairs_ot = pairs.clone();
 
         let log_dir = log_dir.clone();
 
-        thread::spawn(move || {
+        // The workers are restarted by the supervisor if they fail, so each run gets
+        // its own copy of the arguments.
+        let event_handle = supervisor.spawn("user data events", move || {
             event_thread(
-                ec,
-                ad_events,
-                positions_events,
-                events_tx,
-                event_thread_ready_barrier,
-                event_thread_order_completed_cv,
-                stop_percent_et,
+                ec.clone(),
+                Arc::clone(&ad_events),
+                Arc::clone(&positions_events),
+                events_tx.clone(),
+                &mut event_thread_ready_barrier,
+                Arc::clone(&event_thread_order_completed_cv),
+                Arc::clone(&exit_params_et),
+                Arc::clone(&triggered_stops_et),
                 log_dir.to_string(),
+                state_dir.clone(),
+                pairs.clone(),
+                policy,
+                Arc::clone(&reserved_et),
+                Arc::clone(&closed_trades_et),
+                Arc::clone(&stop_et),
+                Arc::clone(&user_stream_et),
+                Arc::clone(&listen_key),
+                Arc::clone(&working_et),
+                Arc::clone(&parents_et),
             )
         });
-        thread::spawn(move || {
+        let order_handle = supervisor.spawn("orders", move || {
             order_thread(
-                ec2,
-                ad_orders,
-                order_rx,
-                order_completed_cv,
-                stop_percent_ot,
+                ec2.clone(),
+                Arc::clone(&ad_orders),
+                Arc::clone(&order_rx),
+                Arc::clone(&order_completed_cv),
+                Arc::clone(&exit_params_ot),
+                Arc::clone(&reserved_ot),
+                Arc::clone(&positions_orders),
+                budgets.clone(),
+                Arc::clone(&closed_trades_ot),
+                pairs_ot.clone(),
+                books.clone(),
+                Arc::clone(&working_ot),
+                fill_policy,
+                Arc::clone(&parents_ot),
+                algo,
                 margin,
-            )
+            );
+            Ok(())
+        });
+        let watchdog_handle = supervisor.spawn("stop watchdog", move || {
+            stop_watchdog_thread(
+                ec3.clone(),
+                Arc::clone(&triggered_stops_wd),
+                Arc::clone(&stop_wd),
+            );
+            Ok(())
+        });
+
+        let keepalive_handle = supervisor.spawn("listen key keepalive", move || {
+            keepalive_thread(
+                ec4.clone(),
+                Arc::clone(&listen_key_ka),
+                Arc::clone(&user_stream_ka),
+                Arc::clone(&stop_ka),
+            );
+            Ok(())
+        });
+
+        let chase_handle = supervisor.spawn("limit order chase", move || {
+            chase_thread(
+                ec5.clone(),
+                chase_tx.clone(),
+                Arc::clone(&working),
+                books_ch.clone(),
+                fill_policy,
+                Arc::clone(&stop_ch),
+            );
+            Ok(())
+        });
+
+        let execution_handle = supervisor.spawn("execution", move || {
+            execution_thread(
+                ec6.clone(),
+                exec_tx.clone(),
+                Arc::clone(&parents),
+                books_ex.clone(),
+                hub.clone(),
+                Arc::clone(&stop_ex),
+            );
+            Ok(())
         });
 
         // Wait until the event thread is ready to go.
@@ -777,11 +2559,27 @@ impl AccountManager {
         AccountManager {
             tx_channel: order_tx,
             positions: Arc::clone(&positions),
+            ad: ad,
+            closed_trades: closed_trades,
+            pairs: pairs_am,
+            stop: stop,
+            user_stream: user_stream,
+            threads: Arc::new(Mutex::new(vec![
+                order_handle,
+                event_handle,
+                watchdog_handle,
+                keepalive_handle,
+                chase_handle,
+                execution_handle,
+            ])),
         }
     }
 
     fn submit_order(&self, om: OrderMsg) {
-        self.tx_channel.send(om).unwrap();
+        // Fails if the order thread has been given up on.
+        if let Err(e) = self.tx_channel.send(om) {
+            error!("failed to send order to the order thread: {}", e);
+        }
     }
 
     // Queue a long position to the order thread.
@@ -790,8 +2588,11 @@ impl AccountManager {
         tp: TradingPair,
         position: PositionType,
         quantity: OrderQuantity,
-        limit_price: Option<f64>,
-        stop_percent: Option<f64>,
+        limit_price: Option<LimitPrice>,
+        max_slippage_bps: Option<f64>,
+        exit_params: ExitParams,
+        sizing: PositionSizing,
+        max_notional: Option<f64>,
     ) {
         let om = OrderMsg {
             tp: tp,
@@ -803,7 +2604,12 @@ impl AccountManager {
             position: position,
             quantity: quantity,
             limit_price: limit_price,
-            stop_percent: stop_percent,
+            max_slippage_bps: max_slippage_bps,
+            exit_params: exit_params,
+            sizing: sizing,
+            max_notional: max_notional,
+            chase: None,
+            parent: None,
             quit: false,
         };
 
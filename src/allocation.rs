@@ -0,0 +1,117 @@
+// Capital allocation between pairs (allocation.rs).
+//
+// Each configured pair, or BASE/UP/DOWN set in bvlt mode, is given a share of our
+// capital. The shares always sum to 1.0.
+use crate::binance::Binance;
+
+use log::{error, info};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Allocation {
+    // Same share for every pair.
+    Equal,
+    // Share in proportion to a weight given per pair.
+    Weights(HashMap<String, f64>),
+    // Share in proportion to the inverse of the volatility of each pair, calmer
+    // pairs get more capital.
+    Volatility,
+}
+
+// Scale the weights so they sum to 1.0, pairs without a weight get nothing.
+pub fn normalise(names: &[String], weights: &HashMap<String, f64>) -> HashMap<String, f64> {
+    let total: f64 = names.iter().filter_map(|n| weights.get(n)).sum();
+    names
+        .iter()
+        .map(|n| {
+            let w = weights.get(n).cloned().unwrap_or(0.0);
+            (n.to_string(), if total > 0.0 { w / total } else { 0.0 })
+        })
+        .collect()
+}
+
+// Standard deviation of the close to close returns.
+pub fn volatility(closes: &[f64]) -> Option<f64> {
+    if closes.len() < 3 {
+        return None;
+    }
+
+    let returns: Vec<f64> = closes.windows(2).map(|w| (w[1] - w[0]) / w[0]).collect();
+    let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+    let var = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / returns.len() as f64;
+    Some(var.sqrt())
+}
+
+// Compute the share of capital for each pair, 'pairs' are (name, symbol) tuples where
+// the symbol is the one we analyse for the pair.
+pub fn shares(
+    bex: &Binance,
+    allocation: &Allocation,
+    pairs: &[(String, String)],
+    time_frame: &str,
+    lookback: u16,
+) -> HashMap<String, f64> {
+    let names: Vec<String> = pairs.iter().map(|(n, _)| n.to_string()).collect();
+    let weights: HashMap<String, f64> = match allocation {
+        Allocation::Equal => names.iter().map(|n| (n.to_string(), 1.0)).collect(),
+        Allocation::Weights(w) => w.clone(),
+        Allocation::Volatility => {
+            let limit = lookback.to_string();
+            let mut weights = HashMap::new();
+            for (name, symbol) in pairs {
+                let mut req_params: HashMap<&str, &str> = HashMap::with_capacity(3);
+                req_params.insert("symbol", symbol);
+                req_params.insert("interval", time_frame);
+                req_params.insert("limit", &limit);
+
+                let closes: Vec<f64> = match bex.get_cstick_data(&req_params) {
+                    Ok(cd) => cd
+                        .iter()
+                        .filter_map(|c| c.close_price.parse::<f64>().ok())
+                        .collect(),
+                    Err(code) => {
+                        error!("failed to get cstick data for {}: {}", symbol, code);
+                        Vec::new()
+                    }
+                };
+
+                match volatility(&closes) {
+                    Some(v) if v > 0.0 => {
+                        info!("{} volatility {:.6}", name, v);
+                        weights.insert(name.to_string(), 1.0 / v);
+                    }
+                    _ => {
+                        panic!("unable to compute volatility of {}", name);
+                    }
+                }
+            }
+            weights
+        }
+    };
+
+    normalise(&names, &weights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalise_weights() {
+        let names = vec![String::from("ADA/USDT"), String::from("BTC/USDT")];
+        let mut weights = HashMap::new();
+        weights.insert(String::from("ADA/USDT"), 1.0);
+        weights.insert(String::from("BTC/USDT"), 3.0);
+
+        let s = normalise(&names, &weights);
+        assert_eq!(s["ADA/USDT"], 0.25);
+        assert_eq!(s["BTC/USDT"], 0.75);
+    }
+
+    #[test]
+    fn flat_prices_have_no_volatility() {
+        assert_eq!(volatility(&[1.0, 1.0, 1.0, 1.0]), Some(0.0));
+        assert_eq!(volatility(&[1.0, 1.0]), None);
+        assert!(volatility(&[1.0, 1.1, 1.0, 1.1]).unwrap() > 0.0);
+    }
+}
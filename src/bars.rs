@@ -0,0 +1,379 @@
+// Candles built from the trade stream (bars.rs).
+//
+// Rather than the fixed exchange kline intervals, candles can be built locally from
+// the @aggTrade or @trade stream, closing:
+//
+// time: every so many seconds, minutes or hours, e.g. 2m, 10m or 90m.
+// tick: after so many trades.
+// volume: after so much of the base asset has traded.
+// dollar: after so much of the quote asset has traded.
+// range: once the high and low are so far apart.
+// renko: a brick each time the price moves a brick size past the last brick.
+//
+// Any of these can be turned into Heikin-Ashi candles.
+
+// What candles to build and from which stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BarSpec {
+    pub bar_type: BarType,
+    pub heikin_ashi: bool,
+    // @aggTrade rather than @trade.
+    pub aggregated: bool,
+    // Hours of 1m klines to warm up candles other than time candles from.
+    pub warmup_hours: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BarType {
+    // Interval in ms.
+    Time(u64),
+    Tick(u64),
+    Volume(f64),
+    Dollar(f64),
+    Range(f64),
+    Renko(f64),
+}
+
+impl BarType {
+    // Parse 2m, 90m, 4h, 30s, tick:500, volume:10, dollar:1000000, range:25 or renko:10.
+    pub fn parse(s: &str) -> Option<BarType> {
+        if let Some((kind, size)) = s.split_once(':') {
+            let size = size.parse::<f64>().ok().filter(|v| *v > 0.0)?;
+            return match kind {
+                "tick" => Some(BarType::Tick(size as u64)),
+                "volume" => Some(BarType::Volume(size)),
+                "dollar" => Some(BarType::Dollar(size)),
+                "range" => Some(BarType::Range(size)),
+                "renko" => Some(BarType::Renko(size)),
+                _ => None,
+            };
+        }
+
+        if s.len() < 2 {
+            return None;
+        }
+        let (n, unit) = s.split_at(s.len() - 1);
+        let n = n.parse::<u64>().ok().filter(|n| *n > 0)?;
+        let unit_ms = match unit {
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            _ => return None,
+        };
+        Some(BarType::Time(n * unit_ms))
+    }
+}
+
+// Trades to build candles from. A single trade has the same open, high, low and close,
+// a kline used to warm up has its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trades {
+    pub time: u64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub qty: f64,
+    pub quote: f64,
+    pub count: u64,
+}
+
+impl Trades {
+    // From an @aggTrade or @trade stream event, an aggregate trade may be several.
+    pub fn from_json(t: &serde_json::Value) -> Option<Trades> {
+        let price = t["p"].as_str()?.parse::<f64>().ok()?;
+        let qty = t["q"].as_str()?.parse::<f64>().ok()?;
+        let count = match (t["f"].as_u64(), t["l"].as_u64()) {
+            (Some(first), Some(last)) if last >= first => last - first + 1,
+            _ => 1,
+        };
+        Some(Trades {
+            time: t["T"].as_u64()?,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            qty: qty,
+            quote: price * qty,
+            count: count,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bar {
+    pub open_time: u64,
+    pub close_time: u64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    pub quote_volume: f64,
+    pub trades: u64,
+}
+
+impl Bar {
+    fn new(open_time: u64, price: f64) -> Bar {
+        Bar {
+            open_time: open_time,
+            close_time: open_time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: 0.0,
+            quote_volume: 0.0,
+            trades: 0,
+        }
+    }
+
+    fn add(&mut self, t: &Trades) {
+        self.close_time = self.close_time.max(t.time);
+        self.high = self.high.max(t.high);
+        self.low = self.low.min(t.low);
+        self.close = t.close;
+        self.volume += t.qty;
+        self.quote_volume += t.quote;
+        self.trades += t.count;
+    }
+}
+
+#[derive(Debug)]
+pub struct BarBuilder {
+    bar_type: BarType,
+    heikin_ashi: bool,
+    current: Option<Bar>,
+    // Open and close of the last Heikin-Ashi candle.
+    prev_ha: Option<(f64, f64)>,
+    // Open and close of the last Renko brick.
+    brick: Option<(f64, f64)>,
+}
+
+impl BarBuilder {
+    pub fn new(bar_type: BarType, heikin_ashi: bool) -> BarBuilder {
+        BarBuilder {
+            bar_type: bar_type,
+            heikin_ashi: heikin_ashi,
+            current: None,
+            prev_ha: None,
+            brick: None,
+        }
+    }
+
+    // Add trades, returning any candles they closed.
+    pub fn add(&mut self, t: &Trades) -> Vec<Bar> {
+        let mut closed = Vec::new();
+
+        match self.bar_type {
+            BarType::Time(interval) => {
+                closed.extend(self.close_due(t.time));
+                let open_time = (t.time / interval) * interval;
+                self.current
+                    .get_or_insert_with(|| Bar::new(open_time, t.open))
+                    .add(t);
+            }
+            BarType::Renko(size) => {
+                let (open, close) = *self.brick.get_or_insert((t.close, t.close));
+                let bar = self.current.get_or_insert_with(|| Bar::new(t.time, t.open));
+                bar.add(t);
+
+                // A brick for each brick size the price has moved past the last brick.
+                let (mut top, mut bottom) = (open.max(close), open.min(close));
+                while t.close >= top + size || t.close <= bottom - size {
+                    let (o, c) = if t.close >= top + size {
+                        (top, top + size)
+                    } else {
+                        (bottom, bottom - size)
+                    };
+                    let mut brick = *bar;
+                    brick.open = o;
+                    brick.close = c;
+                    brick.high = o.max(c);
+                    brick.low = o.min(c);
+                    closed.push(brick);
+                    *bar = Bar::new(t.time, c);
+                    self.brick = Some((o, c));
+                    top = o.max(c);
+                    bottom = o.min(c);
+                }
+            }
+            _ => {
+                let bar = self.current.get_or_insert_with(|| Bar::new(t.time, t.open));
+                bar.add(t);
+                let done = match self.bar_type {
+                    BarType::Tick(n) => bar.trades >= n,
+                    BarType::Volume(v) => bar.volume >= v,
+                    BarType::Dollar(d) => bar.quote_volume >= d,
+                    BarType::Range(r) => bar.high - bar.low >= r,
+                    _ => false,
+                };
+                if done {
+                    closed.push(self.current.take().unwrap());
+                }
+            }
+        }
+
+        closed.into_iter().map(|b| self.finish(b)).collect()
+    }
+
+    // Close time candles which ended before 'now', including those with no trades
+    // which are flat at the last close. Other candles only close on trades.
+    pub fn close_due(&mut self, now: u64) -> Vec<Bar> {
+        let interval = match self.bar_type {
+            BarType::Time(interval) => interval,
+            _ => return Vec::new(),
+        };
+
+        let mut closed = Vec::new();
+        while let Some(bar) = self.current {
+            if now < bar.open_time + interval {
+                break;
+            }
+            let mut bar = bar;
+            bar.close_time = bar.open_time + interval - 1;
+            closed.push(bar);
+
+            let next = bar.open_time + interval;
+            self.current = if now >= next + interval {
+                Some(Bar::new(next, bar.close))
+            } else {
+                None
+            };
+        }
+
+        if self.heikin_ashi {
+            closed.into_iter().map(|b| self.to_heikin_ashi(b)).collect()
+        } else {
+            closed
+        }
+    }
+
+    // The candle still forming, as it would be if it closed now.
+    pub fn forming(&self) -> Option<Bar> {
+        let bar = self.current?;
+        if !self.heikin_ashi {
+            return Some(bar);
+        }
+        // Without recording it as the last Heikin-Ashi candle.
+        let mut ha = BarBuilder::new(self.bar_type, true);
+        ha.prev_ha = self.prev_ha;
+        Some(ha.to_heikin_ashi(bar))
+    }
+
+    // Interval of time candles in ms, 0 for the others.
+    pub fn interval_ms(&self) -> u64 {
+        match self.bar_type {
+            BarType::Time(interval) => interval,
+            _ => 0,
+        }
+    }
+
+    fn finish(&mut self, bar: Bar) -> Bar {
+        // Time candles have already been through close_due().
+        if self.heikin_ashi && self.interval_ms() == 0 {
+            self.to_heikin_ashi(bar)
+        } else {
+            bar
+        }
+    }
+
+    fn to_heikin_ashi(&mut self, bar: Bar) -> Bar {
+        let close = (bar.open + bar.high + bar.low + bar.close) / 4.0;
+        let open = match self.prev_ha {
+            Some((o, c)) => (o + c) / 2.0,
+            None => (bar.open + bar.close) / 2.0,
+        };
+        self.prev_ha = Some((open, close));
+        Bar {
+            open: open,
+            close: close,
+            high: bar.high.max(open).max(close),
+            low: bar.low.min(open).min(close),
+            ..bar
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(time: u64, price: f64, qty: f64) -> Trades {
+        Trades {
+            time: time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            qty: qty,
+            quote: price * qty,
+            count: 1,
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(BarType::parse("2m"), Some(BarType::Time(120_000)));
+        assert_eq!(BarType::parse("90m"), Some(BarType::Time(5_400_000)));
+        assert_eq!(BarType::parse("tick:500"), Some(BarType::Tick(500)));
+        assert_eq!(BarType::parse("renko:2.5"), Some(BarType::Renko(2.5)));
+        assert_eq!(BarType::parse("volume:0"), None);
+        assert_eq!(BarType::parse("2x"), None);
+    }
+
+    #[test]
+    fn time_bars() {
+        let mut b = BarBuilder::new(BarType::Time(60_000), false);
+        assert!(b.add(&trade(1_000, 10.0, 1.0)).is_empty());
+        assert!(b.add(&trade(30_000, 12.0, 1.0)).is_empty());
+
+        // The next trade is two minutes on, the empty minute is flat.
+        let closed = b.add(&trade(150_000, 11.0, 1.0));
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed[0].open_time, 0);
+        assert_eq!(closed[0].close_time, 59_999);
+        assert_eq!(closed[0].high, 12.0);
+        assert_eq!(closed[0].volume, 2.0);
+        assert_eq!(closed[1].open_time, 60_000);
+        assert_eq!(closed[1].close, 12.0);
+        assert_eq!(closed[1].volume, 0.0);
+
+        assert_eq!(b.forming().unwrap().open_time, 120_000);
+        assert!(b.close_due(179_999).is_empty());
+        assert_eq!(b.close_due(180_000)[0].close, 11.0);
+    }
+
+    #[test]
+    fn activity_bars() {
+        let mut b = BarBuilder::new(BarType::Volume(3.0), false);
+        assert!(b.add(&trade(1, 10.0, 2.0)).is_empty());
+        let closed = b.add(&trade(2, 11.0, 2.0));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].volume, 4.0);
+
+        let mut b = BarBuilder::new(BarType::Range(1.0), false);
+        assert!(b.add(&trade(1, 10.0, 1.0)).is_empty());
+        assert!(b.add(&trade(2, 10.5, 1.0)).is_empty());
+        assert_eq!(b.add(&trade(3, 9.5, 1.0))[0].low, 9.5);
+    }
+
+    #[test]
+    fn renko_and_heikin_ashi() {
+        let mut b = BarBuilder::new(BarType::Renko(1.0), false);
+        assert!(b.add(&trade(1, 10.0, 1.0)).is_empty());
+        let closed = b.add(&trade(2, 12.2, 1.0));
+        assert_eq!(closed.len(), 2);
+        assert_eq!((closed[1].open, closed[1].close), (11.0, 12.0));
+        // A reversal needs two bricks.
+        assert!(b.add(&trade(3, 10.5, 1.0)).is_empty());
+        let closed = b.add(&trade(4, 9.9, 1.0));
+        assert_eq!((closed[0].open, closed[0].close), (11.0, 10.0));
+
+        let mut b = BarBuilder::new(BarType::Tick(2), true);
+        b.add(&trade(1, 10.0, 1.0));
+        let ha = b.add(&trade(2, 12.0, 1.0))[0];
+        assert_eq!((ha.open, ha.close, ha.high), (11.0, 11.0, 12.0));
+    }
+}
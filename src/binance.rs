@@ -9,7 +9,7 @@ use crate::price::Price;
 use crate::utils;
 
 use account::{Account, IsolatedMarginAccount};
-use order::{OrderResponseAck, ShortOrderResponse};
+use order::{OcoOrderResponse, OrderResponseAck, ShortOrderResponse, Trade};
 
 use log::error;
 use std::collections::HashMap;
@@ -827,6 +827,39 @@ impl Binance {
         }
     }
 
+    pub fn send_oco_order(&self, params: &HashMap<&str, &str>) -> Result<OcoOrderResponse, i64> {
+        let config = self.get_config();
+        let oco_ep = match config.endpoints_map.get(&String::from("ORDER_OCO")) {
+            Some(ep) => ep,
+            None => {
+                panic!(
+                    "no ORDER_OCO endpoint configured for exchange {:#?}",
+                    config.name
+                );
+            }
+        };
+
+        match self.post(&oco_ep, Some(&params), &config, true, false, false) {
+            Ok(s) => {
+                if s.status().is_success() {
+                    let or: OcoOrderResponse = s.json().unwrap();
+                    return Ok(or);
+                }
+
+                // Return the status code from binance.
+                let text = &s.text().unwrap();
+                let j: serde_json::Value = serde_json::from_str(text).unwrap();
+                error!("{}", text);
+                return Err(j["code"].as_i64().unwrap());
+            }
+
+            Err(e) => {
+                error!("failed to send oco order: {:#?}", e);
+                return Err(-1);
+            }
+        }
+    }
+
     pub fn send_order(
         &self,
         params: &mut HashMap<&str, &str>,
@@ -910,6 +943,50 @@ impl Binance {
         }
     }
 
+    pub fn cancel_order(&self, symbol: &str, order_id: u64) -> Result<serde_json::Value, i64> {
+        let config = self.get_config();
+        let co_ep = match config.endpoints_map.get(&String::from("CANCEL")) {
+            Some(ep) => ep,
+            None => {
+                panic!(
+                    "no CANCEL endpoint configured for exchange {:#?}",
+                    config.name
+                );
+            }
+        };
+
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        let ts_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        let t = ts_now.to_string();
+        params.insert("timestamp", &t);
+        params.insert("symbol", symbol);
+        let id = order_id.to_string();
+        params.insert("orderId", &id);
+
+        match self.delete(&co_ep, &params, &config, true, false, false) {
+            Ok(s) => {
+                if s.status().is_success() {
+                    let v: serde_json::Value = serde_json::from_str(&s.text().unwrap()).unwrap();
+                    return Ok(v);
+                }
+
+                // Return the status code from binance.
+                let text = &s.text().unwrap();
+                let j: serde_json::Value = serde_json::from_str(text).unwrap();
+                error!("{}", text);
+                return Err(j["code"].as_i64().unwrap());
+            }
+
+            Err(e) => {
+                error!("failed to send cancel order: {:#?}", e);
+                return Err(-1);
+            }
+        }
+    }
+
     pub fn get_open_orders(&self, symbol: &str) -> Result<serde_json::Value, i64> {
         let config = self.get_config();
         let co_ep = match config.endpoints_map.get(&String::from("OPEN_ORDERS")) {
@@ -952,6 +1029,54 @@ impl Binance {
         }
     }
 
+    // Trades on our account for the symbol, starting from trade id 'from_id' if given
+    // otherwise the most recent trades.
+    pub fn get_my_trades(&self, symbol: &str, from_id: Option<u64>) -> Result<Vec<Trade>, i64> {
+        let config = self.get_config();
+        let mt_ep = match config.endpoints_map.get(&String::from("MY_TRADES")) {
+            Some(ep) => ep,
+            None => {
+                panic!(
+                    "no MY_TRADES endpoint configured for exchange {:#?}",
+                    config.name
+                );
+            }
+        };
+
+        let mut params: HashMap<&str, &str> = HashMap::new();
+        let ts_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        let t = ts_now.to_string();
+        params.insert("timestamp", &t);
+        params.insert("symbol", symbol);
+        let from_id_str = from_id.unwrap_or(0).to_string();
+        if from_id.is_some() {
+            params.insert("fromId", &from_id_str);
+        }
+
+        match self.get_retries(&mt_ep, Some(&params), &config, true, false, false) {
+            Ok(s) => {
+                if s.status().is_success() {
+                    let trades: Vec<Trade> = s.json().unwrap();
+                    return Ok(trades);
+                }
+
+                // Return the status code from binance.
+                let text = &s.text().unwrap();
+                let j: serde_json::Value = serde_json::from_str(text).unwrap();
+                error!("{}", text);
+                return Err(j["code"].as_i64().unwrap());
+            }
+
+            Err(e) => {
+                error!("failed to get trades for {}: {:#?}", symbol, e);
+                return Err(-1);
+            }
+        }
+    }
+
     pub fn get_lot_size_filter(&self, symbol: &str) -> Result<LotSizeFilter, i64> {
         match self.get_exchange_info(Some(symbol)) {
             Ok(ei) => {
@@ -1002,6 +1127,28 @@ impl Binance {
         }
     }
 
+    // Most parts an iceberg order may be split into, 1 if the symbol has no filter.
+    pub fn get_iceberg_parts_filter(&self, symbol: &str) -> Result<u32, i64> {
+        match self.get_exchange_info(Some(symbol)) {
+            Ok(ei) => {
+                let sym = &ei["symbols"][0];
+                let parts = sym["filters"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|f| f["filterType"] == "ICEBERG_PARTS")
+                    .and_then(|f| f["limit"].as_u64())
+                    .unwrap_or(1);
+
+                return Ok(parts as u32);
+            }
+
+            Err(code) => {
+                return Err(code);
+            }
+        }
+    }
+
     pub fn get_price_filter(&self, symbol: &str) -> Result<PriceFilter, i64> {
         match self.get_exchange_info(Some(symbol)) {
             Ok(ei) => {
@@ -1150,6 +1297,45 @@ impl Binance {
         }
     }
 
+    // The last 'count' candles of 'interval', oldest first, fetched in as many requests
+    // as the limit of 1000 candles a request needs. Fewer are returned if the symbol
+    // hasn't that much history.
+    pub fn get_recent_cstick_data(
+        &self,
+        symbol: &str,
+        interval: &str,
+        count: u64,
+    ) -> Result<Vec<CandleStick>, i64> {
+        let mut candles: Vec<CandleStick> = Vec::with_capacity(count as usize);
+        let mut end_time: Option<String> = None;
+        while (candles.len() as u64) < count {
+            let want = (count - candles.len() as u64).min(1000);
+            let limit = want.to_string();
+            let mut params: HashMap<&str, &str> = HashMap::with_capacity(4);
+            params.insert("symbol", symbol);
+            params.insert("interval", interval);
+            params.insert("limit", &limit);
+            if let Some(end_time) = &end_time {
+                params.insert("endTime", end_time);
+            }
+
+            let mut page = self.get_cstick_data(&params)?;
+            let got = page.len() as u64;
+            let first_open = match page.first() {
+                Some(c) => c.open_time,
+                None => break,
+            };
+            page.append(&mut candles);
+            candles = page;
+
+            if got < want || first_open == 0 {
+                break;
+            }
+            end_time = Some((first_open - 1).to_string());
+        }
+        Ok(candles)
+    }
+
     #[allow(dead_code)]
     pub fn get_order_book(&self, symbol: &str, limit: Option<u16>) -> Result<OrderBook, i64> {
         let config = self.get_config();
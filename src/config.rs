@@ -22,6 +22,7 @@ pub struct ExchangeConfig {
 pub struct Config {
     pub log_level: String,
     pub log_dir: String,
+    pub state_dir: String,
     pub strategy: StrategyConfig,
 }
 
@@ -119,6 +120,12 @@ pub fn new(cfg_file_path: &String) -> (Config, ExchangeConfig) {
         None => "info".to_string(),
     };
 
+    let state_dir = match manager_section.get("StateDir") {
+        Some(v) => v.to_string(),
+
+        None => "state".to_string(),
+    };
+
     // Parse [Strategy] section.
     let strategy_section = match inifile.section(Some("Strategy")) {
         Some(s) => s,
@@ -137,6 +144,7 @@ pub fn new(cfg_file_path: &String) -> (Config, ExchangeConfig) {
             strategy: sc,
             log_level: log_level,
             log_dir: log_dir,
+            state_dir: state_dir,
         },
         ExchangeConfig {
             name: exchange_name.to_string(),
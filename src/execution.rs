@@ -0,0 +1,252 @@
+// Execution algorithms (execution.rs).
+//
+// Rather than trading the whole of an order at once, a parent order can be worked
+// as a number of smaller child orders:
+//
+// twap: split evenly over a time window.
+// iceberg: one limit order showing only part of its quantity on the book at a time.
+// pov: trade a percentage of the volume traded on the market.
+//
+// The fills of the children are added up to report the progress and average price
+// of the parent.
+use math::round;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Ids of parent orders, only unique while we are running.
+static NEXT_PARENT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecAlgo {
+    // The whole order at once.
+    Direct,
+    Twap { secs: u64, slices: u32 },
+    Iceberg { parts: u32 },
+    // Percentage of market volume, whatever is left after 'max_secs' is not traded.
+    Pov { percent: f64, max_secs: u64 },
+}
+
+impl ExecAlgo {
+    // Is the order worked as child orders over time.
+    pub fn slices_order(&self) -> bool {
+        match self {
+            ExecAlgo::Twap { .. } | ExecAlgo::Pov { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChildFill {
+    qty: f64,
+    filled: f64,
+    quote: f64,
+    done: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParentOrder {
+    pub id: u64,
+    pub symbol: String,
+    pub buy: bool,
+    pub qty: f64,
+    pub algo: ExecAlgo,
+    pub started: Instant,
+    // Traded market volume seen since we started, for POV.
+    pub market_volume: f64,
+    // A child sent to the order thread which it has not placed or rejected yet.
+    pub pending: Option<f64>,
+    // Keyed by client order id.
+    children: HashMap<String, ChildFill>,
+}
+
+impl ParentOrder {
+    pub fn new(symbol: &str, buy: bool, qty: f64, algo: ExecAlgo) -> ParentOrder {
+        ParentOrder {
+            id: NEXT_PARENT_ID.fetch_add(1, Ordering::Relaxed),
+            symbol: symbol.to_string(),
+            buy: buy,
+            qty: qty,
+            algo: algo,
+            started: Instant::now(),
+            market_volume: 0.0,
+            pending: None,
+            children: HashMap::new(),
+        }
+    }
+
+    pub fn add_child(&mut self, client_order_id: &str, qty: f64) {
+        self.pending = None;
+        self.children.insert(
+            client_order_id.to_string(),
+            ChildFill {
+                qty: qty,
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn has_child(&self, client_order_id: &str) -> bool {
+        self.children.contains_key(client_order_id)
+    }
+
+    // Latest totals of a child order from its execution reports.
+    pub fn update_child(&mut self, client_order_id: &str, filled: f64, quote: f64, done: bool) {
+        if let Some(c) = self.children.get_mut(client_order_id) {
+            c.filled = filled;
+            c.quote = quote;
+            c.done = done;
+        }
+    }
+
+    pub fn filled(&self) -> f64 {
+        self.children.values().map(|c| c.filled).sum()
+    }
+
+    // Filled by children other than this one.
+    pub fn filled_by_others(&self, client_order_id: &str) -> f64 {
+        self.children
+            .iter()
+            .filter(|(id, _)| id.as_str() != client_order_id)
+            .map(|(_, c)| c.filled)
+            .sum()
+    }
+
+    pub fn avg_price(&self) -> Option<f64> {
+        let filled = self.filled();
+        if filled > 0.0 {
+            Some(self.children.values().map(|c| c.quote).sum::<f64>() / filled)
+        } else {
+            None
+        }
+    }
+
+    // Percentage of the parent filled.
+    pub fn progress(&self) -> f64 {
+        (self.filled() / self.qty) * 100.0
+    }
+
+    // Quantity of children placed which may still fill, plus any child on its way to
+    // the order thread.
+    pub fn outstanding(&self) -> f64 {
+        let working: f64 = self
+            .children
+            .values()
+            .filter(|c| !c.done)
+            .map(|c| c.qty - c.filled)
+            .sum();
+        working + self.pending.unwrap_or(0.0)
+    }
+
+    // Have all the children we placed finished.
+    pub fn children_done(&self) -> bool {
+        self.outstanding() <= 0.0
+    }
+
+    // Quantity of the next child, what should have traded by now less what has traded
+    // or may still trade, at most what is left of the parent.
+    pub fn due(&self, target: f64, qty_dps: i8) -> f64 {
+        let done = self.filled() + self.outstanding();
+        round::floor((target.min(self.qty) - done).max(0.0), qty_dps)
+    }
+}
+
+// How much of 'qty' should have traded 'elapsed' into a TWAP over 'secs' seconds in
+// 'slices' slices, the first slice goes straight away.
+pub fn twap_target(qty: f64, secs: u64, slices: u32, elapsed: Duration) -> f64 {
+    let slices = slices.max(1);
+    let interval = secs as f64 / slices as f64;
+    let slice = if interval > 0.0 {
+        (elapsed.as_secs_f64() / interval).floor() as u32 + 1
+    } else {
+        slices
+    };
+    qty * (slice.min(slices) as f64 / slices as f64)
+}
+
+// How much of 'qty' should have traded to be 'percent' of the market volume.
+pub fn pov_target(qty: f64, percent: f64, market_volume: f64) -> f64 {
+    (market_volume * (percent / 100.0)).min(qty)
+}
+
+// Quantity shown on the book by an iceberg of 'parts' parts, at most 'max_parts' as
+// allowed by the exchange. None if the order can't be split.
+pub fn iceberg_qty(qty: f64, parts: u32, max_parts: u32, qty_dps: i8) -> Option<f64> {
+    let parts = parts.min(max_parts);
+    if parts < 2 {
+        return None;
+    }
+    // Rounding up keeps us within the number of parts.
+    let visible = round::ceil(qty / parts as f64, qty_dps);
+    if visible < qty {
+        Some(visible)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slicing() {
+        // A slice every 10 seconds.
+        assert_eq!(twap_target(12.0, 60, 6, Duration::from_secs(0)), 2.0);
+        assert_eq!(twap_target(12.0, 60, 6, Duration::from_secs(10)), 4.0);
+        assert_eq!(twap_target(12.0, 60, 6, Duration::from_secs(19)), 4.0);
+        assert_eq!(twap_target(12.0, 60, 6, Duration::from_secs(600)), 12.0);
+
+        assert_eq!(pov_target(10.0, 5.0, 100.0), 5.0);
+        assert_eq!(pov_target(10.0, 5.0, 1000.0), 10.0);
+
+        assert_eq!(iceberg_qty(10.0, 3, 10, 2), Some(3.34));
+        assert_eq!(iceberg_qty(10.0, 20, 10, 2), Some(1.0));
+        assert_eq!(iceberg_qty(10.0, 1, 10, 2), None);
+        assert_eq!(iceberg_qty(0.01, 3, 10, 2), None);
+    }
+
+    #[test]
+    fn parent_progress() {
+        let mut p = ParentOrder::new("BTCUSDT", true, 2.0, ExecAlgo::Direct);
+        p.pending = Some(1.0);
+        assert_eq!(p.due(2.0, 2), 1.0);
+
+        p.add_child("a", 1.0);
+        p.update_child("a", 0.5, 5.0, false);
+        assert_eq!(p.outstanding(), 0.5);
+        assert_eq!(p.due(2.0, 2), 1.0);
+
+        // The rest of the child is cancelled.
+        p.update_child("a", 0.5, 5.0, true);
+        assert!(p.children_done());
+        assert_eq!(p.due(2.0, 2), 1.5);
+
+        p.add_child("b", 1.5);
+        p.update_child("b", 1.5, 18.0, true);
+        assert_eq!(p.filled(), 2.0);
+        assert_eq!(p.filled_by_others("b"), 0.5);
+        assert_eq!(p.avg_price(), Some(11.5));
+        assert_eq!(p.progress(), 100.0);
+        assert_eq!(p.due(2.0, 2), 0.0);
+    }
+
+    #[test]
+    fn slow_child_placement() {
+        // The order thread can be held up for a while, cancelling open orders first.
+        let mut p = ParentOrder::new("BTCUSDT", true, 2.0, ExecAlgo::Direct);
+        p.pending = Some(1.0);
+        assert_eq!(p.outstanding(), 1.0);
+        assert_eq!(p.due(1.0, 2), 0.0);
+        assert!(!p.children_done());
+
+        // Placed in the end, nothing more is due until it has filled.
+        p.add_child("a", 1.0);
+        assert_eq!(p.outstanding(), 1.0);
+        assert_eq!(p.due(1.0, 2), 0.0);
+        p.update_child("a", 1.0, 10.0, true);
+        assert_eq!(p.due(2.0, 2), 1.0);
+    }
+}
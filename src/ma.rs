@@ -5,80 +5,551 @@ use crate::tradingpair;
 use position::PositionType;
 use tradingpair::TradingPair;
 
-use log::{debug, info};
+use log::{debug, log};
 use math::round;
 use std::collections::VecDeque;
 
-#[derive(Debug)]
+// Running sums are added up again from scratch after this many values, so rounding
+// errors can't build up.
+static RESUM_EVERY: u64 = 1000;
+
+// KAMA smoothing constants, those of 2 and 30 period EMAs.
+static KAMA_FAST: f64 = 2.0 / 3.0;
+static KAMA_SLOW: f64 = 2.0 / 31.0;
+
+// ALMA weights, where the gaussian peaks in the window and how wide it is.
+static ALMA_OFFSET: f64 = 0.85;
+static ALMA_SIGMA: f64 = 6.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MAType {
+    Sma,
+    // Exponential, seeded by the SMA.
+    Ema,
+    // Linearly weighted, the newest close weighs the most.
+    Wma,
+    // Hull, a WMA over sqrt(n) candles of 2 * WMA(n / 2) - WMA(n).
+    Hma,
+    // Double and triple exponential.
+    Dema,
+    Tema,
+    // Kaufman's adaptive, follows price closely in a trend and barely moves in chop.
+    Kama,
+    // Arnaud Legoux, gaussian weights peaking towards the newest close.
+    Alma,
+    // Wilder's, an EMA with a weight of 1 / n.
+    Rma,
+}
+
+impl MAType {
+    pub fn parse(s: &str) -> Option<MAType> {
+        match s.to_lowercase().as_str() {
+            "sma" => Some(MAType::Sma),
+            "ema" => Some(MAType::Ema),
+            "wma" => Some(MAType::Wma),
+            "hma" => Some(MAType::Hma),
+            "dema" => Some(MAType::Dema),
+            "tema" => Some(MAType::Tema),
+            "kama" => Some(MAType::Kama),
+            "alma" => Some(MAType::Alma),
+            "rma" => Some(MAType::Rma),
+            _ => None,
+        }
+    }
+
+    // Candles needed for the first value of an average over 'n' candles.
+    pub fn candles_required(&self, n: u16) -> u16 {
+        match self {
+            MAType::Hma => n + hull_len(n) - 1,
+            MAType::Dema => (2 * n).saturating_sub(1),
+            MAType::Tema => (3 * n).saturating_sub(2),
+            MAType::Kama => n + 1,
+            _ => n,
+        }
+    }
+}
+
+fn hull_len(n: u16) -> u16 {
+    ((n as f64).sqrt().round() as u16).max(1)
+}
+
+// The last 'len' values, newest first, and their sum.
+#[derive(Debug, Clone)]
+struct Window {
+    values: VecDeque<f64>,
+    len: usize,
+    sum: f64,
+    pushes: u64,
+}
+
+impl Window {
+    fn new(len: u16) -> Window {
+        Window {
+            values: VecDeque::with_capacity(len as usize),
+            len: len.max(1) as usize,
+            sum: 0.0,
+            pushes: 0,
+        }
+    }
+
+    // Add a value, returning the one that dropped out of the window.
+    fn push(&mut self, value: f64) -> Option<f64> {
+        let dropped = if self.values.len() == self.len {
+            self.values.pop_back()
+        } else {
+            None
+        };
+        self.values.push_front(value);
+        self.pushes += 1;
+        if self.pushes % RESUM_EVERY == 0 {
+            self.sum = self.values.iter().sum();
+        } else {
+            self.sum += value - dropped.unwrap_or(0.0);
+        }
+        dropped
+    }
+
+    fn full(&self) -> bool {
+        self.values.len() == self.len
+    }
+
+    fn mean(&self) -> Option<f64> {
+        if self.full() {
+            Some(self.sum / self.len as f64)
+        } else {
+            None
+        }
+    }
+
+    fn oldest(&self) -> Option<f64> {
+        self.values.back().cloned()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Wma {
+    window: Window,
+    // Sum of the values times their weight, the oldest weighs 1.
+    weighted: f64,
+}
+
+impl Wma {
+    fn new(len: u16) -> Wma {
+        Wma {
+            window: Window::new(len),
+            weighted: 0.0,
+        }
+    }
+
+    fn push(&mut self, value: f64) -> Option<f64> {
+        let count = self.window.values.len() as f64;
+        let sum = self.window.sum;
+        if self.window.push(value).is_some() {
+            // Every weight drops by one, the oldest to nothing.
+            self.weighted += (count * value) - sum;
+        } else {
+            self.weighted += (count + 1.0) * value;
+        }
+        if self.window.pushes % RESUM_EVERY == 0 {
+            let n = self.window.values.len();
+            self.weighted = self
+                .window
+                .values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (n - i) as f64 * v)
+                .sum();
+        }
+
+        if self.window.full() {
+            let n = self.window.len as f64;
+            Some(self.weighted / (n * (n + 1.0) / 2.0))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Ema {
+    // Closes for the SMA the average starts from.
+    seed: Window,
+    weight: f64,
+    // Apply the first close to the SMA it is part of, as our EMA always has.
+    blend_seed: bool,
+    value: Option<f64>,
+}
+
+impl Ema {
+    fn new(len: u16, weight: f64, blend_seed: bool) -> Ema {
+        Ema {
+            seed: Window::new(len),
+            weight: weight,
+            blend_seed: blend_seed,
+            value: None,
+        }
+    }
+
+    fn push(&mut self, value: f64) -> Option<f64> {
+        self.value = match self.value {
+            Some(prev) => Some((value * self.weight) + (prev * (1.0 - self.weight))),
+            None => {
+                self.seed.push(value);
+                match self.seed.mean() {
+                    Some(sma) if self.blend_seed => {
+                        Some((value * self.weight) + (sma * (1.0 - self.weight)))
+                    }
+                    sma => sma,
+                }
+            }
+        };
+        self.value
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Kama {
+    // n + 1 closes, to see how far price went over n candles.
+    closes: Window,
+    // How far price went each candle, summed over n candles.
+    noise: Window,
+    value: Option<f64>,
+}
+
+impl Kama {
+    fn push(&mut self, value: f64) -> Option<f64> {
+        if let Some(prev) = self.closes.values.front() {
+            self.noise.push((value - prev).abs());
+        }
+        self.closes.push(value);
+        if !self.closes.full() {
+            return None;
+        }
+
+        // Efficiency ratio, 1 when price moved in a straight line, 0 in pure noise.
+        let change = (value - self.closes.oldest().unwrap()).abs();
+        let er = if self.noise.sum > 0.0 {
+            (change / self.noise.sum).min(1.0)
+        } else {
+            0.0
+        };
+        let sc = ((er * (KAMA_FAST - KAMA_SLOW)) + KAMA_SLOW).powi(2);
+        self.value = match self.value {
+            Some(prev) => Some(prev + (sc * (value - prev))),
+            None => Some(value),
+        };
+        self.value
+    }
+}
+
+// Running state of the average, each type is updated without going over the whole
+// window, apart from ALMA whose gaussian weights can't be carried forward.
+#[derive(Debug, Clone)]
+enum Average {
+    Sma(Window),
+    Ema(Ema),
+    Wma(Wma),
+    Hma { half: Wma, full: Wma, hull: Wma },
+    Dema(Ema, Ema),
+    Tema(Ema, Ema, Ema),
+    Kama(Kama),
+    Alma { window: Window, weights: Vec<f64> },
+}
+
+impl Average {
+    fn new(ma_type: MAType, n: u16) -> Average {
+        let ema = || Ema::new(n, 2.0 / (n as f64 + 1.0), true);
+        match ma_type {
+            MAType::Sma => Average::Sma(Window::new(n)),
+            MAType::Ema => Average::Ema(ema()),
+            MAType::Rma => Average::Ema(Ema::new(n, 1.0 / n.max(1) as f64, false)),
+            MAType::Wma => Average::Wma(Wma::new(n)),
+            MAType::Hma => Average::Hma {
+                half: Wma::new((n / 2).max(1)),
+                full: Wma::new(n),
+                hull: Wma::new(hull_len(n)),
+            },
+            MAType::Dema => Average::Dema(ema(), ema()),
+            MAType::Tema => Average::Tema(ema(), ema(), ema()),
+            MAType::Kama => Average::Kama(Kama {
+                closes: Window::new(n + 1),
+                noise: Window::new(n),
+                value: None,
+            }),
+            MAType::Alma => {
+                let len = n.max(1) as usize;
+                let m = ALMA_OFFSET * (len - 1) as f64;
+                let s = len as f64 / ALMA_SIGMA;
+                // Oldest first.
+                let weights: Vec<f64> = (0..len)
+                    .map(|i| (-((i as f64 - m).powi(2)) / (2.0 * s * s)).exp())
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                Average::Alma {
+                    window: Window::new(n),
+                    weights: weights.iter().map(|w| w / total).collect(),
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, value: f64) -> Option<f64> {
+        match self {
+            Average::Sma(window) => {
+                window.push(value);
+                window.mean()
+            }
+            Average::Ema(ema) => ema.push(value),
+            Average::Wma(wma) => wma.push(value),
+            Average::Hma { half, full, hull } => match (half.push(value), full.push(value)) {
+                (Some(h), Some(f)) => hull.push((2.0 * h) - f),
+                _ => None,
+            },
+            Average::Dema(e1, e2) => {
+                let v1 = e1.push(value)?;
+                let v2 = e2.push(v1)?;
+                Some((2.0 * v1) - v2)
+            }
+            Average::Tema(e1, e2, e3) => {
+                let v1 = e1.push(value)?;
+                let v2 = e2.push(v1)?;
+                let v3 = e3.push(v2)?;
+                Some((3.0 * v1) - (3.0 * v2) + v3)
+            }
+            Average::Kama(kama) => kama.push(value),
+            Average::Alma { window, weights } => {
+                window.push(value);
+                if !window.full() {
+                    return None;
+                }
+                Some(
+                    window
+                        .values
+                        .iter()
+                        .rev()
+                        .zip(weights.iter())
+                        .map(|(v, w)| v * w)
+                        .sum(),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct MAData {
     latest: Option<f64>,                  // Current MA value.
     penultimate: Option<f64>,             // Previous MA value.
     penultimate_penultimate: Option<f64>, // Previous previous MA value.
 
-    // MA accumulator data.
-    pub acc: VecDeque<f64>,
-    // Number of candles required before computing the average.
+    pub ma_type: MAType,
+    average: Average,
+    // Number of candles the average is over.
     pub num_candles: u16,
 }
 
-#[derive(Debug)]
+// Candles either side of a swing high or low.
+static SWING_WIDTH: usize = 2;
+
+// Which MACD event is a signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacdMode {
+    // MACD crossing the signal line.
+    SignalCross,
+    // MACD crossing zero.
+    ZeroCross,
+    // The histogram turning up below zero or down above zero.
+    Histogram,
+    // Price making a lower swing low while MACD makes a higher one, or a higher swing
+    // high while MACD makes a lower one.
+    Divergence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MacdParams {
+    pub fast: u16,
+    pub slow: u16,
+    pub signal: u16,
+    pub mode: MacdMode,
+    // Candles to look back over for the previous swing.
+    pub divergence_lookback: u16,
+}
+
+impl Default for MacdParams {
+    fn default() -> Self {
+        MacdParams {
+            fast: 12,
+            slow: 26,
+            signal: 9,
+            mode: MacdMode::SignalCross,
+            divergence_lookback: 30,
+        }
+    }
+}
+
+impl MacdParams {
+    // Fewest candles to look back over with room for two swings, the earlier swing and
+    // the candles around it, then the latest.
+    pub fn min_divergence_lookback() -> u16 {
+        ((3 * SWING_WIDTH) + 2) as u16
+    }
+
+    // Candles needed before the first signal.
+    pub fn candles_required(&self) -> u16 {
+        let required = self.slow + self.signal;
+        match self.mode {
+            MacdMode::Divergence => required + self.divergence_lookback,
+            _ => required,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct MACD {
-    pub ema12: MAData,
-    pub ema26: MAData,
+    pub fast: MAData,
+    pub slow: MAData,
     pub signal: MAData,
     pub macd_latest: Option<f64>,
     pub macd_previous: Option<f64>,
+    pub mode: MacdMode,
+    // Last three histogram values, newest first.
+    pub histogram: VecDeque<f64>,
+    // Recent closes and MACD values, oldest first, to find swings in.
+    pub history: VecDeque<(f64, f64)>,
+    lookback: usize,
 }
 
 impl MACD {
-    pub fn new() -> Self {
+    pub fn new(params: MacdParams) -> Self {
         MACD {
-            ema12: MAData::new(12),
-            ema26: MAData::new(26),
-            signal: MAData::new(9),
+            fast: MAData::new(params.fast, MAType::Ema),
+            slow: MAData::new(params.slow, MAType::Ema),
+            signal: MAData::new(params.signal, MAType::Ema),
             macd_latest: None,
             macd_previous: None,
+            mode: params.mode,
+            histogram: VecDeque::with_capacity(3),
+            history: VecDeque::with_capacity(params.divergence_lookback as usize),
+            lookback: params.divergence_lookback as usize,
         }
     }
 
     pub fn compute(&mut self, close_price: f64) {
-        self.ema12.compute(close_price, true);
-        self.ema26.compute(close_price, true);
+        self.fast.compute(close_price);
+        self.slow.compute(close_price);
 
-        if self.ema26.latest().is_some() {
+        if self.slow.latest().is_some() {
             if self.macd_latest.is_some() {
                 self.macd_previous = self.macd_latest;
             }
 
-            let macd = self.ema12.latest().unwrap() - self.ema26.latest().unwrap();
+            let macd = self.fast.latest().unwrap() - self.slow.latest().unwrap();
             self.macd_latest = Some(macd);
-            self.signal.compute(macd, true);
+            self.signal.compute(macd);
+
+            if let Some(signal) = self.signal.latest() {
+                if self.histogram.len() == 3 {
+                    self.histogram.pop_back();
+                }
+                self.histogram.push_front(macd - signal);
+            }
+
+            if self.mode == MacdMode::Divergence {
+                if self.history.len() == self.lookback {
+                    self.history.pop_front();
+                }
+                self.history.push_back((close_price, macd));
+            }
+        }
+    }
+
+    // Long on a histogram turning up below zero, Short on one turning down above zero.
+    fn histogram_reversal(&self) -> PositionType {
+        if self.histogram.len() < 3 {
+            return PositionType::None;
+        }
+        let (h, prev, prev_prev) = (self.histogram[0], self.histogram[1], self.histogram[2]);
+        if prev < 0.0 && h > prev && prev < prev_prev {
+            PositionType::Long
+        } else if prev > 0.0 && h < prev && prev > prev_prev {
+            PositionType::Short
+        } else {
+            PositionType::None
+        }
+    }
+}
+
+// Divergence between price and MACD at a swing, seen SWING_WIDTH candles after it,
+// against the previous swing of the same kind. 'history' is of (close, macd), oldest
+// first.
+pub fn divergence(history: &[(f64, f64)]) -> PositionType {
+    let n = history.len();
+    if n < (2 * SWING_WIDTH) + 1 {
+        return PositionType::None;
+    }
+
+    let is_swing = |i: usize, low: bool| -> bool {
+        i >= SWING_WIDTH
+            && i + SWING_WIDTH < n
+            && (i - SWING_WIDTH..=i + SWING_WIDTH)
+                .filter(|j| *j != i)
+                .all(|j| {
+                    if low {
+                        history[i].0 < history[j].0
+                    } else {
+                        history[i].0 > history[j].0
+                    }
+                })
+    };
+
+    let latest = n - 1 - SWING_WIDTH;
+    for low in &[true, false] {
+        if !is_swing(latest, *low) {
+            continue;
+        }
+        if let Some(prev) = (SWING_WIDTH..latest).rev().find(|i| is_swing(*i, *low)) {
+            let (price, macd) = history[latest];
+            let (prev_price, prev_macd) = history[prev];
+            if *low && price < prev_price && macd > prev_macd {
+                return PositionType::Long;
+            }
+            if !*low && price > prev_price && macd < prev_macd {
+                return PositionType::Short;
+            }
         }
     }
+
+    PositionType::None
 }
 
 impl MAData {
-    pub fn new(num_candles: u16) -> Self {
+    pub fn new(num_candles: u16, ma_type: MAType) -> Self {
         MAData {
-            acc: VecDeque::with_capacity(num_candles as usize),
             latest: None,
             penultimate: None,
             penultimate_penultimate: None,
+            ma_type: ma_type,
+            average: Average::new(ma_type, num_candles),
             num_candles: num_candles,
         }
     }
 
-    // Current simple moving average value.
+    // Candles needed before the first value.
+    pub fn candles_required(&self) -> u16 {
+        self.ma_type.candles_required(self.num_candles)
+    }
+
+    // Current moving average value.
     pub fn latest(&self) -> Option<f64> {
         self.latest
     }
 
-    // Previous simple moving average value.
+    // Previous moving average value.
     pub fn penultimate(&self) -> Option<f64> {
         self.penultimate
     }
 
-    // Previous previous simple moving average value.
+    // Previous previous moving average value.
     pub fn penultimate_penultimate(&self) -> Option<f64> {
         self.penultimate_penultimate
     }
@@ -92,118 +563,144 @@ impl MAData {
     }
 
     // Compute the latest moving average value based on the close price.
-    pub fn compute(&mut self, close_price: f64, ema: bool) {
+    pub fn compute(&mut self, close_price: f64) {
         if self.num_candles == 0 {
             return;
         }
 
-        if self.acc.len() == self.num_candles as usize {
-            // Discard the oldest close price we saved.
-            self.acc.pop_back();
-        }
-
-        // Add the newest close price to the accumulator vector.
-        self.acc.push_front(close_price);
-        if self.acc.len() == self.num_candles as usize {
-            // We've got enough data to compute the MA.
-            let mut acc_val = 0.0;
-
-            for cp in self.acc.iter() {
-                acc_val += cp;
-            }
-
-            let new_ma = acc_val / self.num_candles as f64;
-
-            if ema {
-                let prev_ema = match self.latest() {
-                    Some(prev_ema) => prev_ema,
-                    // No previous ema exists, use the current sma value as our starting value.
-                    None => new_ma,
-                };
-
-                // https://www.investopedia.com/ask/answers/122314/what-exponential-moving-average-ema-formula-and-how-ema-calculated.asp
-                let weight = 2.0 / (self.num_candles as f64 + 1.0);
-                let ema = (close_price * weight) + (prev_ema * (1.0 - weight));
-                self.update(ema);
-            } else {
-                self.update(new_ma);
-            }
+        if let Some(new_ma) = self.average.push(close_price) {
+            self.update(new_ma);
         }
     }
 }
 
-// MACD crossing signal line.
+// MACD signal, as chosen by the MACD mode. Longs may also need the trend MA rising.
 pub fn trading_decision_macd(
     tp: &TradingPair,
     mt: &process_md::MarketDataTracker,
     closing_price: f64,
 ) -> PositionType {
-    if mt.macd.macd_latest.is_some()
-        && mt.macd.signal.latest().is_some()
-        && mt.macd.macd_previous.is_some()
+    let macd = &mt.macd;
+    let (m, macd_prev, signal) = match (macd.macd_latest, macd.macd_previous, macd.signal.latest())
     {
-        let signal = mt.macd.signal.latest().unwrap();
-        let macd_prev = mt.macd.macd_previous.unwrap();
-        let macd = mt.macd.macd_latest.unwrap();
+        (Some(m), Some(macd_prev), Some(signal)) => (m, macd_prev, signal),
+        _ => return PositionType::None,
+    };
 
-        debug!(
-            "[MACD] {}, CLOSE: {}, MACD: {}, MACD_PREV: {}, SIGNAL: {}",
-            tp.symbol(),
-            closing_price,
-            macd,
-            macd_prev,
-            signal,
-        );
+    debug!(
+        "[MACD] {}, CLOSE: {}, MACD: {}, MACD_PREV: {}, SIGNAL: {}",
+        tp.symbol(),
+        closing_price,
+        m,
+        macd_prev,
+        signal,
+    );
 
-        if macd > signal && macd_prev < signal {
+    let (decision, reason) = match macd.mode {
+        MacdMode::SignalCross => {
+            if m > signal && macd_prev < signal {
+                (
+                    PositionType::Long,
+                    format!(
+                        "MACD({}) > SIGNAL({}) > MACD_PREV({})",
+                        m, signal, macd_prev
+                    ),
+                )
+            } else if m < signal && macd_prev > signal {
+                (
+                    PositionType::Short,
+                    format!(
+                        "MACD({}) < SIGNAL({}) < MACD_PREV({})",
+                        m, signal, macd_prev
+                    ),
+                )
+            } else {
+                (PositionType::None, String::new())
+            }
+        }
+        MacdMode::ZeroCross => {
+            if m > 0.0 && macd_prev <= 0.0 {
+                (
+                    PositionType::Long,
+                    format!("MACD({}) > 0 >= MACD_PREV({})", m, macd_prev),
+                )
+            } else if m < 0.0 && macd_prev >= 0.0 {
+                (
+                    PositionType::Short,
+                    format!("MACD({}) < 0 <= MACD_PREV({})", m, macd_prev),
+                )
+            } else {
+                (PositionType::None, String::new())
+            }
+        }
+        MacdMode::Histogram => (
+            macd.histogram_reversal(),
+            format!("HISTOGRAM({:?}) reversal", macd.histogram),
+        ),
+        MacdMode::Divergence => {
+            let history: Vec<(f64, f64)> = macd.history.iter().cloned().collect();
+            let decision = divergence(&history);
+            (
+                decision,
+                format!(
+                    "{} divergence at MACD({})",
+                    if decision == PositionType::Long {
+                        "bullish"
+                    } else {
+                        "bearish"
+                    },
+                    m
+                ),
+            )
+        }
+    };
+
+    match decision {
+        PositionType::Long => {
             if mt.macd_trend_ma.num_candles > 0 {
                 let trend_ma_prev = mt.macd_trend_ma.penultimate();
                 let trend_ma_latest = mt.macd_trend_ma.latest();
 
-                if trend_ma_latest.is_some() && trend_ma_prev.is_some() {
-                    if trend_ma_latest.unwrap() >= trend_ma_prev.unwrap() {
-                        // Trending up, we can take this long.
-                        info!(
-                            "[BUY][MACD] {}, close: {}, signal: MACD({}) > SIGNAL({}) > MACD_PREV({}) TREND_UP_MA({})",
-                            tp.symbol(),
-                            closing_price,
-                            macd,
-                            signal,
-                            macd_prev,
-                            mt.macd_trend_ma.num_candles,
-                        );
-                        return position::PositionType::Long;
-                    }
+                if trend_ma_latest.is_some()
+                    && trend_ma_prev.is_some()
+                    && trend_ma_latest.unwrap() >= trend_ma_prev.unwrap()
+                {
+                    // Trending up, we can take this long.
+                    log!(
+                        mt.signal_log_level(),
+                        "[BUY][MACD] {}, close: {}, signal: {} TREND_UP_MA({})",
+                        tp.symbol(),
+                        closing_price,
+                        reason,
+                        mt.macd_trend_ma.num_candles,
+                    );
+                    return PositionType::Long;
                 }
 
-                return position::PositionType::None;
-            } else {
-                info!(
-                    "[BUY][MACD] {}, close: {}, signal: MACD({}) > SIGNAL({}) > MACD_PREV({})",
-                    tp.symbol(),
-                    closing_price,
-                    macd,
-                    signal,
-                    macd_prev,
-                );
-
-                return position::PositionType::Long;
+                return PositionType::None;
             }
-        } else if macd < signal && macd_prev > signal {
-            info!(
-                "[SELL][MACD] {}, close: {}, signal: MACD({}) < SIGNAL({}) < MACD_PREV({})",
+
+            log!(
+                mt.signal_log_level(),
+                "[BUY][MACD] {}, close: {}, signal: {}",
                 tp.symbol(),
                 closing_price,
-                macd,
-                signal,
-                macd_prev,
+                reason,
             );
-
-            return position::PositionType::Short;
+            PositionType::Long
+        }
+        PositionType::Short => {
+            log!(
+                mt.signal_log_level(),
+                "[SELL][MACD] {}, close: {}, signal: {}",
+                tp.symbol(),
+                closing_price,
+                reason,
+            );
+            PositionType::Short
         }
+        PositionType::None => PositionType::None,
     }
-
-    return PositionType::None;
 }
 
 // Trend reversal detection, returns:
@@ -236,7 +733,8 @@ pub fn trading_decision_ma_trend_change(
     );
 
     if c > p && p < pp {
-        info!(
+        log!(
+                mt.signal_log_level(),
                 "[BUY][TREND] {}, close: {}, signal: FMA({}) > FMA_PREV({}) and FMA_PREV({}) < FMA_PREV_PREV({})",
                 tp.symbol(),
                 closing_price,
@@ -248,7 +746,8 @@ pub fn trading_decision_ma_trend_change(
 
         return PositionType::Long;
     } else if c < p && p > pp {
-        info!(
+        log!(
+                mt.signal_log_level(),
                 "[SELL][TREND] {}, close: {}, signal: FMA({}) < FMA_PREV({}) and FMA_PREV({}) > FMA_PREV_PREV({})",
                 tp.symbol(),
                 closing_price,
@@ -292,7 +791,8 @@ pub fn trading_decision_ma_cross(
 
         if f_ma_latest_val > s_ma_latest_val && f_ma_prev_val < s_ma_latest_val {
             // Fast moving average is above the slow moving average
-            info!(
+            log!(
+                mt.signal_log_level(),
                 "[BUY][CROSS] {:#?}, close: {}, signal: FMA({}) > SMA({} > FMA_PREV({})",
                 tp.symbol(),
                 closing_price,
@@ -304,7 +804,8 @@ pub fn trading_decision_ma_cross(
             return PositionType::Long;
         } else if f_ma_latest_val < s_ma_latest_val && f_ma_prev_val > s_ma_latest_val {
             // Fast moving average is below the slow moving average.
-            info!(
+            log!(
+                mt.signal_log_level(),
                 "[SELL][CROSS] {:#?}, close: {}, signal: FMA({}) < SMA({}) < FMA_PREV({})",
                 tp.symbol(),
                 closing_price,
@@ -320,3 +821,137 @@ pub fn trading_decision_ma_cross(
     // No signal indicated or no change detected.
     return PositionType::None;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The latest average after feeding in a series of closes.
+    fn average_of(ma_type: MAType, n: u16, closes: &[f64]) -> Option<f64> {
+        let mut ma = MAData::new(n, ma_type);
+        for close in closes {
+            ma.compute(*close);
+        }
+        ma.latest()
+    }
+
+    #[test]
+    fn ma_types() {
+        let closes: Vec<f64> = (0..40)
+            .map(|i| 100.0 + ((i as f64) * 0.7).sin() * 5.0 + i as f64 * 0.2)
+            .collect();
+
+        for ma_type in &[
+            MAType::Sma,
+            MAType::Ema,
+            MAType::Wma,
+            MAType::Hma,
+            MAType::Dema,
+            MAType::Tema,
+            MAType::Kama,
+            MAType::Alma,
+            MAType::Rma,
+        ] {
+            let required = ma_type.candles_required(9) as usize;
+            assert_eq!(
+                average_of(*ma_type, 9, &closes[..required - 1]),
+                None,
+                "{:?}",
+                ma_type
+            );
+            assert!(
+                average_of(*ma_type, 9, &closes[..required]).is_some(),
+                "{:?}",
+                ma_type
+            );
+        }
+
+        // Sliding sums match sums over the window.
+        let last: Vec<f64> = closes[closes.len() - 5..].to_vec();
+        let sma = last.iter().sum::<f64>() / 5.0;
+        assert!((average_of(MAType::Sma, 5, &closes).unwrap() - sma).abs() < 1e-9);
+        let wma = last
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i + 1) as f64 * c)
+            .sum::<f64>()
+            / 15.0;
+        assert!((average_of(MAType::Wma, 5, &closes).unwrap() - wma).abs() < 1e-9);
+
+        // Wilder's starts from the SMA and weights each close by 1 / n.
+        let rma = ((sma * 4.0) + 110.0) / 5.0;
+        let mut with_next = closes[closes.len() - 5..].to_vec();
+        with_next.push(110.0);
+        assert!((average_of(MAType::Rma, 5, &with_next).unwrap() - rma).abs() < 1e-9);
+
+        // A straight line is followed without the lag of the SMA.
+        let line: Vec<f64> = (0..100).map(|i| i as f64).collect();
+        for ma_type in &[MAType::Dema, MAType::Tema] {
+            assert!(
+                (average_of(*ma_type, 5, &line).unwrap() - 99.0).abs() < 1e-6,
+                "{:?}",
+                ma_type
+            );
+        }
+        assert_eq!(average_of(MAType::Sma, 5, &line), Some(97.0));
+
+        assert_eq!(MAType::parse("HMA"), Some(MAType::Hma));
+        assert_eq!(MAType::parse("x"), None);
+    }
+
+    #[test]
+    fn macd_modes() {
+        let params = MacdParams {
+            fast: 2,
+            slow: 3,
+            signal: 2,
+            mode: MacdMode::Histogram,
+            divergence_lookback: 10,
+        };
+        assert_eq!(params.candles_required(), 5);
+
+        let mut macd = MACD::new(params);
+        for close in &[10.0, 10.0, 10.0, 9.0, 7.0, 4.0, 3.5] {
+            macd.compute(*close);
+        }
+        // The histogram turned up below zero.
+        assert_eq!(macd.histogram_reversal(), PositionType::Long);
+
+        // A lower low in price with a higher low in MACD.
+        let history = vec![
+            (10.0, -1.0),
+            (9.0, -2.0),
+            (8.0, -3.0),
+            (9.0, -2.0),
+            (10.0, -1.0),
+            (9.0, -1.5),
+            (7.5, -2.5),
+            (8.5, -1.0),
+            (9.5, -0.5),
+        ];
+        assert_eq!(divergence(&history), PositionType::Long);
+        assert_eq!(divergence(&history[..8]), PositionType::None);
+
+        // The fewest candles two swings fit in.
+        let shortest = vec![
+            (10.0, -1.0),
+            (9.0, -2.0),
+            (8.0, -3.0),
+            (9.0, -2.0),
+            (9.5, -1.0),
+            (7.5, -2.5),
+            (8.5, -1.0),
+            (9.5, -0.5),
+        ];
+        assert_eq!(
+            shortest.len(),
+            MacdParams::min_divergence_lookback() as usize
+        );
+        assert_eq!(divergence(&shortest), PositionType::Long);
+
+        // A higher high in MACD with it, no divergence.
+        let mut history = history.clone();
+        history[6].1 = -3.5;
+        assert_eq!(divergence(&history), PositionType::None);
+    }
+}
@@ -1,18 +1,32 @@
 mod account;
 mod account_manager;
+mod allocation;
 mod balance;
+mod bars;
 mod binance;
 mod candlestick;
 mod config;
 mod exchangeinfo;
+mod execution;
 mod ma;
 mod order;
+mod order_tracker;
 mod orderbook;
 mod position;
 mod price;
+mod pricing;
 mod process_md;
+mod risk;
+mod rsi;
+mod shutdown;
+mod sizing;
+mod state;
+mod stream_hub;
+mod supervisor;
+mod timeframe;
 mod tradingpair;
 mod utils;
+mod watchdog;
 
 use log::debug;
 
@@ -25,8 +39,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         global_config, config_file
     );
 
+    // Stop cleanly on Ctrl-C or kill.
+    shutdown::install_handlers();
+
     let strat_cfg = global_config.get_strategy();
-    process_md::run_strategy(strat_cfg, &global_config.log_dir, &exchange_config);
+    process_md::run_strategy(
+        strat_cfg,
+        &global_config.log_dir,
+        &global_config.state_dir,
+        &exchange_config,
+    );
 
     Ok(())
 }
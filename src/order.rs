@@ -8,8 +8,12 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+// Sequence number making client order ids placed in the same millisecond unique.
+static CLIENT_ORDER_SEQ: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum OrderType {
     // Simple market order.
@@ -102,10 +106,48 @@ pub struct OrderResponseAck {
     pub symbol: String,
     pub orderId: i64,
     orderListId: i64,
-    clientOrderId: String,
+    pub clientOrderId: String,
     transactTime: u64,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+pub struct OcoOrder {
+    pub symbol: String,
+    pub orderId: i64,
+    pub clientOrderId: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+pub struct OcoOrderResponse {
+    pub orderListId: i64,
+    contingencyType: String,
+    listStatusType: String,
+    listOrderStatus: String,
+    listClientOrderId: String,
+    transactionTime: u64,
+    pub symbol: String,
+    pub orders: Vec<OcoOrder>,
+}
+
+// A trade from our account trade history.
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+pub struct Trade {
+    pub symbol: String,
+    pub id: u64,
+    pub orderId: i64,
+    pub price: String,
+    pub qty: String,
+    pub quoteQty: String,
+    pub commission: String,
+    pub commissionAsset: String,
+    pub time: u64,
+    pub isBuyer: bool,
+    pub isMaker: bool,
+}
+
 impl Fill {
     #[allow(dead_code)]
     pub fn get_ave_price(&self) -> f64 {
@@ -128,14 +170,32 @@ impl Fill {
     }
 }
 
+// Our own id for a new order, every execution report for the order carries it.
+// Binance allows at most 36 characters, a long symbol is shortened to fit so the
+// time and sequence number keep ids unique.
+pub fn new_client_order_id(symbol: &str) -> String {
+    let ts_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis();
+    let seq = CLIENT_ORDER_SEQ.fetch_add(1, Ordering::Relaxed) % 10000;
+    let suffix = format!("_{}_{}", ts_now, seq);
+    let symbol: String = symbol
+        .chars()
+        .take(36usize.saturating_sub(3 + suffix.len()))
+        .collect();
+    format!("ct_{}{}", symbol, suffix)
+}
+
 fn place_limit_order_internal(
     bex: &Binance,
     tp: &TradingPair,
     position: PositionType,
     qty: f64,
     price: f64,
+    iceberg_qty: Option<f64>,
 ) -> Result<OrderResponseAck, i64> {
-    let mut order_params: HashMap<&str, &str> = HashMap::with_capacity(6);
+    let mut order_params: HashMap<&str, &str> = HashMap::with_capacity(7);
     order_params.insert("symbol", tp.symbol());
     order_params.insert("side", "SELL");
     order_params.insert("timeInForce", "GTC");
@@ -145,6 +205,12 @@ fn place_limit_order_internal(
     let price_str = price.to_string();
     order_params.insert("price", &price_str);
 
+    // Only part of an iceberg order is shown on the book, it must be GTC.
+    let iceberg_str = iceberg_qty.map(|q| q.to_string());
+    if let Some(q) = &iceberg_str {
+        order_params.insert("icebergQty", q);
+    }
+
     if position == PositionType::Long {
         order_params.insert("side", "BUY");
     } else if position == PositionType::Short {
@@ -159,6 +225,9 @@ fn place_limit_order_internal(
 
     order_params.insert("timestamp", &ts_str);
 
+    let cid = new_client_order_id(tp.symbol());
+    order_params.insert("newClientOrderId", &cid);
+
     bex.send_order(&mut order_params, false)
 }
 
@@ -168,9 +237,17 @@ pub fn place_order_quantity(
     tp: &TradingPair,
     quantity: f64,
     limit_price: Option<f64>,
+    iceberg_qty: Option<f64>,
 ) -> Result<OrderResponseAck, i64> {
     if limit_price.is_some() {
-        place_limit_order_internal(ex, tp, position, quantity, limit_price.unwrap())
+        place_limit_order_internal(
+            ex,
+            tp,
+            position,
+            quantity,
+            limit_price.unwrap(),
+            iceberg_qty,
+        )
     } else {
         let mut order_params: HashMap<&str, &str> = HashMap::with_capacity(6);
         order_params.insert("symbol", tp.symbol());
@@ -194,10 +271,40 @@ pub fn place_order_quantity(
         let t = ts_now.to_string();
         order_params.insert("timestamp", &t);
 
+        let cid = new_client_order_id(tp.symbol());
+        order_params.insert("newClientOrderId", &cid);
+
         ex.send_order(&mut order_params, false)
     }
 }
 
+// Sell the given quantity at market, used when we must get out of a position.
+pub fn place_market_sell(
+    ex: &Binance,
+    symbol: &str,
+    quantity: f64,
+) -> Result<OrderResponseAck, i64> {
+    let mut order_params: HashMap<&str, &str> = HashMap::with_capacity(5);
+    order_params.insert("symbol", symbol);
+    order_params.insert("side", "SELL");
+    order_params.insert("type", "MARKET");
+
+    let q_str = quantity.to_string();
+    order_params.insert("quantity", &q_str);
+
+    let ts_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64;
+    let t = ts_now.to_string();
+    order_params.insert("timestamp", &t);
+
+    let cid = new_client_order_id(symbol);
+    order_params.insert("newClientOrderId", &cid);
+
+    ex.send_order(&mut order_params, false)
+}
+
 pub fn place_stop_limit(
     ex: &Binance,
     symbol: &str,
@@ -230,5 +337,71 @@ pub fn place_stop_limit(
     let t = ts_now.to_string();
     order_params.insert("timestamp", &t);
 
+    let cid = new_client_order_id(symbol);
+    order_params.insert("newClientOrderId", &cid);
+
     ex.send_stop_order(&order_params)
 }
+
+// Place an OCO sell order list, a LIMIT_MAKER take profit leg plus a STOP_LOSS_LIMIT
+// leg. When either leg executes the exchange cancels the other.
+pub fn place_oco_sell(
+    ex: &Binance,
+    symbol: &str,
+    quantity: f64,
+    take_profit_price: f64,
+    stop_trigger_price: f64,
+    stop_limit_price: f64,
+) -> Result<OcoOrderResponse, i64> {
+    let mut order_params: HashMap<&str, &str> = HashMap::with_capacity(9);
+
+    order_params.insert("symbol", symbol);
+    order_params.insert("side", "SELL");
+
+    let q_str = quantity.to_string();
+    order_params.insert("quantity", &q_str);
+
+    // Take profit leg, rests on the book as a LIMIT_MAKER.
+    let tp_str = take_profit_price.to_string();
+    order_params.insert("price", &tp_str);
+
+    // Stop loss leg.
+    let st_str = stop_trigger_price.to_string();
+    order_params.insert("stopPrice", &st_str);
+    let sl_str = stop_limit_price.to_string();
+    order_params.insert("stopLimitPrice", &sl_str);
+    order_params.insert("stopLimitTimeInForce", "GTC");
+
+    let ts_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64;
+    let t = ts_now.to_string();
+    order_params.insert("timestamp", &t);
+
+    // Each leg gets its own id so its execution reports can be told apart.
+    let list_cid = new_client_order_id(symbol);
+    order_params.insert("listClientOrderId", &list_cid);
+    let limit_cid = new_client_order_id(symbol);
+    order_params.insert("limitClientOrderId", &limit_cid);
+    let stop_cid = new_client_order_id(symbol);
+    order_params.insert("stopClientOrderId", &stop_cid);
+
+    ex.send_oco_order(&order_params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_order_ids() {
+        let a = new_client_order_id("ABCDEFGHIJKLMNOPQRSTUSDT");
+        let b = new_client_order_id("ABCDEFGHIJKLMNOPQRSTUSDT");
+        assert!(a.len() <= 36);
+        assert!(a.starts_with("ct_ABCDEFGHIJ"));
+        assert_ne!(a, b);
+
+        assert!(new_client_order_id("BTCUSDT").starts_with("ct_BTCUSDT_"));
+    }
+}
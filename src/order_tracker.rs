@@ -0,0 +1,333 @@
+// Order lifecycle tracking (order_tracker.rs).
+//
+// Every order we place carries our own newClientOrderId, execution reports from
+// the user data stream are applied to the order with that id moving it through:
+//
+// NEW --> PARTIALLY_FILLED --> FILLED
+//  |             |
+//  +-------------+-----------> CANCELED / EXPIRED / REJECTED
+use log::error;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Canceled,
+    Expired,
+    Rejected,
+}
+
+impl OrderStatus {
+    pub fn from_binance(status: &str) -> Option<OrderStatus> {
+        match status {
+            "NEW" => Some(OrderStatus::New),
+            "PARTIALLY_FILLED" => Some(OrderStatus::PartiallyFilled),
+            "FILLED" => Some(OrderStatus::Filled),
+            "CANCELED" => Some(OrderStatus::Canceled),
+            "EXPIRED" => Some(OrderStatus::Expired),
+            "REJECTED" => Some(OrderStatus::Rejected),
+            _ => None,
+        }
+    }
+
+    // No more fills can happen once an order reaches a final state.
+    pub fn is_final(&self) -> bool {
+        match self {
+            OrderStatus::New | OrderStatus::PartiallyFilled => false,
+            _ => true,
+        }
+    }
+
+    fn can_move_to(&self, next: OrderStatus) -> bool {
+        match self {
+            OrderStatus::New => true,
+            OrderStatus::PartiallyFilled => next != OrderStatus::New,
+            _ => false,
+        }
+    }
+}
+
+// The parts of an executionReport event we need.
+#[derive(Debug, Clone)]
+pub struct ExecutionReport {
+    pub client_order_id: String,
+    pub order_id: u64,
+    pub order_list_id: i64,
+    pub symbol: String,
+    pub side: String,
+    pub order_type: String,
+    pub time_in_force: String,
+    pub status: String,
+    pub orig_qty: f64,
+    pub last_filled_qty: f64,
+    pub last_filled_price: f64,
+    pub commission: f64,
+    pub commission_asset: String,
+    pub trade_id: i64,
+    pub on_book: bool,
+}
+
+impl ExecutionReport {
+    pub fn from_json(payload: &serde_json::Value) -> Option<ExecutionReport> {
+        let f64_field = |k: &str| -> f64 {
+            payload[k]
+                .as_str()
+                .unwrap_or("0.0")
+                .parse::<f64>()
+                .unwrap_or(0.0)
+        };
+
+        // Cancellations carry the id of the cancel request in 'c' and the id of the
+        // order that was cancelled in 'C'.
+        let client_order_id = match payload["C"].as_str() {
+            Some(orig) if !orig.is_empty() => orig,
+            _ => payload["c"].as_str()?,
+        };
+
+        Some(ExecutionReport {
+            client_order_id: client_order_id.to_string(),
+            order_id: payload["i"].as_u64()?,
+            order_list_id: payload["g"].as_i64().unwrap_or(-1),
+            symbol: payload["s"].as_str()?.to_string(),
+            side: payload["S"].as_str()?.to_string(),
+            order_type: payload["o"].as_str()?.to_string(),
+            time_in_force: payload["f"].as_str().unwrap_or("").to_string(),
+            status: payload["X"].as_str()?.to_string(),
+            orig_qty: f64_field("q"),
+            last_filled_qty: f64_field("l"),
+            last_filled_price: f64_field("L"),
+            commission: f64_field("n"),
+            commission_asset: payload["N"].as_str().unwrap_or("NONE").to_string(),
+            trade_id: payload["t"].as_i64().unwrap_or(-1),
+            on_book: payload["w"].as_bool().unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackedOrder {
+    pub client_order_id: String,
+    #[allow(dead_code)]
+    pub order_id: u64,
+    pub symbol: String,
+    pub side: String,
+    pub order_type: String,
+    pub status: OrderStatus,
+    #[allow(dead_code)]
+    pub orig_qty: f64,
+    pub filled_qty: f64,
+    // Sum of price * qty over all fills.
+    pub filled_quote: f64,
+    pub commission_usdt: f64,
+}
+
+impl TrackedOrder {
+    // Quantity weighted average fill price.
+    pub fn ave_price(&self) -> Option<f64> {
+        if self.filled_qty > 0.0 {
+            Some(self.filled_quote / self.filled_qty)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_buy(&self) -> bool {
+        self.side.eq("BUY")
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OrderTracker {
+    orders: HashMap<String, TrackedOrder>,
+}
+
+impl OrderTracker {
+    pub fn new() -> Self {
+        OrderTracker {
+            orders: HashMap::new(),
+        }
+    }
+
+    // Apply an execution report, 'commission_usdt' is the commission of this report's
+    // fill converted to USDT. Returns the order as it now stands or None if the report
+    // could not be applied.
+    pub fn update(&mut self, er: &ExecutionReport, commission_usdt: f64) -> Option<TrackedOrder> {
+        let status = match OrderStatus::from_binance(&er.status) {
+            Some(status) => status,
+            None => {
+                error!(
+                    "unknown status {} for order {}",
+                    er.status, er.client_order_id
+                );
+                return None;
+            }
+        };
+
+        // Orders placed outside of ct, or before a restart, are picked up on their first
+        // report.
+        let order = self
+            .orders
+            .entry(er.client_order_id.clone())
+            .or_insert_with(|| TrackedOrder {
+                client_order_id: er.client_order_id.clone(),
+                order_id: er.order_id,
+                symbol: er.symbol.clone(),
+                side: er.side.clone(),
+                order_type: er.order_type.clone(),
+                status: OrderStatus::New,
+                orig_qty: er.orig_qty,
+                filled_qty: 0.0,
+                filled_quote: 0.0,
+                commission_usdt: 0.0,
+            });
+
+        if order.status != status && !order.status.can_move_to(status) {
+            error!(
+                "order {} can't move from {:?} to {:?}",
+                order.client_order_id, order.status, status
+            );
+            return None;
+        }
+
+        order.status = status;
+        if er.last_filled_qty > 0.0 {
+            order.filled_qty += er.last_filled_qty;
+            order.filled_quote += er.last_filled_qty * er.last_filled_price;
+            order.commission_usdt += commission_usdt;
+        }
+
+        let order = order.clone();
+        if status.is_final() {
+            self.orders.remove(&er.client_order_id);
+        }
+
+        Some(order)
+    }
+
+    // Pick up an order from the exchange's list of open orders, for example when the
+    // event thread starts again. Its fills so far are taken from the order, their
+    // commission is unknown.
+    pub fn track_open(&mut self, o: &serde_json::Value) -> Option<()> {
+        let f64_field =
+            |k: &str| -> f64 { o[k].as_str().unwrap_or("0.0").parse::<f64>().unwrap_or(0.0) };
+        let client_order_id = o["clientOrderId"].as_str()?.to_string();
+        let order = TrackedOrder {
+            client_order_id: client_order_id.clone(),
+            order_id: o["orderId"].as_u64()?,
+            symbol: o["symbol"].as_str()?.to_string(),
+            side: o["side"].as_str()?.to_string(),
+            order_type: o["type"].as_str()?.to_string(),
+            status: OrderStatus::from_binance(o["status"].as_str()?)?,
+            orig_qty: f64_field("origQty"),
+            filled_qty: f64_field("executedQty"),
+            filled_quote: f64_field("cummulativeQuoteQty"),
+            commission_usdt: 0.0,
+        };
+        self.orders.entry(client_order_id).or_insert(order);
+        Some(())
+    }
+
+    pub fn get(&self, client_order_id: &str) -> Option<&TrackedOrder> {
+        self.orders.get(client_order_id)
+    }
+
+    // Orders which have not yet reached a final state.
+    #[allow(dead_code)]
+    pub fn open_orders(&self, symbol: &str) -> Vec<&TrackedOrder> {
+        self.orders
+            .values()
+            .filter(|o| o.symbol.eq(symbol))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_orders_after_restart() {
+        let mut tracker = OrderTracker::new();
+        tracker.track_open(&serde_json::json!({
+            "clientOrderId": "ct_test_1",
+            "orderId": 1,
+            "symbol": "ADAUSDT",
+            "side": "BUY",
+            "type": "LIMIT",
+            "status": "PARTIALLY_FILLED",
+            "origQty": "10.0",
+            "executedQty": "4.0",
+            "cummulativeQuoteQty": "8.0",
+        }));
+
+        // The rest fills.
+        let mut er = report("FILLED", 6.0, 2.5);
+        er.client_order_id = String::from("ct_test_1");
+        let order = tracker.update(&er, 0.0).unwrap();
+        assert_eq!(order.filled_qty, 10.0);
+        assert_eq!(order.ave_price(), Some(2.3));
+        assert!(tracker.get("ct_test_1").is_none());
+    }
+
+    fn report(status: &str, qty: f64, price: f64) -> ExecutionReport {
+        ExecutionReport {
+            client_order_id: String::from("ct_test_1"),
+            order_id: 1,
+            order_list_id: -1,
+            symbol: String::from("ADAUSDT"),
+            side: String::from("BUY"),
+            order_type: String::from("LIMIT"),
+            time_in_force: String::from("GTC"),
+            status: String::from(status),
+            orig_qty: 30.0,
+            last_filled_qty: qty,
+            last_filled_price: price,
+            commission: 0.0,
+            commission_asset: String::from("BNB"),
+            trade_id: -1,
+            on_book: true,
+        }
+    }
+
+    #[test]
+    fn quantity_weighted_fills() {
+        let mut ot = OrderTracker::new();
+        ot.update(&report("NEW", 0.0, 0.0), 0.0).unwrap();
+        let o = ot
+            .update(&report("PARTIALLY_FILLED", 10.0, 1.0), 0.01)
+            .unwrap();
+        assert_eq!(o.status, OrderStatus::PartiallyFilled);
+        let o = ot.update(&report("FILLED", 20.0, 2.5), 0.02).unwrap();
+        assert_eq!(o.status, OrderStatus::Filled);
+        assert_eq!(o.filled_qty, 30.0);
+        assert!((o.ave_price().unwrap() - 2.0).abs() < 1e-9);
+        assert!((o.commission_usdt - 0.03).abs() < 1e-9);
+
+        // Final orders are no longer tracked.
+        assert!(ot.get("ct_test_1").is_none());
+    }
+
+    #[test]
+    fn invalid_transition() {
+        let mut ot = OrderTracker::new();
+        ot.update(&report("PARTIALLY_FILLED", 10.0, 1.0), 0.0)
+            .unwrap();
+        assert!(ot.update(&report("NEW", 0.0, 0.0), 0.0).is_none());
+        let o = ot.update(&report("CANCELED", 0.0, 0.0), 0.0).unwrap();
+        assert_eq!(o.status, OrderStatus::Canceled);
+        assert_eq!(o.filled_qty, 10.0);
+    }
+
+    #[test]
+    fn cancel_uses_original_client_order_id() {
+        let payload = serde_json::json!({
+            "e": "executionReport", "s": "ADAUSDT", "c": "cancel_req", "C": "ct_test_1",
+            "S": "BUY", "o": "LIMIT", "f": "GTC", "q": "30.0", "X": "CANCELED",
+            "i": 1, "l": "0.0", "L": "0.0", "n": "0", "N": null, "t": -1, "g": -1,
+        });
+        let er = ExecutionReport::from_json(&payload).unwrap();
+        assert_eq!(er.client_order_id, "ct_test_1");
+    }
+}
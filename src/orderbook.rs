@@ -1,4 +1,31 @@
+// Order books (orderbook.rs).
+//
+// OrderBook is a REST snapshot of the book. LocalOrderBook is a live copy of the
+// book kept up to date from the <symbol>@depth diff stream, following the Binance
+// procedure:
+//
+// Subscribe to the diff stream, buffering its events.
+// Get a snapshot, events with u <= lastUpdateId of the snapshot are dropped.
+// The first event applied must have U <= lastUpdateId + 1 <= u.
+// Each event after must have U == u + 1 of the previous event, if not there is a
+// gap and the book is resynced from a new snapshot.
+use crate::binance::Binance;
+use crate::config::ExchangeConfig;
+use crate::shutdown;
+use crate::stream_hub::StreamHub;
+use crate::supervisor::{LockExt, Supervisor};
+
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Prices are keyed in units of 1e-8 so they can be ordered.
+static PRICE_SCALE: f64 = 100_000_000.0;
+// Levels in the snapshot, the most Binance returns.
+static SNAPSHOT_DEPTH: u16 = 1000;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BidAsk {
@@ -24,4 +51,371 @@ impl OrderBook {
     pub fn get_asks(&self) -> &Vec<BidAsk> {
         &self.asks
     }
+
+    pub fn last_update_id(&self) -> u64 {
+        self.lastUpdateId
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookSide {
+    Bid,
+    Ask,
+}
+
+// The parts of a depthUpdate event we need.
+#[derive(Debug, Clone)]
+pub struct DepthUpdate {
+    pub first_update_id: u64,
+    pub final_update_id: u64,
+    pub bids: Vec<(f64, f64)>,
+    pub asks: Vec<(f64, f64)>,
+}
+
+impl DepthUpdate {
+    pub fn from_json(payload: &serde_json::Value) -> Option<DepthUpdate> {
+        let levels = |k: &str| -> Option<Vec<(f64, f64)>> {
+            payload[k]
+                .as_array()?
+                .iter()
+                .map(|l| {
+                    Some((
+                        l[0].as_str()?.parse::<f64>().ok()?,
+                        l[1].as_str()?.parse::<f64>().ok()?,
+                    ))
+                })
+                .collect()
+        };
+
+        Some(DepthUpdate {
+            first_update_id: payload["U"].as_u64()?,
+            final_update_id: payload["u"].as_u64()?,
+            bids: levels("b")?,
+            asks: levels("a")?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DiffResult {
+    Applied,
+    // Older than the book, ignored.
+    Stale,
+    // Updates are missing, the book must be resynced.
+    Gap,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LocalOrderBook {
+    bids: BTreeMap<i64, f64>,
+    asks: BTreeMap<i64, f64>,
+    last_update_id: u64,
+    // False until the first diff after the snapshot has been applied.
+    synced: bool,
+}
+
+fn price_key(price: f64) -> i64 {
+    (price * PRICE_SCALE).round() as i64
+}
+
+fn key_price(key: i64) -> f64 {
+    key as f64 / PRICE_SCALE
+}
+
+fn apply_levels(side: &mut BTreeMap<i64, f64>, levels: &[(f64, f64)]) {
+    for (price, qty) in levels {
+        if *qty == 0.0 {
+            side.remove(&price_key(*price));
+        } else {
+            side.insert(price_key(*price), *qty);
+        }
+    }
+}
+
+impl LocalOrderBook {
+    pub fn from_snapshot(snapshot: &OrderBook) -> LocalOrderBook {
+        let parse = |levels: &[BidAsk]| -> Vec<(f64, f64)> {
+            levels
+                .iter()
+                .filter_map(|l| Some((l.price.parse::<f64>().ok()?, l.qty.parse::<f64>().ok()?)))
+                .collect()
+        };
+
+        let mut book = LocalOrderBook {
+            last_update_id: snapshot.last_update_id(),
+            ..Default::default()
+        };
+        apply_levels(&mut book.bids, &parse(&snapshot.bids));
+        apply_levels(&mut book.asks, &parse(&snapshot.asks));
+        book
+    }
+
+    pub fn apply(&mut self, update: &DepthUpdate) -> DiffResult {
+        if update.final_update_id <= self.last_update_id {
+            return DiffResult::Stale;
+        }
+
+        let expected = self.last_update_id + 1;
+        let in_sequence = if self.synced {
+            update.first_update_id == expected
+        } else {
+            update.first_update_id <= expected && update.final_update_id >= expected
+        };
+        if !in_sequence {
+            return DiffResult::Gap;
+        }
+
+        apply_levels(&mut self.bids, &update.bids);
+        apply_levels(&mut self.asks, &update.asks);
+        self.last_update_id = update.final_update_id;
+        self.synced = true;
+        DiffResult::Applied
+    }
+
+    // (price, quantity) of the best bid.
+    pub fn best_bid(&self) -> Option<(f64, f64)> {
+        self.bids
+            .iter()
+            .next_back()
+            .map(|(k, q)| (key_price(*k), *q))
+    }
+
+    // (price, quantity) of the best ask.
+    pub fn best_ask(&self) -> Option<(f64, f64)> {
+        self.asks.iter().next().map(|(k, q)| (key_price(*k), *q))
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        Some((self.best_bid()?.0 + self.best_ask()?.0) / 2.0)
+    }
+
+    pub fn spread(&self) -> Option<f64> {
+        Some(self.best_ask()?.0 - self.best_bid()?.0)
+    }
+
+    pub fn spread_bps(&self) -> Option<f64> {
+        Some((self.spread()? / self.mid()?) * 10_000.0)
+    }
+
+    // Quantity on one side of the book within 'bps' of the mid price.
+    pub fn depth(&self, side: BookSide, bps: f64) -> Option<f64> {
+        let mid = self.mid()?;
+        let qty = match side {
+            BookSide::Bid => {
+                let limit = price_key(mid * (1.0 - (bps / 10_000.0)));
+                self.bids.range(limit..).map(|(_, q)| q).sum()
+            }
+            BookSide::Ask => {
+                let limit = price_key(mid * (1.0 + (bps / 10_000.0)));
+                self.asks.range(..=limit).map(|(_, q)| q).sum()
+            }
+        };
+        Some(qty)
+    }
+
+    // (bid - ask) / (bid + ask) of the quantity within 'bps' of the mid price, from
+    // -1.0, all asks, to 1.0, all bids.
+    pub fn imbalance(&self, bps: f64) -> Option<f64> {
+        let bid = self.depth(BookSide::Bid, bps)?;
+        let ask = self.depth(BookSide::Ask, bps)?;
+        if bid + ask == 0.0 {
+            return None;
+        }
+        Some((bid - ask) / (bid + ask))
+    }
+
+    // (price, quantity) of the levels on one side, best first.
+    pub fn levels(&self, bids: bool) -> Vec<(f64, f64)> {
+        if bids {
+            self.bids
+                .iter()
+                .rev()
+                .map(|(k, q)| (key_price(*k), *q))
+                .collect()
+        } else {
+            self.asks.iter().map(|(k, q)| (key_price(*k), *q)).collect()
+        }
+    }
+
+    // Average price of buying or selling 'qty' at market, None if the book is not
+    // deep enough.
+    #[allow(dead_code)]
+    pub fn sweep_price(&self, side: BookSide, qty: f64) -> Option<f64> {
+        let levels: Box<dyn Iterator<Item = (&i64, &f64)>> = match side {
+            // Buying takes the asks, selling the bids.
+            BookSide::Ask => Box::new(self.asks.iter()),
+            BookSide::Bid => Box::new(self.bids.iter().rev()),
+        };
+
+        let mut remaining = qty;
+        let mut cost = 0.0;
+        for (k, q) in levels {
+            let take = remaining.min(*q);
+            cost += take * key_price(*k);
+            remaining -= take;
+            if remaining <= 0.0 {
+                return Some(cost / qty);
+            }
+        }
+        None
+    }
+}
+
+// Live books shared between the threads which keep them and those which read them.
+#[derive(Debug, Clone, Default)]
+pub struct OrderBooks {
+    books: Arc<Mutex<HashMap<String, LocalOrderBook>>>,
+}
+
+impl OrderBooks {
+    pub fn new() -> OrderBooks {
+        OrderBooks::default()
+    }
+
+    // Start keeping a live book for 'symbol'.
+    pub fn track(
+        &self,
+        ec: &ExchangeConfig,
+        hub: &StreamHub,
+        supervisor: &Supervisor,
+        symbol: &str,
+    ) {
+        let books = self.clone();
+        let ec = ec.clone();
+        let hub = hub.clone();
+        let symbol = symbol.to_string();
+        supervisor.spawn(&format!("{} order book", symbol), move || {
+            books.book_thread(&ec, &hub, &symbol)
+        });
+    }
+
+    // Copy of the book for 'symbol', None if it is not tracked or not yet in sync.
+    pub fn get(&self, symbol: &str) -> Option<LocalOrderBook> {
+        match self.books.locked().get(symbol) {
+            Some(book) if book.synced => Some(book.clone()),
+            _ => None,
+        }
+    }
+
+    fn book_thread(
+        &self,
+        ec: &ExchangeConfig,
+        hub: &StreamHub,
+        symbol: &str,
+    ) -> Result<(), String> {
+        let bex = Binance::new(ec.clone());
+        // Events arriving while we get the snapshot wait in the channel.
+        let updates = hub.subscribe(&format!("{}@depth@100ms", symbol.to_lowercase()));
+
+        while !shutdown::requested() {
+            let snapshot = bex
+                .get_order_book(symbol, Some(SNAPSHOT_DEPTH))
+                .map_err(|code| format!("failed to get {} order book: {}", symbol, code))?;
+            info!(
+                "{} order book snapshot at {}",
+                symbol,
+                snapshot.last_update_id()
+            );
+            self.books
+                .locked()
+                .insert(symbol.to_string(), LocalOrderBook::from_snapshot(&snapshot));
+
+            while !shutdown::requested() {
+                let payload = match updates.recv_timeout(Duration::from_secs(60)) {
+                    Ok(payload) => payload,
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        return Err(format!("{} depth stream closed", symbol));
+                    }
+                };
+
+                let update = match DepthUpdate::from_json(&payload) {
+                    Some(update) => update,
+                    None => {
+                        warn!("{} unexpected depth update: {}", symbol, payload);
+                        continue;
+                    }
+                };
+
+                let result = match self.books.locked().get_mut(symbol) {
+                    Some(book) => book.apply(&update),
+                    None => DiffResult::Gap,
+                };
+                if result == DiffResult::Gap {
+                    warn!("{} gap in order book updates, resyncing", symbol);
+                    // Nobody may price off the old levels while we get a new snapshot.
+                    self.books.locked().remove(symbol);
+                    break;
+                }
+                debug!("{} order book {:?}", symbol, result);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> OrderBook {
+        serde_json::from_value(serde_json::json!({
+            "lastUpdateId": 100,
+            "bids": [["9.9", "10.0"], ["9.8", "20.0"]],
+            "asks": [["10.1", "5.0"], ["10.2", "15.0"]],
+        }))
+        .unwrap()
+    }
+
+    fn update(first: u64, last: u64, bids: Vec<(f64, f64)>, asks: Vec<(f64, f64)>) -> DepthUpdate {
+        DepthUpdate {
+            first_update_id: first,
+            final_update_id: last,
+            bids: bids,
+            asks: asks,
+        }
+    }
+
+    #[test]
+    fn sequencing() {
+        let mut book = LocalOrderBook::from_snapshot(&snapshot());
+        assert_eq!(
+            book.apply(&update(90, 100, vec![], vec![])),
+            DiffResult::Stale
+        );
+        // First event must straddle lastUpdateId + 1.
+        assert_eq!(
+            book.apply(&update(102, 105, vec![], vec![])),
+            DiffResult::Gap
+        );
+        assert_eq!(
+            book.apply(&update(95, 103, vec![(9.9, 0.0)], vec![])),
+            DiffResult::Applied
+        );
+        assert_eq!(book.best_bid(), Some((9.8, 20.0)));
+        // Then they must follow on.
+        assert_eq!(
+            book.apply(&update(104, 106, vec![], vec![])),
+            DiffResult::Applied
+        );
+        assert_eq!(
+            book.apply(&update(108, 110, vec![], vec![])),
+            DiffResult::Gap
+        );
+    }
+
+    #[test]
+    fn book_metrics() {
+        let book = LocalOrderBook::from_snapshot(&snapshot());
+        assert_eq!(book.mid(), Some(10.0));
+        assert!((book.spread().unwrap() - 0.2).abs() < 1e-9);
+        assert!((book.spread_bps().unwrap() - 200.0).abs() < 1e-6);
+        // 150bps from 10.0 takes in 9.9 and 10.1 only.
+        assert_eq!(book.depth(BookSide::Bid, 150.0), Some(10.0));
+        assert_eq!(book.depth(BookSide::Ask, 150.0), Some(5.0));
+        assert!((book.imbalance(150.0).unwrap() - (5.0 / 15.0)).abs() < 1e-9);
+        assert!((book.sweep_price(BookSide::Ask, 10.0).unwrap() - 10.15).abs() < 1e-9);
+        assert_eq!(book.sweep_price(BookSide::Ask, 100.0), None);
+    }
 }
@@ -1,13 +1,38 @@
-#[derive(Debug, PartialEq, Clone, Copy)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 pub enum PositionType {
     Long,
     Short,
     None,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Position {
     pub r#type: PositionType,
     pub qty: f64,
+    // Average entry price.
     pub price: f64,
+    // Number of buys made to build the position.
+    #[serde(default)]
+    pub tranches: u8,
+    // Number of partial profit targets taken.
+    #[serde(default)]
+    pub targets_hit: u8,
+    // Largest quantity held, partial exits are a share of this.
+    #[serde(default)]
+    pub peak_qty: f64,
+}
+
+impl Position {
+    pub fn new(r#type: PositionType, qty: f64, price: f64) -> Position {
+        Position {
+            r#type: r#type,
+            qty: qty,
+            price: price,
+            tranches: 1,
+            targets_hit: 0,
+            peak_qty: qty,
+        }
+    }
 }
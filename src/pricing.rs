@@ -0,0 +1,260 @@
+// Limit order pricing (pricing.rs).
+//
+// Limit prices are either worked out from the last close, closing price plus or
+// minus LimitOffset ticks, or from the live order book:
+//
+// join: the best bid for buys, the best ask for sells.
+// improve: N ticks better than the best bid or ask, without crossing the spread.
+// mid: the mid price.
+// cross: up to X bps through the best ask for buys, the best bid for sells.
+//
+// The expected fill price of an order is worked out from the depth of the book, the
+// order is not placed if it is too far from the mid price.
+//
+// A limit order which does not fill can be repriced toward the market every so
+// often, up to a most it may move from its first price, and dealt with once it has
+// been working too long.
+use crate::orderbook::LocalOrderBook;
+
+use math::round;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitPrice {
+    // Ticks away from the last close, turned into a Fixed price by the strategy.
+    Offset(u8),
+    Fixed(f64),
+    Join,
+    Improve(u8),
+    Mid,
+    Cross(f64),
+}
+
+impl LimitPrice {
+    // Does this need the live order book.
+    pub fn needs_book(&self) -> bool {
+        match self {
+            LimitPrice::Offset(_) | LimitPrice::Fixed(_) => false,
+            _ => true,
+        }
+    }
+}
+
+// What to do with a limit order which has not filled in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutAction {
+    Cancel,
+    // Cancel and sell or buy what is left at market.
+    Market,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FillPolicy {
+    pub reprice_secs: Option<u64>,
+    // Most a repriced order may move from its first price.
+    pub max_chase_bps: f64,
+    pub timeout_secs: Option<u64>,
+    pub on_timeout: TimeoutAction,
+}
+
+impl Default for FillPolicy {
+    fn default() -> Self {
+        FillPolicy {
+            reprice_secs: None,
+            max_chase_bps: 20.0,
+            timeout_secs: None,
+            on_timeout: TimeoutAction::Cancel,
+        }
+    }
+}
+
+impl FillPolicy {
+    // Do limit orders need watching at all.
+    pub fn active(&self) -> bool {
+        self.reprice_secs.is_some() || self.timeout_secs.is_some()
+    }
+}
+
+// Round a price to a whole number of ticks, down for buys and up for sells so we
+// never pay more than we asked for.
+fn to_tick(price: f64, tick: f64, price_dps: i8, buy: bool) -> f64 {
+    let ticks = price / tick;
+    // Allow for the price already being on a tick but not quite in binary.
+    let ticks = if buy {
+        (ticks + 1e-9).floor()
+    } else {
+        (ticks - 1e-9).ceil()
+    };
+    round::half_up(ticks * tick, price_dps)
+}
+
+// Limit price from the book, None if the book is one sided or the mode does not
+// use the book.
+pub fn book_price(
+    mode: LimitPrice,
+    book: &LocalOrderBook,
+    buy: bool,
+    tick: f64,
+    price_dps: i8,
+) -> Option<f64> {
+    let (bid, _) = book.best_bid()?;
+    let (ask, _) = book.best_ask()?;
+
+    let price = match mode {
+        LimitPrice::Offset(_) | LimitPrice::Fixed(_) => return None,
+        LimitPrice::Join => {
+            if buy {
+                bid
+            } else {
+                ask
+            }
+        }
+        LimitPrice::Improve(ticks) => {
+            let improve = tick * ticks as f64;
+            if buy {
+                (bid + improve).min(ask - tick).max(bid)
+            } else {
+                (ask - improve).max(bid + tick).min(ask)
+            }
+        }
+        LimitPrice::Mid => book.mid()?,
+        LimitPrice::Cross(bps) => {
+            if buy {
+                ask * (1.0 + (bps / 10_000.0))
+            } else {
+                bid * (1.0 - (bps / 10_000.0))
+            }
+        }
+    };
+
+    Some(to_tick(price, tick, price_dps, buy))
+}
+
+// Average price we expect to fill 'qty' at with a limit order at 'limit', what it
+// takes from the other side of the book fills straight away, the rest rests at the
+// limit price.
+pub fn expected_fill_price(book: &LocalOrderBook, buy: bool, qty: f64, limit: f64) -> f64 {
+    let mut remaining = qty;
+    let mut cost = 0.0;
+    for (price, level_qty) in book.levels(!buy) {
+        let crosses = if buy { price <= limit } else { price >= limit };
+        if !crosses || remaining <= 0.0 {
+            break;
+        }
+        let take = remaining.min(level_qty);
+        cost += take * price;
+        remaining -= take;
+    }
+
+    (cost + (remaining * limit)) / qty
+}
+
+// How much worse than the mid price, in bps, we expect to fill. Negative when we
+// expect to fill better than the mid price.
+pub fn slippage_bps(book: &LocalOrderBook, buy: bool, qty: f64, limit: f64) -> Option<f64> {
+    let mid = book.mid()?;
+    let fill = expected_fill_price(book, buy, qty, limit);
+    let slippage = if buy { fill - mid } else { mid - fill };
+    Some((slippage / mid) * 10_000.0)
+}
+
+// New price for an order resting at 'current', first placed at 'first', chasing a
+// market now at 'target'. The price only moves toward the market and never more than
+// 'max_bps' from the first price, None if it should stay where it is.
+pub fn chase_price(
+    buy: bool,
+    current: f64,
+    first: f64,
+    target: f64,
+    max_bps: f64,
+    tick: f64,
+    price_dps: i8,
+) -> Option<f64> {
+    let price = if buy {
+        target.min(first * (1.0 + (max_bps / 10_000.0)))
+    } else {
+        target.max(first * (1.0 - (max_bps / 10_000.0)))
+    };
+    let price = to_tick(price, tick, price_dps, buy);
+
+    let better = if buy {
+        price > current
+    } else {
+        price < current
+    };
+    if better {
+        Some(price)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::OrderBook;
+
+    fn book() -> LocalOrderBook {
+        let snapshot: OrderBook = serde_json::from_value(serde_json::json!({
+            "lastUpdateId": 1,
+            "bids": [["9.98", "10.0"], ["9.97", "20.0"]],
+            "asks": [["10.02", "5.0"], ["10.03", "15.0"]],
+        }))
+        .unwrap();
+        LocalOrderBook::from_snapshot(&snapshot)
+    }
+
+    #[test]
+    fn prices_from_the_book() {
+        let b = book();
+        assert_eq!(book_price(LimitPrice::Join, &b, true, 0.01, 2), Some(9.98));
+        assert_eq!(
+            book_price(LimitPrice::Join, &b, false, 0.01, 2),
+            Some(10.02)
+        );
+        assert_eq!(
+            book_price(LimitPrice::Improve(2), &b, true, 0.01, 2),
+            Some(10.0)
+        );
+        // Improving never crosses the spread.
+        assert_eq!(
+            book_price(LimitPrice::Improve(9), &b, true, 0.01, 2),
+            Some(10.01)
+        );
+        assert_eq!(book_price(LimitPrice::Mid, &b, true, 0.01, 2), Some(10.0));
+        // 20bps through 10.02 is 10.04004, rounded down to the tick.
+        assert_eq!(
+            book_price(LimitPrice::Cross(20.0), &b, true, 0.01, 2),
+            Some(10.04)
+        );
+    }
+
+    #[test]
+    fn chasing() {
+        // Market moved up 5 ticks, we follow.
+        assert_eq!(
+            chase_price(true, 10.0, 10.0, 10.05, 100.0, 0.01, 2),
+            Some(10.05)
+        );
+        // But no further than 20bps from the first price.
+        assert_eq!(
+            chase_price(true, 10.0, 10.0, 10.5, 20.0, 0.01, 2),
+            Some(10.02)
+        );
+        // Never away from the market.
+        assert_eq!(chase_price(true, 10.0, 10.0, 9.9, 20.0, 0.01, 2), None);
+        assert_eq!(
+            chase_price(false, 10.0, 10.0, 9.95, 100.0, 0.01, 2),
+            Some(9.95)
+        );
+    }
+
+    #[test]
+    fn slippage_from_depth() {
+        let b = book();
+        // 5 fill at 10.02 and 5 at 10.03.
+        assert!((expected_fill_price(&b, true, 10.0, 10.04) - 10.025).abs() < 1e-9);
+        assert!((slippage_bps(&b, true, 10.0, 10.04).unwrap() - 25.0).abs() < 1e-6);
+        // Resting below the ask fills at the limit.
+        assert!((slippage_bps(&b, true, 10.0, 9.98).unwrap() + 20.0).abs() < 1e-6);
+    }
+}
@@ -1,40 +1,68 @@
 // Process market data (process_md.rs).
 use crate::account_manager;
+use crate::allocation;
+use crate::bars;
 use crate::binance;
 use crate::candlestick;
 use crate::config;
+use crate::execution;
 use crate::ma;
 use crate::order;
+use crate::orderbook;
 use crate::position;
+use crate::pricing;
+use crate::risk;
+use crate::rsi;
+use crate::shutdown;
+use crate::sizing;
+use crate::stream_hub;
+use crate::supervisor;
+use crate::timeframe;
 use crate::tradingpair;
+use crate::watchdog;
 
 use math::round;
 use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Instant;
 use std::{thread, time::Duration};
-use websocket::{stream::sync::NetworkStream, sync::Client, ClientBuilder, OwnedMessage};
 
 use serde_json;
 
-use log::{debug, error, info};
+use log::{debug, error, info, log};
 
-use account_manager::{AccountManager, OrderQuantity};
+use account_manager::{AccountManager, ExistingPositionPolicy, ExitParams, OrderQuantity};
+use allocation::Allocation;
+use bars::{BarBuilder, BarSpec, BarType, Trades};
 use binance::Binance;
 use config::{ExchangeConfig, StrategyConfig};
+use execution::ExecAlgo;
+use orderbook::OrderBooks;
 use position::PositionType;
+use pricing::{FillPolicy, LimitPrice, TimeoutAction};
+use risk::{RiskDecision, RiskLimits, RiskManager};
+use sizing::PositionSizing;
+use stream_hub::StreamHub;
+use supervisor::Supervisor;
+use timeframe::HigherTimeFrame;
 use tradingpair::TradingPair;
+use watchdog::{kline_interval_ms, DataWatchdog, WatchdogLimits};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum TradeSignal {
     MaCross,
     MaTrendReversal,
     MACD,
+    RSI,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MarketDataTracker {
     pub slow_ma_data: ma::MAData,
     pub fast_ma_data: ma::MAData,
     pub macd: ma::MACD,
+    pub rsi: rsi::RSI,
 
     pub desired_position: PositionType,
 
@@ -44,25 +72,33 @@ pub struct MarketDataTracker {
     // Previous candles, green or red?
     pub candle_color_history: Vec<candlestick::CandleColor>,
 
-    // Exponential or simple MA.
-    pub ema: bool,
-
     // Are we using BLVTs or not?
     pub bvlt: bool,
 
     // Market/Limit?
     pub order_type: order::OrderType,
 
-    // % Away from the last close price we'll accept for a limit order.
-    pub limit_offset: Option<u8>,
+    // How limit orders are priced.
+    pub limit_price: Option<LimitPrice>,
+
+    // Entries expected to fill further than this from the mid price are not placed.
+    pub max_slippage_bps: Option<f64>,
+
+    // Stop loss and take profit settings for the exits placed once a buy fills.
+    pub exit_params: ExitParams,
+
+    // How much to spend when entering a position.
+    pub sizing: PositionSizing,
 
-    // % Away from the average fill price that we want to set our stop loss at.
-    pub stop_percent: Option<f64>,
+    // Maximum number of buys to build a position from and the % gain over the
+    // average entry price required before each further buy.
+    pub scale_in: Option<(u8, f64)>,
 
-    // % Gain we are happy to take a profit at.
-    pub take_profit_percent: Option<f64>,
+    // Partial profit targets, % gain over the average entry price and % of the
+    // largest position size to sell, in ascending order of gain.
+    pub scale_out: Vec<(f64, f64)>,
 
-    // If trade_signal is TradeSignal::MACD then we want this number of green
+    // If trade_signal is TradeSignal::MACD or RSI then we want this number of green
     // candle before entering a position even if the signal has been triggered.
     // Same goes in the reverse direction for red candles.
     pub confirmation_candles: Option<u8>,
@@ -70,11 +106,44 @@ pub struct MarketDataTracker {
     // If we are using the macd as the primary indicator we might also have a
     // trend MA we need to be above in order to take a long position.
     pub macd_trend_ma: ma::MAData,
+
+    // Live order books, empty unless OrderBook is set.
+    pub books: OrderBooks,
+
+    // Higher time frames, entries must go with the trend of each of them.
+    pub higher_tfs: Vec<HigherTimeFrame>,
+
+    // In intrabar mode, how long an entry signal on the forming candle must hold
+    // before we act on it. None unless Intrabar is set.
+    pub intrabar_hold: Option<Duration>,
+
+    // Entry signal seen on the forming candle and since when.
+    pub intrabar_signal: Option<(PositionType, Instant)>,
+
+    // What we have already traded on the forming candle.
+    pub intrabar_acted: Option<PositionType>,
+
+    // Set on the copy used to evaluate the forming candle, its signals are only
+    // logged at debug.
+    pub provisional: bool,
+}
+
+impl MarketDataTracker {
+    pub fn signal_log_level(&self) -> log::Level {
+        if self.provisional {
+            log::Level::Debug
+        } else {
+            log::Level::Info
+        }
+    }
 }
 
 // The number of ticks away from the last closing price that we will accept.
 static DEFAULT_LIMIT_RANGE: u8 = 2;
 
+// Book imbalance is measured over the levels within this many bps of the mid price.
+static BOOK_IMBALANCE_BPS: f64 = 10.0;
+
 // Check & update if the last required number of candles are all green or all red.
 fn trade_confirmation_via_previous_candles(
     mt: &mut MarketDataTracker,
@@ -132,6 +201,7 @@ fn trading_decision(
                 ma::trading_decision_ma_trend_change(&trading_pair, mt, closing_price)
             }
             TradeSignal::MACD => ma::trading_decision_macd(&trading_pair, mt, closing_price),
+            TradeSignal::RSI => rsi::trading_decision_rsi(&trading_pair, mt, closing_price),
         };
 
         // Update the list of previous candle colours and return if we've matched a number in
@@ -162,6 +232,25 @@ fn trading_decision(
             true
         };
 
+        // Entries must go with the trend on every higher time frame, as of their last
+        // closed candles.
+        if decision == PositionType::Long {
+            if let Some(htf) = mt
+                .higher_tfs
+                .iter()
+                .find(|htf| htf.trend() != PositionType::Long)
+            {
+                log!(
+                    mt.signal_log_level(),
+                    "{} long signal ignored, {} trend is {:?}",
+                    trading_pair.symbol(),
+                    htf.interval,
+                    htf.trend()
+                );
+                decision = PositionType::None;
+            }
+        }
+
         // Check if we have any open positions at the moment.
         let cur_position = am.get_position(trading_pair.symbol());
         let cur_position_type = match cur_position {
@@ -169,13 +258,18 @@ fn trading_decision(
             None => PositionType::None,
         };
 
-        // Maybe override the signals if we hit a profit target.
-        let take_profit_override = if mt.take_profit_percent.is_some() {
+        // Maybe override the signals if we hit a profit target. When a stop is also
+        // configured the take profit rests on the exchange as part of an OCO order
+        // list so there is nothing to do here.
+        let take_profit_override = if mt.exit_params.take_profit_percent.is_some()
+            && mt.exit_params.stop_percent.is_none()
+        {
             match cur_position {
                 Some((r#type, _qty, price)) => {
                     if r#type == PositionType::Long
                         && (closing_price
-                            >= (price + ((price / 100.0) * mt.take_profit_percent.unwrap())))
+                            >= (price
+                                + ((price / 100.0) * mt.exit_params.take_profit_percent.unwrap())))
                     {
                         true
                     } else {
@@ -192,19 +286,30 @@ fn trading_decision(
         if take_profit_override {
             if cur_position_type == PositionType::Long {
                 decision = PositionType::Short;
-                info!(
+                log!(
+                    mt.signal_log_level(),
                     "{:#?} take profit hit: close price: {}, +{}% from entry, will sell",
                     trading_pair.symbol(),
                     closing_price,
-                    mt.take_profit_percent.unwrap(),
+                    mt.exit_params.take_profit_percent.unwrap(),
                 );
             }
         }
 
         if decision == cur_position_type {
             decision = PositionType::None;
-        } else if (confirmed || take_profit_override) && decision != PositionType::None {
-            info!(
+        } else if !confirmed && !take_profit_override && decision != PositionType::None {
+            log!(
+                mt.signal_log_level(),
+                "{} {:?} signal ignored, not confirmed by the last {} candles",
+                trading_pair.symbol(),
+                decision,
+                mt.confirmation_candles.unwrap_or(0),
+            );
+            decision = PositionType::None;
+        } else if decision != PositionType::None {
+            log!(
+                mt.signal_log_level(),
                 "{:#?} trade decision changed: {:#?} --> {:#?}",
                 trading_pair.symbol(),
                 cur_position_type,
@@ -216,67 +321,175 @@ fn trading_decision(
     return decision;
 }
 
-// Update all required TA indicators and check if we should make a trade, if we should
-// then we submit an order to the AccountManager.
-fn process_close_data(
+// With no new signal, check if we should add to a winning position or take part of
+// our profit.
+fn scale_decision(
     am: &AccountManager,
     trading_pair: &TradingPair,
-    mt: &mut MarketDataTracker,
+    mt: &MarketDataTracker,
     closing_price: f64,
-    prev_closing_price: Option<f64>,
-    place_trades: bool,
-) {
-    // Compute the various technical indicators.
+) -> Option<(PositionType, OrderQuantity)> {
+    if trading_pair.get_bvlt_type().is_some() {
+        return None;
+    }
+
+    let pos = am.get_position_detail(trading_pair.symbol())?;
+    if pos.r#type != PositionType::Long {
+        return None;
+    }
+
+    if let Some((target_pct, sell_pct)) = mt.scale_out.get(pos.targets_hit as usize) {
+        if closing_price >= pos.price + ((pos.price / 100.0) * target_pct) {
+            info!(
+                "{} profit target {} hit: close price: {}, +{}% from entry, selling {}%",
+                trading_pair.symbol(),
+                pos.targets_hit + 1,
+                closing_price,
+                target_pct,
+                sell_pct
+            );
+            let quantity = if pos.targets_hit as usize + 1 == mt.scale_out.len() {
+                OrderQuantity::Percentage100
+            } else {
+                OrderQuantity::Exact(pos.peak_qty * (sell_pct / 100.0))
+            };
+            return Some((PositionType::Short, quantity));
+        }
+    }
+
+    if let Some((tranches, step_pct)) = mt.scale_in {
+        // Only add before taking any profit.
+        if pos.tranches < tranches
+            && pos.targets_hit == 0
+            && closing_price >= pos.price + ((pos.price / 100.0) * step_pct)
+        {
+            info!(
+                "{} adding tranche {} of {}: close price: {}, +{}% from entry",
+                trading_pair.symbol(),
+                pos.tranches + 1,
+                tranches,
+                closing_price,
+                step_pct
+            );
+            return Some((
+                PositionType::Long,
+                OrderQuantity::Tranche(pos.tranches + 1, tranches),
+            ));
+        }
+    }
+
+    None
+}
+
+// Update the indicators with the closing price of a candle.
+fn compute_indicators(mt: &mut MarketDataTracker, closing_price: f64) {
     match mt.trade_signal {
         TradeSignal::MaCross => {
-            mt.slow_ma_data.compute(closing_price, mt.ema);
-            mt.fast_ma_data.compute(closing_price, mt.ema);
+            mt.slow_ma_data.compute(closing_price);
+            mt.fast_ma_data.compute(closing_price);
         }
         TradeSignal::MaTrendReversal => {
-            mt.fast_ma_data.compute(closing_price, mt.ema);
+            mt.fast_ma_data.compute(closing_price);
         }
         TradeSignal::MACD => {
             mt.macd.compute(closing_price);
 
             if mt.macd_trend_ma.num_candles > 0 {
-                mt.macd_trend_ma.compute(closing_price, mt.ema);
+                mt.macd_trend_ma.compute(closing_price);
             }
         }
+        TradeSignal::RSI => {
+            mt.rsi.compute(closing_price);
+        }
     }
+}
 
-    if !place_trades {
-        // If we just want to process the data then return now.
-        return;
+// Based on the latest TA and currently active position, compute the best new
+// position for us to take and how much to trade.
+fn signal_decision(
+    am: &AccountManager,
+    trading_pair: &TradingPair,
+    mt: &mut MarketDataTracker,
+    closing_price: f64,
+    prev_closing_price: Option<f64>,
+) -> (PositionType, OrderQuantity) {
+    let decision = trading_decision(am, trading_pair, mt, closing_price, prev_closing_price);
+
+    // Entries are a tranche of the position when scaling in, exits are for everything.
+    let quantity = match mt.scale_in {
+        Some((tranches, _)) if decision == PositionType::Long => {
+            OrderQuantity::Tranche(1, tranches)
+        }
+        _ => OrderQuantity::Percentage100,
+    };
+
+    if decision == PositionType::None {
+        match scale_decision(am, trading_pair, mt, closing_price) {
+            Some(d) => d,
+            None => (decision, quantity),
+        }
+    } else {
+        (decision, quantity)
     }
+}
 
-    // Based on the latest TA and currently active position, compute the best new
-    // position for us to take.
-    let decision = trading_decision(am, trading_pair, mt, closing_price, prev_closing_price);
+// Pass a decision by the risk manager and submit an order to the AccountManager.
+fn place_trade(
+    am: &AccountManager,
+    risk: &RiskManager,
+    trading_pair: &TradingPair,
+    mt: &MarketDataTracker,
+    decision: PositionType,
+    quantity: OrderQuantity,
+    closing_price: f64,
+) {
+    // The risk manager has the final say.
+    let (decision, quantity, max_notional, order_type) =
+        match risk.review(am, trading_pair, decision, closing_price) {
+            RiskDecision::Allow(max_notional) => (decision, quantity, max_notional, mt.order_type),
+            RiskDecision::Block => (PositionType::None, quantity, None, mt.order_type),
+            RiskDecision::Flatten => (
+                PositionType::Short,
+                OrderQuantity::Percentage100,
+                None,
+                order::OrderType::Market,
+            ),
+        };
 
     match decision {
         PositionType::None => {}
         PositionType::Short | PositionType::Long => {
-            // Compute the limit prices we are willing to accept for BUY/SELL orders.
-            let limit_price = if mt.order_type == order::OrderType::Limit {
-                let tick_increment = trading_pair.get_tick_size();
-                if decision == PositionType::Long {
-                    Some(round::floor(
-                        closing_price
-                            + (tick_increment
-                                * mt.limit_offset
-                                    .expect("limit offset is None but this is a limit order")
-                                    as f64),
-                        trading_pair.get_price_dps(),
-                    ))
-                } else {
-                    Some(round::floor(
-                        closing_price
-                            - (tick_increment
-                                * mt.limit_offset
-                                    .expect("limit offset is None but this is a limit order")
-                                    as f64),
-                        trading_pair.get_price_dps(),
-                    ))
+            if let Some(book) = mt.books.get(trading_pair.symbol()) {
+                info!(
+                    "{} {:?} book bid: {:?}, ask: {:?}, spread: {:.1}bps, imbalance: {:.2}",
+                    trading_pair.symbol(),
+                    decision,
+                    book.best_bid(),
+                    book.best_ask(),
+                    book.spread_bps().unwrap_or(0.0),
+                    book.imbalance(BOOK_IMBALANCE_BPS).unwrap_or(0.0)
+                );
+            }
+
+            // Compute the limit prices we are willing to accept for BUY/SELL orders,
+            // those priced from the book are worked out when the order is placed.
+            let limit_price = if order_type == order::OrderType::Limit {
+                match mt
+                    .limit_price
+                    .expect("limit price is None but this is a limit order")
+                {
+                    LimitPrice::Offset(ticks) => {
+                        let offset = trading_pair.get_tick_size() * ticks as f64;
+                        Some(LimitPrice::Fixed(round::floor(
+                            if decision == PositionType::Long {
+                                closing_price + offset
+                            } else {
+                                closing_price - offset
+                            },
+                            trading_pair.get_price_dps(),
+                        )))
+                    }
+                    mode => Some(mode),
                 }
             } else {
                 // Using MARKET orders.
@@ -287,36 +500,368 @@ fn process_close_data(
             am.spot_trade(
                 trading_pair.clone(),
                 decision,
-                OrderQuantity::Percentage100,
+                quantity,
                 limit_price,
-                mt.stop_percent,
+                mt.max_slippage_bps,
+                mt.exit_params,
+                mt.sizing.clone(),
+                max_notional,
             );
         }
     }
 }
 
-// Reconnect to the websocket stream.
-fn reconnect_stream(
-    ws_client: &mut ClientBuilder,
-) -> Option<Client<Box<dyn NetworkStream + std::marker::Send>>> {
-    let mut cur_try = 0;
-    let max_tries = 5;
-    while cur_try < max_tries {
-        cur_try += 1;
-        if let Ok(c) = ws_client.connect(None) {
-            c.stream_ref()
-                .as_tcp()
-                .set_read_timeout(Some(Duration::new(60 * 1, 0)))
-                .expect("failed to set read timeout");
-            info!("connected to kline stream");
-            return Some(c);
-        } else {
-            error!("failed to reconnect to kline stream");
-            thread::sleep(Duration::from_millis(5000 * cur_try));
+// Update all required TA indicators and check if we should make a trade, if we should
+// then we submit an order to the AccountManager.
+fn process_close_data(
+    am: &AccountManager,
+    risk: &RiskManager,
+    trading_pair: &TradingPair,
+    mt: &mut MarketDataTracker,
+    closing_price: f64,
+    prev_closing_price: Option<f64>,
+    place_trades: bool,
+) {
+    // Compute the various technical indicators.
+    compute_indicators(mt, closing_price);
+
+    // A new candle starts.
+    let acted = mt.intrabar_acted.take();
+    mt.intrabar_signal = None;
+
+    if !place_trades {
+        // If we just want to process the data then return now.
+        return;
+    }
+
+    let (decision, quantity) =
+        signal_decision(am, trading_pair, mt, closing_price, prev_closing_price);
+
+    if decision != PositionType::None && acted == Some(decision) {
+        info!(
+            "{} {:?} already traded on the forming candle",
+            trading_pair.symbol(),
+            decision
+        );
+        return;
+    }
+
+    place_trade(
+        am,
+        risk,
+        trading_pair,
+        mt,
+        decision,
+        quantity,
+        closing_price,
+    );
+}
+
+// Evaluate the indicators on the forming candle, on a copy so nothing is committed
+// until it closes. Exits are acted on straight away, entries once the signal has
+// held for the intrabar hold time. Each is acted on at most once per candle.
+fn process_intrabar_data(
+    am: &AccountManager,
+    risk: &RiskManager,
+    trading_pair: &TradingPair,
+    mt: &mut MarketDataTracker,
+    price: f64,
+    prev_closing_price: Option<f64>,
+) {
+    let hold = match mt.intrabar_hold {
+        Some(hold) => hold,
+        None => return,
+    };
+
+    let mut provisional = mt.clone();
+    provisional.provisional = true;
+    compute_indicators(&mut provisional, price);
+    let (decision, quantity) = signal_decision(
+        am,
+        trading_pair,
+        &mut provisional,
+        price,
+        prev_closing_price,
+    );
+
+    if decision == PositionType::None || mt.intrabar_acted == Some(decision) {
+        mt.intrabar_signal = None;
+        return;
+    }
+
+    if decision == PositionType::Long {
+        let since = match mt.intrabar_signal {
+            Some((PositionType::Long, since)) => since,
+            _ => {
+                mt.intrabar_signal = Some((decision, Instant::now()));
+                Instant::now()
+            }
+        };
+        if since.elapsed() < hold {
+            return;
         }
     }
 
-    None
+    info!(
+        "{} intrabar {:?} signal at {}",
+        trading_pair.symbol(),
+        decision,
+        price
+    );
+    mt.intrabar_acted = Some(decision);
+    mt.intrabar_signal = None;
+    place_trade(am, risk, trading_pair, mt, decision, quantity, price);
+}
+
+// Range of open times, inclusive, of the candles missing between the last candle we
+// processed, which closed at 'last_close_time', and a candle opening at 'open_time'.
+fn missing_candles(last_close_time: u64, open_time: u64) -> Option<(u64, u64)> {
+    if open_time > last_close_time + 1 {
+        Some((last_close_time + 1, open_time - 1))
+    } else {
+        None
+    }
+}
+
+// Fetch the candles which closed while we were not receiving the stream, for example
+// while reconnecting, and replay them without trading so the moving averages are
+// correct. Returns the closing price and close time of the last candle replayed.
+fn backfill_candles(
+    bex: &Binance,
+    am: &AccountManager,
+    risk: &RiskManager,
+    tp: &TradingPair,
+    mt: &mut MarketDataTracker,
+    time_frame: &str,
+    range: (u64, u64),
+    mut prev_closing_price: Option<f64>,
+) -> Option<(f64, u64)> {
+    let (mut start, end) = range;
+    let mut last = None;
+
+    // At most 1000 candles are returned per request.
+    while start <= end {
+        let start_time = start.to_string();
+        let end_time = end.to_string();
+        let mut req_params: HashMap<&str, &str> = HashMap::with_capacity(5);
+        req_params.insert("symbol", tp.symbol());
+        req_params.insert("interval", time_frame);
+        req_params.insert("startTime", &start_time);
+        req_params.insert("endTime", &end_time);
+        req_params.insert("limit", "1000");
+
+        let cd = match bex.get_cstick_data(&req_params) {
+            Ok(cd) => cd,
+            Err(code) => {
+                error!("{} failed to backfill candles: {}", tp.symbol(), code);
+                return last;
+            }
+        };
+
+        if cd.is_empty() {
+            break;
+        }
+
+        for stick in cd.iter().filter(|c| c.close_time <= end) {
+            if let Ok(closing_price) = stick.close_price.parse::<f64>() {
+                process_close_data(am, risk, tp, mt, closing_price, prev_closing_price, false);
+                prev_closing_price = Some(closing_price);
+                last = Some((closing_price, stick.close_time));
+            }
+        }
+
+        start = cd.last().unwrap().close_time + 1;
+    }
+
+    if let Some((_, close_time)) = last {
+        info!(
+            "{} backfilled candles from {} to {}",
+            tp.symbol(),
+            range.0,
+            close_time
+        );
+    }
+    last
+}
+
+// Apply any higher time frame candles which have closed.
+fn update_higher_time_frames(
+    bex: &Binance,
+    tp: &TradingPair,
+    mt: &mut MarketDataTracker,
+    streams: &[mpsc::Receiver<serde_json::Value>],
+) {
+    for (htf, rx) in mt.higher_tfs.iter_mut().zip(streams.iter()) {
+        while let Ok(k) = rx.try_recv() {
+            if htf.on_kline(&k["k"]) {
+                info!(
+                    "{} {} candles missed, warming up again",
+                    tp.symbol(),
+                    htf.interval
+                );
+                if let Err(code) = htf.warm_up(bex, tp.symbol()) {
+                    error!(
+                        "{} failed to get {} cstick data: {}",
+                        tp.symbol(),
+                        htf.interval,
+                        code
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Build candles from the trade stream rather than using the exchange klines. The
+// indicators are first warmed up from 1m klines, each added as trades at its prices.
+fn process_bar_data(
+    bex: &Binance,
+    hub: &StreamHub,
+    am: &AccountManager,
+    risk: &RiskManager,
+    tp: &TradingPair,
+    mt: &mut MarketDataTracker,
+    spec: BarSpec,
+    candles_required: u64,
+    watchdog_limits: WatchdogLimits,
+    htf_streams: &[mpsc::Receiver<serde_json::Value>],
+) -> Result<(), String> {
+    let mut builder = BarBuilder::new(spec.bar_type, spec.heikin_ashi);
+    let mut prev_closing_price: Option<f64> = None;
+
+    let now = match bex.get_server_time() {
+        Ok(st) => st,
+        Err(_) => return Err(format!("{} failed to get server time", tp.symbol())),
+    };
+    let minutes = match spec.bar_type {
+        BarType::Time(interval) => (candles_required + 1) * ((interval / 60_000) + 1),
+        _ => spec.warmup_hours * 60,
+    };
+    let end = ((now / 60_000) * 60_000).saturating_sub(1);
+    let mut start = end.saturating_sub(minutes * 60_000) + 1;
+
+    // At most 1000 candles are returned per request.
+    while start < end {
+        let start_time = start.to_string();
+        let end_time = end.to_string();
+        let mut req_params: HashMap<&str, &str> = HashMap::with_capacity(5);
+        req_params.insert("symbol", tp.symbol());
+        req_params.insert("interval", "1m");
+        req_params.insert("startTime", &start_time);
+        req_params.insert("endTime", &end_time);
+        req_params.insert("limit", "1000");
+
+        let cd = match bex.get_cstick_data(&req_params) {
+            Ok(cd) => cd,
+            Err(code) => {
+                return Err(format!(
+                    "{} failed to get cstick data: {}",
+                    tp.symbol(),
+                    code
+                ))
+            }
+        };
+        if cd.is_empty() {
+            break;
+        }
+
+        for stick in cd.iter().filter(|c| c.close_time <= end) {
+            let price = |p: &str| p.parse::<f64>().unwrap_or(0.0);
+            let trades = Trades {
+                time: stick.close_time,
+                open: price(&stick.open_price),
+                high: price(&stick.high_price),
+                low: price(&stick.low_price),
+                close: price(&stick.close_price),
+                qty: price(&stick.vol),
+                quote: price(&stick.quote_asset_vol),
+                count: stick.num_trades,
+            };
+            for bar in builder.add(&trades) {
+                process_close_data(am, risk, tp, mt, bar.close, prev_closing_price, false);
+                prev_closing_price = Some(bar.close);
+            }
+        }
+
+        start = cd.last().unwrap().close_time + 1;
+    }
+
+    let stream = format!(
+        "{}@{}",
+        tp.symbol().to_lowercase(),
+        if spec.aggregated { "aggTrade" } else { "trade" }
+    );
+    let trades = hub.subscribe(&stream);
+    info!(
+        "{} building {:?} candles from {}",
+        tp.symbol(),
+        spec,
+        stream
+    );
+
+    let mut watchdog = DataWatchdog::new(tp.symbol(), watchdog_limits, builder.interval_ms());
+    let mut last_trade_time = now;
+    let mut reconnected = Instant::now();
+    while !shutdown::requested() {
+        watchdog.check_clock(bex);
+        let overdue = watchdog.check_candle_due(last_trade_time);
+
+        let closed = match trades.recv_timeout(Duration::from_secs(1)) {
+            Ok(t) => match Trades::from_json(&t) {
+                Some(trades) => {
+                    watchdog.candle_received(t["E"].as_u64().unwrap_or(0));
+                    last_trade_time = trades.time;
+                    builder.add(&trades)
+                }
+                None => {
+                    error!("failed to parse trade: {}", t);
+                    continue;
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if overdue && reconnected.elapsed() >= Duration::from_secs(60) {
+                    // The stream has stalled, start it again.
+                    hub.reconnect(&stream);
+                    reconnected = Instant::now();
+                }
+                // Time candles close on the clock when there are no trades.
+                builder.close_due(chrono::Utc::now().timestamp_millis() as u64)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(format!("{} trade stream closed", tp.symbol()));
+            }
+        };
+
+        update_higher_time_frames(bex, tp, mt, htf_streams);
+
+        // The forming candle, in intrabar mode.
+        if let (true, Some(_), true) = (
+            closed.is_empty(),
+            mt.intrabar_hold,
+            watchdog.trading_allowed(),
+        ) {
+            if let Some(bar) = builder.forming() {
+                process_intrabar_data(am, risk, tp, mt, bar.close, prev_closing_price);
+            }
+        }
+
+        for bar in closed {
+            debug!("{} candle closed: {:?}", tp.symbol(), bar);
+            process_close_data(
+                am,
+                risk,
+                tp,
+                mt,
+                bar.close,
+                prev_closing_price,
+                watchdog.trading_allowed(),
+            );
+            prev_closing_price = Some(bar.close);
+        }
+    }
+
+    info!("{} market data thread exiting", tp.symbol());
+    Ok(())
 }
 
 // Process market data for the given trading pair and time frame, this processing
@@ -324,24 +869,36 @@ fn reconnect_stream(
 // thread.
 fn process_market_data_thread(
     ec: ExchangeConfig,
-    log_dir: String,
+    hub: StreamHub,
+    books: OrderBooks,
+    am: AccountManager,
+    risk: Arc<RiskManager>,
     tp: TradingPair,
     time_frame: String,
     slow_ma: Option<u16>,
     fast_ma: Option<u16>,
     bvlt: bool,
-    ema: bool,
+    ma_type: ma::MAType,
     signal: TradeSignal,
     order_type: order::OrderType,
-    limit_offset: Option<u8>,
-    stop_percent: Option<f64>,
-    take_profit_percent: Option<f64>,
+    limit_price: Option<LimitPrice>,
+    max_slippage_bps: Option<f64>,
+    exit_params: ExitParams,
+    sizing: PositionSizing,
+    scale_in: Option<(u8, f64)>,
+    scale_out: Vec<(f64, f64)>,
     confirmation_candles: Option<u8>,
     macd_trend_ma: Option<u16>,
-) {
+    macd_params: ma::MacdParams,
+    rsi_params: rsi::RsiParams,
+    watchdog_limits: WatchdogLimits,
+    bars: Option<BarSpec>,
+    intrabar_hold: Option<Duration>,
+    higher_time_frames: Vec<(String, u16)>,
+) -> Result<(), String> {
     info!(
-        "starting {}ma compute thread for {:#?} using time frame {:#?} slow ma: {:#?}, fast ma {:#?}, signal: {:#?}",
-        if ema { "e" } else { "s" },
+        "starting {:?} compute thread for {:#?} using time frame {:#?} slow ma: {:#?}, fast ma {:#?}, signal: {:#?}",
+        ma_type,
         tp.symbol(),
         time_frame,
         slow_ma,
@@ -350,40 +907,88 @@ fn process_market_data_thread(
     );
 
     let mut prev_closing_price: Option<f64> = None;
-    let ec_am = ec.clone();
+    // Close time of the last candle processed, used to spot gaps in the stream.
+    let mut last_close_time: Option<u64> = None;
     let bex = Binance::new(ec);
-    let am = AccountManager::new(ec_am, false, log_dir);
     let mut mt = MarketDataTracker {
-        slow_ma_data: ma::MAData::new(slow_ma.unwrap_or(0)),
-        fast_ma_data: ma::MAData::new(fast_ma.unwrap_or(0)),
-        macd: ma::MACD::new(),
+        slow_ma_data: ma::MAData::new(slow_ma.unwrap_or(0), ma_type),
+        fast_ma_data: ma::MAData::new(fast_ma.unwrap_or(0), ma_type),
+        macd: ma::MACD::new(macd_params),
+        rsi: rsi::RSI::new(rsi_params),
         desired_position: PositionType::None,
         candle_color_history: Vec::with_capacity(confirmation_candles.unwrap_or(0) as usize),
-        ema: ema,
         bvlt: bvlt,
         trade_signal: signal,
         order_type: order_type,
-        limit_offset: limit_offset,
-        stop_percent: stop_percent,
-        take_profit_percent: take_profit_percent,
+        limit_price: limit_price,
+        max_slippage_bps: max_slippage_bps,
+        exit_params: exit_params,
+        sizing: sizing,
+        scale_in: scale_in,
+        scale_out: scale_out,
         confirmation_candles: confirmation_candles,
-        macd_trend_ma: ma::MAData::new(macd_trend_ma.unwrap_or(0)),
+        macd_trend_ma: ma::MAData::new(macd_trend_ma.unwrap_or(0), ma_type),
+        books: books,
+        intrabar_hold: intrabar_hold,
+        intrabar_signal: None,
+        intrabar_acted: None,
+        provisional: false,
+        higher_tfs: Vec::new(),
     };
 
-    let mut req_params: HashMap<&str, &str> = HashMap::with_capacity(3);
-    req_params.insert("symbol", tp.symbol());
-    req_params.insert("interval", &time_frame);
+    // Higher time frames have their own history and kline streams, subscribed to first
+    // so no candle closes unseen.
+    let mut htf_streams = Vec::with_capacity(higher_time_frames.len());
+    for (interval, ma_len) in &higher_time_frames {
+        htf_streams.push(hub.subscribe(&format!(
+            "{}@kline_{}",
+            tp.symbol().to_lowercase(),
+            interval
+        )));
+        let mut htf = HigherTimeFrame::new(interval, *ma_len, ma_type);
+        if let Err(code) = htf.warm_up(&bex, tp.symbol()) {
+            return Err(format!(
+                "{} failed to get {} cstick data: {}",
+                tp.symbol(),
+                interval,
+                code
+            ));
+        }
+        mt.higher_tfs.push(htf);
+    }
 
-    let historical_candles_required = if macd_trend_ma.unwrap_or(0) > slow_ma.unwrap_or(0) {
-        macd_trend_ma.unwrap_or(0).to_string()
-    } else {
-        slow_ma.unwrap().to_string()
-    };
+    let historical_candles_required = match signal {
+        TradeSignal::MACD => macd_params
+            .candles_required()
+            .max(mt.macd_trend_ma.candles_required()),
+        TradeSignal::RSI => rsi_params.candles_required(),
+        _ => mt
+            .slow_ma_data
+            .candles_required()
+            .max(mt.fast_ma_data.candles_required()),
+    } as u64;
+
+    // Candles built from the trade stream replace the exchange klines.
+    if let Some(spec) = bars {
+        return process_bar_data(
+            &bex,
+            &hub,
+            &am,
+            &risk,
+            &tp,
+            &mut mt,
+            spec,
+            historical_candles_required,
+            watchdog_limits,
+            &htf_streams,
+        );
+    }
 
     // Get the last candle sticks that we need to compute current moving averages.
-    req_params.insert("limit", &historical_candles_required);
     if let Ok(st) = bex.get_server_time() {
-        if let Ok(cd) = bex.get_cstick_data(&req_params) {
+        if let Ok(cd) =
+            bex.get_recent_cstick_data(tp.symbol(), &time_frame, historical_candles_required)
+        {
             let mut idx = 0;
             for stick in cd.iter() {
                 if let Ok(closing_price) = stick.close_price.parse::<f64>() {
@@ -399,6 +1004,7 @@ fn process_market_data_thread(
 
                         process_close_data(
                             &am,
+                            &risk,
                             &tp,
                             &mut mt,
                             closing_price,
@@ -406,6 +1012,7 @@ fn process_market_data_thread(
                             false,
                         );
                         prev_closing_price = Some(closing_price);
+                        last_close_time = Some(stick.close_time);
                     }
                 } else {
                     error!(
@@ -415,102 +1022,121 @@ fn process_market_data_thread(
                 }
             }
         } else {
-            error!("{:?} failed to get cstick data, exiting", tp.symbol());
-            am.exit();
-            return;
+            return Err(format!("{} failed to get cstick data", tp.symbol()));
         }
     } else {
-        error!("{:?} failed to get server time, exiting", tp.symbol());
-        am.exit();
-        return;
+        return Err(format!("{} failed to get server time", tp.symbol()));
     }
 
-    // We now switch over to the websocket interface to stream the candle
-    // stick data from the exchange.
-    let stream = format!(
-        "wss://stream.binance.com:9443/ws/{}@kline_{}",
-        tp.symbol().to_lowercase(),
-        time_frame
-    );
-    let mut ws_client = ClientBuilder::new(&stream).unwrap();
-    let mut conn = reconnect_stream(&mut ws_client).expect("failed to connect to stream");
-
-    loop {
-        match conn.recv_message() {
-            Ok(om) => {
-                match om {
-                    OwnedMessage::Text(s) => {
-                        let cstick: Result<serde_json::Value, _> = serde_json::from_str(&s);
-                        if let Ok(cstick) = cstick {
-                            let cstick_data: &serde_json::Value = &cstick["k"];
-                            if cstick_data["x"] == false {
-                                // Not closed, keep reading waiting.
-                                continue;
-                            }
-
-                            let closing_price = cstick_data["c"]
-                                .as_str()
-                                .unwrap_or("0.0")
-                                .parse::<f64>()
-                                .unwrap_or(-1.0);
-
-                            if closing_price > -1.0 {
-                                process_close_data(
-                                    &am,
-                                    &tp,
-                                    &mut mt,
-                                    closing_price,
-                                    prev_closing_price,
-                                    true,
-                                );
-                            } else {
-                                error!("failed to parse closing price: {}", cstick_data);
-                            }
-                        } else {
-                            error!("failed to deserialize candlestick data: {}", s);
-                        }
-                    }
-
-                    OwnedMessage::Ping(m) => match conn.send_message(&OwnedMessage::Pong(m)) {
-                        Ok(_) => {
-                            debug!("sent kline pong");
-                        }
-                        Err(e) => {
-                            error!("failed to reply to ping message: {}", e);
-                        }
-                    },
-
-                    OwnedMessage::Pong(_) => {
-                        // I don't think we ever see pong messages.
-                        debug!("got kline pong");
-                    }
+    // We now switch over to the stream hub to stream the candle stick data from
+    // the exchange.
+    let stream = format!("{}@kline_{}", tp.symbol().to_lowercase(), time_frame);
+    let klines = hub.subscribe(&stream);
+
+    let interval_ms = kline_interval_ms(&time_frame);
+    let mut watchdog = DataWatchdog::new(tp.symbol(), watchdog_limits, interval_ms);
+    while !shutdown::requested() {
+        watchdog.check_clock(&bex);
+        let overdue = match last_close_time {
+            Some(last) => watchdog.check_candle_due(last),
+            None => false,
+        };
 
-                    OwnedMessage::Binary(_) => {}
+        let cstick = match klines.recv_timeout(Duration::from_secs(60)) {
+            Ok(cstick) => cstick,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if overdue {
+                    // The stream has stalled, start it again.
+                    hub.reconnect(&stream);
+                }
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(format!("{} kline stream closed", tp.symbol()));
+            }
+        };
 
-                    OwnedMessage::Close(e) => {
-                        info!("disconnected from kline stream: {:?}", e);
-                        match reconnect_stream(&mut ws_client) {
-                            Some(c) => conn = c,
-                            None => break,
-                        };
-                    }
+        update_higher_time_frames(&bex, &tp, &mut mt, &htf_streams);
+
+        let cstick_data: &serde_json::Value = &cstick["k"];
+        if cstick_data["x"] == false {
+            // Not closed, only looked at in intrabar mode.
+            if let (Some(_), Some(last), true) = (
+                mt.intrabar_hold,
+                last_close_time,
+                watchdog.trading_allowed(),
+            ) {
+                let open_time = cstick_data["t"].as_u64().unwrap_or(0);
+                let price = cstick_data["c"]
+                    .as_str()
+                    .and_then(|c| c.parse::<f64>().ok());
+                // Not while there are candles to backfill.
+                if let (Some(price), None) = (price, missing_candles(last, open_time)) {
+                    process_intrabar_data(&am, &risk, &tp, &mut mt, price, prev_closing_price);
                 }
             }
+            continue;
+        }
+
+        let closing_price = cstick_data["c"]
+            .as_str()
+            .unwrap_or("0.0")
+            .parse::<f64>()
+            .unwrap_or(-1.0);
+
+        let open_time = cstick_data["t"].as_u64().unwrap_or(0);
+        let close_time = cstick_data["T"].as_u64().unwrap_or(0);
+
+        if let Some(last) = last_close_time {
+            if close_time <= last {
+                // Already processed, seen again after a reconnect.
+                continue;
+            }
 
-            Err(e) => {
-                error!("failed to receive data from the websocket: {}", e);
+            // Catch up on anything we missed before acting on this candle.
+            if let Some(range) = missing_candles(last, open_time) {
+                info!(
+                    "{} missing candles from {} to {}, backfilling",
+                    tp.symbol(),
+                    range.0,
+                    range.1
+                );
+                if let Some((close, _)) = backfill_candles(
+                    &bex,
+                    &am,
+                    &risk,
+                    &tp,
+                    &mut mt,
+                    &time_frame,
+                    range,
+                    prev_closing_price,
+                ) {
+                    prev_closing_price = Some(close);
+                }
             }
         }
-    }
 
-    match conn.shutdown() {
-        Ok(_) => {}
-        Err(e) => {
-            error!("failed to shutdown: {:?}", e);
+        watchdog.candle_received(cstick["E"].as_u64().unwrap_or(0));
+
+        if closing_price > -1.0 {
+            process_close_data(
+                &am,
+                &risk,
+                &tp,
+                &mut mt,
+                closing_price,
+                prev_closing_price,
+                watchdog.trading_allowed(),
+            );
+            prev_closing_price = Some(closing_price);
+            last_close_time = Some(close_time);
+        } else {
+            error!("failed to parse closing price: {}", cstick_data);
         }
     }
 
-    am.exit();
+    info!("{} market data thread exiting", tp.symbol());
+    Ok(())
 }
 
 // This function just spawns another 4 threads, 3 of those threads handle
@@ -527,32 +1153,43 @@ fn process_market_data_thread(
 // trading thread can set stop loss orders.
 fn md_bvlt_process_thread(
     ec: ExchangeConfig,
-    log_dir: String,
-    symset: String,
+    supervisor: Supervisor,
+    hub: StreamHub,
+    books: OrderBooks,
+    am: AccountManager,
+    risk: Arc<RiskManager>,
+    pairs: Vec<TradingPair>,
     time_frame: String,
     slow_ma: Option<u16>,
     fast_ma: Option<u16>,
-    split_pct: u8,
-    stop_percent: Option<f64>,
-    take_profit_percent: Option<f64>,
-    ema: bool,
+    share: f64,
+    exit_params: ExitParams,
+    sizing: PositionSizing,
+    scale_in: Option<(u8, f64)>,
+    scale_out: Vec<(f64, f64)>,
+    ma_type: ma::MAType,
     signal: TradeSignal,
     order_type: order::OrderType,
-    limit_offset: Option<u8>,
+    limit_price: Option<LimitPrice>,
+    max_slippage_bps: Option<f64>,
     confirmation_candles: Option<u8>,
     macd_trend_ma: Option<u16>,
+    macd_params: ma::MacdParams,
+    rsi_params: rsi::RsiParams,
+    watchdog_limits: WatchdogLimits,
+    bars: Option<BarSpec>,
+    intrabar_hold: Option<Duration>,
+    higher_time_frames: Vec<(String, u16)>,
 ) {
-    info!("starting {}ma bvlt thread for: {} using time frame: {}, slow ma: {:?}, fast ma: {:?}, split {}%, stop_pct: {:?}%",
-        if ema { "e" } else { "s" }, symset, time_frame, slow_ma, fast_ma, split_pct, stop_percent);
+    let symset: Vec<&str> = pairs.iter().map(|tp| tp.symbol()).collect();
+    info!("starting {:?} bvlt thread for: {} using time frame: {}, slow ma: {:?}, fast ma: {:?}, share {:.1}%, stop_pct: {:?}%",
+        ma_type, symset.join(":"), time_frame, slow_ma, fast_ma, share * 100.0, exit_params.stop_percent);
 
-    let pairs: Vec<&str> = symset.split(":").collect();
     let n_ma_threads = pairs.len();
     assert!(n_ma_threads == 3);
     let mut handles = Vec::with_capacity(n_ma_threads);
     let mut base_trading_pair: Option<TradingPair> = None;
-    let bex = Binance::new(ec.clone());
-    for symbol in pairs {
-        let trading_pair = TradingPair::new(&bex, &symbol);
+    for trading_pair in pairs {
         // Find the base pair, we'll create another thread for
         // this one for making trades.
         if trading_pair.get_bvlt_type().is_none() {
@@ -562,84 +1199,136 @@ fn md_bvlt_process_thread(
 
         let time_frame = time_frame.clone();
         let ma_ec = ec.clone();
-        let log_dir = log_dir.clone();
-        let h = thread::spawn(move || {
+        let hub = hub.clone();
+        let books = books.clone();
+        let am = am.clone();
+        let risk = Arc::clone(&risk);
+        let sizing = sizing.clone();
+        let scale_out = scale_out.clone();
+        let higher_time_frames = higher_time_frames.clone();
+        let name = format!("{} market data", trading_pair.symbol());
+        let h = supervisor.spawn(&name, move || {
             process_market_data_thread(
-                ma_ec,
-                log_dir,
-                trading_pair,
-                time_frame,
+                ma_ec.clone(),
+                hub.clone(),
+                books.clone(),
+                am.clone(),
+                Arc::clone(&risk),
+                trading_pair.clone(),
+                time_frame.clone(),
                 slow_ma,
                 fast_ma,
                 true,
-                ema,
+                ma_type,
                 signal,
                 order_type,
-                limit_offset,
-                stop_percent,
-                take_profit_percent,
+                limit_price,
+                max_slippage_bps,
+                exit_params,
+                sizing.clone(),
+                scale_in,
+                scale_out.clone(),
                 confirmation_candles,
                 macd_trend_ma,
-            );
+                macd_params,
+                rsi_params,
+                watchdog_limits,
+                bars,
+                intrabar_hold,
+                higher_time_frames.clone(),
+            )
         });
 
         handles.push(h);
     }
 
     assert!(base_trading_pair.is_some());
+
+    // Sleep until all spawned threads exit.
+    for h in handles {
+        h.join().unwrap();
+    }
 }
 
 // Spawns a data processing thread for processing market data and a trading thread
 // for executing trades.
 fn md_process_thread(
     ec: ExchangeConfig,
-    log_dir: String,
-    symbol: String,
+    supervisor: Supervisor,
+    hub: StreamHub,
+    books: OrderBooks,
+    am: AccountManager,
+    risk: Arc<RiskManager>,
+    trading_pair: TradingPair,
     time_frame: String,
     slow_ma: Option<u16>,
     fast_ma: Option<u16>,
-    split_pct: u8,
-    stop_percent: Option<f64>,
-    take_profit_percent: Option<f64>,
-    ema: bool,
+    share: f64,
+    exit_params: ExitParams,
+    sizing: PositionSizing,
+    scale_in: Option<(u8, f64)>,
+    scale_out: Vec<(f64, f64)>,
+    ma_type: ma::MAType,
     signal: TradeSignal,
     order_type: order::OrderType,
-    limit_offset: Option<u8>,
+    limit_price: Option<LimitPrice>,
+    max_slippage_bps: Option<f64>,
     confirmation_candles: Option<u8>,
     macd_trend_ma: Option<u16>,
+    macd_params: ma::MacdParams,
+    rsi_params: rsi::RsiParams,
+    watchdog_limits: WatchdogLimits,
+    bars: Option<BarSpec>,
+    intrabar_hold: Option<Duration>,
+    higher_time_frames: Vec<(String, u16)>,
 ) {
-    info!("starting {}ma basic thread for: {} using time frame: {}, slow ma: {:?}, fast ma: {:?}, split: {}%, stop_percent: {:?}%",
-        if ema { "e" } else { "s" }, symbol, time_frame, slow_ma, fast_ma, split_pct, stop_percent);
+    info!("starting {:?} basic thread for: {} using time frame: {}, slow ma: {:?}, fast ma: {:?}, share: {:.1}%, stop_percent: {:?}%",
+        ma_type, trading_pair.symbol(), time_frame, slow_ma, fast_ma, share * 100.0, exit_params.stop_percent);
 
-    let bex = Binance::new(ec.clone());
-    let trading_pair = TradingPair::new(&bex, &symbol);
     let tp = trading_pair.clone();
-    let log_dir = log_dir.clone();
-    let handle = thread::spawn(move || {
+    let name = format!("{} market data", tp.symbol());
+    let handle = supervisor.spawn(&name, move || {
         process_market_data_thread(
-            ec,
-            log_dir,
-            tp,
-            time_frame,
+            ec.clone(),
+            hub.clone(),
+            books.clone(),
+            am.clone(),
+            Arc::clone(&risk),
+            tp.clone(),
+            time_frame.clone(),
             slow_ma,
             fast_ma,
             false,
-            ema,
+            ma_type,
             signal,
             order_type,
-            limit_offset,
-            stop_percent,
-            take_profit_percent,
+            limit_price,
+            max_slippage_bps,
+            exit_params,
+            sizing.clone(),
+            scale_in,
+            scale_out.clone(),
             confirmation_candles,
             macd_trend_ma,
-        );
+            macd_params,
+            rsi_params,
+            watchdog_limits,
+            bars,
+            intrabar_hold,
+            higher_time_frames.clone(),
+        )
     });
 
     // Sleep until all spawned threads exit.
     handle.join().unwrap();
 }
 
-pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConfig) {
+pub fn run_strategy(
+    strat_cfg: &StrategyConfig,
+    log_dir: &str,
+    state_dir: &str,
+    ec: &ExchangeConfig,
+) {
     // Parse configuration first.
     let slow_ma = match strat_cfg.members.get("SlowMA") {
         Some(slow_ma) => {
@@ -687,13 +1376,16 @@ pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConf
         false
     };
 
-    // Use simple moving averages or exponential.
-    let ema: bool = strat_cfg
-        .members
-        .get("EMA")
-        .unwrap_or(&"false".to_string())
-        .parse::<bool>()
-        .unwrap();
+    // Type of the slow, fast and trend moving averages.
+    let ma_type = match strat_cfg.members.get("MAType") {
+        Some(t) => ma::MAType::parse(t).unwrap_or_else(|| panic!("Unsupported MAType: {}", t)),
+        None => {
+            if strat_cfg.members.get("EMA").is_some() {
+                panic!("EMA is no longer supported, use MAType=ema");
+            }
+            ma::MAType::Sma
+        }
+    };
 
     // Which signal to watch for.
     let signal = strat_cfg
@@ -718,16 +1410,118 @@ pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConf
         }
     };
 
-    let limit_range = match order_type {
-        order::OrderType::Limit => match strat_cfg.members.get("LimitOffset") {
-            Some(o) => Some(
-                o.to_string()
-                    .parse::<u8>()
-                    .expect("LimitOffset should be >= 0 < 256"),
+    let limit_range = match strat_cfg.members.get("LimitOffset") {
+        Some(o) => o
+            .to_string()
+            .parse::<u8>()
+            .expect("LimitOffset should be >= 0 < 256"),
+        None => DEFAULT_LIMIT_RANGE,
+    };
+
+    // How limit orders are priced, from the last close or from the live book.
+    let limit_price = match order_type {
+        order::OrderType::Limit => {
+            let lp = strat_cfg
+                .members
+                .get("LimitPrice")
+                .map(|s| s.as_str())
+                .unwrap_or("offset");
+            Some(match lp {
+                "offset" => LimitPrice::Offset(limit_range),
+                "join" => LimitPrice::Join,
+                "improve" => LimitPrice::Improve(
+                    strat_cfg
+                        .members
+                        .get("LimitImproveTicks")
+                        .unwrap_or(&"1".to_string())
+                        .parse::<u8>()
+                        .expect("LimitImproveTicks should be >= 0 < 256"),
+                ),
+                "mid" => LimitPrice::Mid,
+                "cross" => LimitPrice::Cross(
+                    strat_cfg
+                        .members
+                        .get("LimitCrossBps")
+                        .unwrap_or(&"10".to_string())
+                        .parse::<f64>()
+                        .expect("LimitCrossBps should be a number"),
+                ),
+                _ => panic!(
+                    "Unexpected LimitPrice {:?}, use one of offset, join, improve, mid or cross",
+                    lp
+                ),
+            })
+        }
+        _ => None,
+    };
+
+    // What to do with limit orders which don't fill.
+    let mut fill_policy = FillPolicy::default();
+    if let Some(secs) = strat_cfg.members.get("LimitRepriceSeconds") {
+        fill_policy.reprice_secs = Some(
+            secs.parse::<u64>()
+                .expect("LimitRepriceSeconds should be a number"),
+        );
+    }
+    if let Some(bps) = strat_cfg.members.get("LimitMaxChaseBps") {
+        fill_policy.max_chase_bps = bps
+            .parse::<f64>()
+            .expect("LimitMaxChaseBps should be a number");
+    }
+    if let Some(secs) = strat_cfg.members.get("LimitTimeoutSeconds") {
+        fill_policy.timeout_secs = Some(
+            secs.parse::<u64>()
+                .expect("LimitTimeoutSeconds should be a number"),
+        );
+    }
+    if let Some(action) = strat_cfg.members.get("LimitTimeoutAction") {
+        fill_policy.on_timeout = match action.as_str() {
+            "cancel" => TimeoutAction::Cancel,
+            "market" => TimeoutAction::Market,
+            _ => panic!(
+                "Unexpected LimitTimeoutAction {:?}, use either cancel (default) or market",
+                action
             ),
-            None => Some(DEFAULT_LIMIT_RANGE),
+        };
+    }
+
+    let max_slippage_bps = strat_cfg
+        .members
+        .get("MaxSlippageBps")
+        .map(|m| m.parse::<f64>().expect("MaxSlippageBps should be a number"));
+
+    // How orders are worked, all at once or as smaller child orders.
+    let exec_number = |key: &str, default: &str| -> f64 {
+        strat_cfg
+            .members
+            .get(key)
+            .map(|s| s.as_str())
+            .unwrap_or(default)
+            .parse::<f64>()
+            .expect(&format!("{} should be a number", key))
+    };
+    let exec_algo = match strat_cfg.members.get("ExecAlgo").map(|s| s.as_str()) {
+        None | Some("direct") => ExecAlgo::Direct,
+        Some("twap") => ExecAlgo::Twap {
+            secs: exec_number("TwapSeconds", "300") as u64,
+            slices: exec_number("TwapSlices", "5") as u32,
         },
-        _ => None,
+        Some("iceberg") => {
+            if limit_price.is_none() {
+                panic!("ExecAlgo iceberg needs OrderType Limit");
+            }
+            ExecAlgo::Iceberg {
+                parts: exec_number("IcebergParts", "10") as u32,
+            }
+        }
+        Some("pov") => ExecAlgo::Pov {
+            percent: exec_number("PovPercent", "10"),
+            max_secs: exec_number("PovMaxSeconds", "600") as u64,
+        },
+        Some(a) => panic!(
+            "Unexpected ExecAlgo {:?}, use one of direct (default), twap, iceberg or pov",
+            a
+        ),
     };
 
     // Stops with margin is not currently supported.
@@ -764,6 +1558,316 @@ pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConf
         None => None,
     };
 
+    // % Below the stop trigger price for the stop loss limit price, gives the stop
+    // a chance to fill when the price gaps through the trigger.
+    let stop_limit_percent = match strat_cfg.members.get("StopLimitPercent") {
+        Some(o) => {
+            let stop_limit_percent = o
+                .to_string()
+                .parse::<f64>()
+                .expect("StopLimitPercent should be >= 0.0 < 100.0");
+            if stop_limit_percent < 0.0 || stop_limit_percent >= 100.0 {
+                panic!("StopLimitPercent should be a percentage");
+            }
+
+            if stop_percent.is_none() {
+                panic!("StopLimitPercent is set but StopPercent is not");
+            }
+
+            Some(stop_limit_percent)
+        }
+
+        None => None,
+    };
+
+    // Seconds a triggered stop loss can sit unfilled before we sell at market.
+    let stop_fill_timeout = match strat_cfg.members.get("StopFillTimeout") {
+        Some(o) => {
+            let stop_fill_timeout = o
+                .to_string()
+                .parse::<u64>()
+                .expect("StopFillTimeout should be a number of seconds");
+
+            if stop_percent.is_none() {
+                panic!("StopFillTimeout is set but StopPercent is not");
+            }
+
+            Some(stop_fill_timeout)
+        }
+
+        None => None,
+    };
+
+    let exit_params = ExitParams {
+        stop_percent: stop_percent,
+        stop_limit_percent: stop_limit_percent,
+        stop_fill_timeout: stop_fill_timeout,
+        take_profit_percent: tp_percent,
+    };
+
+    // How much to spend on each entry, SizingValue is interpreted by the model.
+    let sizing_value = strat_cfg.members.get("SizingValue").map(|v| {
+        let v = v
+            .to_string()
+            .parse::<f64>()
+            .expect("SizingValue is not a number");
+        if v <= 0.0 {
+            panic!("SizingValue should be > 0.0");
+        }
+        v
+    });
+
+    let sizing = match strat_cfg.members.get("PositionSizing") {
+        Some(ps) => {
+            let pct = |max: f64| -> f64 {
+                let v = sizing_value
+                    .unwrap_or_else(|| panic!("PositionSizing={} requires SizingValue", ps));
+                if v > max {
+                    panic!("SizingValue should be <= {} for PositionSizing={}", max, ps);
+                }
+                v
+            };
+
+            if ps.eq_ignore_ascii_case("all") {
+                PositionSizing::All
+            } else if ps.eq_ignore_ascii_case("quote") {
+                PositionSizing::FixedQuote(pct(f64::MAX))
+            } else if ps.eq_ignore_ascii_case("fraction") {
+                PositionSizing::FixedFraction(pct(100.0))
+            } else if ps.eq_ignore_ascii_case("risk") {
+                if stop_percent.is_none() {
+                    panic!("PositionSizing=risk requires StopPercent");
+                }
+                PositionSizing::FixedRisk(pct(100.0))
+            } else if ps.eq_ignore_ascii_case("atr") {
+                let period = match strat_cfg.members.get("AtrPeriod") {
+                    Some(p) => p
+                        .to_string()
+                        .parse::<u16>()
+                        .expect("AtrPeriod is not valid"),
+                    None => 14,
+                };
+                PositionSizing::Atr {
+                    percent: pct(100.0),
+                    period: period,
+                    time_frame: time_frame.to_string(),
+                }
+            } else if ps.eq_ignore_ascii_case("kelly") {
+                let lookback = match strat_cfg.members.get("KellyLookback") {
+                    Some(l) => l
+                        .to_string()
+                        .parse::<usize>()
+                        .expect("KellyLookback is not valid"),
+                    None => 20,
+                };
+                PositionSizing::Kelly {
+                    fraction: pct(1.0),
+                    lookback: lookback,
+                }
+            } else {
+                panic!(
+                    "Unexpected PositionSizing {:?}, use one of all (default), quote, fraction, risk, atr or kelly",
+                    ps
+                );
+            }
+        }
+
+        None => PositionSizing::All,
+    };
+
+    // Build positions from a number of buys, each made once price has moved in our
+    // favour by ScaleInPercent.
+    let scale_in = match strat_cfg.members.get("ScaleIn") {
+        Some(n) => {
+            let n = n
+                .to_string()
+                .parse::<u8>()
+                .expect("ScaleIn is not a number");
+            if n < 1 || n > 10 {
+                panic!("ScaleIn should be >= 1 <= 10");
+            }
+
+            let step = strat_cfg
+                .members
+                .get("ScaleInPercent")
+                .expect("ScaleIn requires \"ScaleInPercent\"")
+                .to_string()
+                .parse::<f64>()
+                .expect("ScaleInPercent should be > 0.0");
+            if step <= 0.0 {
+                panic!("ScaleInPercent should be > 0.0");
+            }
+
+            Some((n, step))
+        }
+
+        None => None,
+    };
+
+    // Partial profit targets, ScaleOut=2.0:50,4.0:50 sells half the position at +2%
+    // and the rest at +4%.
+    let scale_out: Vec<(f64, f64)> = match strat_cfg.members.get("ScaleOut") {
+        Some(targets) => {
+            let mut scale_out = Vec::new();
+            for target in targets.split(",") {
+                let (gain, pct) = target
+                    .split_once(':')
+                    .expect("ScaleOut entries look like GAIN_PERCENT:SELL_PERCENT");
+                let gain = gain
+                    .trim()
+                    .parse::<f64>()
+                    .expect("ScaleOut gain is not a number");
+                let pct = pct
+                    .trim()
+                    .parse::<f64>()
+                    .expect("ScaleOut sell percent is not a number");
+                if gain <= 0.0 || pct <= 0.0 || pct > 100.0 {
+                    panic!("ScaleOut entries should be percentages");
+                }
+                scale_out.push((gain, pct));
+            }
+
+            scale_out.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            if scale_out.iter().map(|t| t.1).sum::<f64>() > 100.0 {
+                panic!("ScaleOut sells more than 100% of the position");
+            }
+
+            scale_out
+        }
+
+        None => Vec::new(),
+    };
+
+    // Keep a live order book for each pair.
+    let order_book = strat_cfg
+        .members
+        .get("OrderBook")
+        .unwrap_or(&"false".to_string())
+        .parse::<bool>()
+        .expect("OrderBook should be true or false");
+
+    // Pause trading on stale market data or a drifting clock.
+    let mut watchdog_limits = WatchdogLimits::default();
+    if let Some(secs) = strat_cfg.members.get("StaleGraceSeconds") {
+        watchdog_limits.grace_ms = secs
+            .parse::<u64>()
+            .expect("StaleGraceSeconds should be a number")
+            * 1000;
+    }
+    if let Some(ms) = strat_cfg.members.get("MaxEventLagMs") {
+        watchdog_limits.max_event_lag_ms =
+            ms.parse::<u64>().expect("MaxEventLagMs should be a number");
+    }
+    if let Some(ms) = strat_cfg.members.get("MaxClockDriftMs") {
+        watchdog_limits.max_clock_drift_ms = ms
+            .parse::<u64>()
+            .expect("MaxClockDriftMs should be a number");
+    }
+
+    // A worker which fails more often than this in an hour shuts us down.
+    let max_restarts = strat_cfg
+        .members
+        .get("MaxRestarts")
+        .unwrap_or(&"5".to_string())
+        .parse::<usize>()
+        .expect("MaxRestarts should be a number");
+
+    // Build candles from the trade stream rather than using the exchange klines.
+    let bars = strat_cfg.members.get("Bars").map(|b| BarSpec {
+        bar_type: BarType::parse(b).expect(
+            "Bars should be an interval such as 2m, 90m or 4h, or one of tick:N, volume:N, dollar:N, range:N or renko:N",
+        ),
+        heikin_ashi: strat_cfg
+            .members
+            .get("HeikinAshi")
+            .unwrap_or(&"false".to_string())
+            .parse::<bool>()
+            .expect("HeikinAshi should be true or false"),
+        aggregated: match strat_cfg.members.get("BarStream").map(|s| s.as_str()) {
+            None | Some("aggTrade") => true,
+            Some("trade") => false,
+            Some(s) => panic!(
+                "Unexpected BarStream {:?}, use either aggTrade (default) or trade",
+                s
+            ),
+        },
+        warmup_hours: strat_cfg
+            .members
+            .get("BarWarmupHours")
+            .unwrap_or(&"24".to_string())
+            .parse::<u64>()
+            .expect("BarWarmupHours should be a number"),
+    });
+
+    // Higher time frames to filter entries with, 4h or 4h:50 for a 50 candle MA.
+    let higher_time_frames: Vec<(String, u16)> = match strat_cfg.members.get("HigherTimeFrames") {
+        Some(tfs) => tfs
+            .split(",")
+            .map(|tf| {
+                timeframe::parse(tf, 50).expect(
+                    "HigherTimeFrames should be a list of time frames, each optionally followed by :MA length",
+                )
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Evaluate signals on the forming candle as well as on close.
+    let intrabar_hold = match strat_cfg
+        .members
+        .get("Intrabar")
+        .unwrap_or(&"false".to_string())
+        .parse::<bool>()
+        .expect("Intrabar should be true or false")
+    {
+        true => Some(Duration::from_secs(
+            strat_cfg
+                .members
+                .get("IntrabarHoldSeconds")
+                .unwrap_or(&"0".to_string())
+                .parse::<u64>()
+                .expect("IntrabarHoldSeconds should be a number"),
+        )),
+        false => None,
+    };
+
+    // Sell our positions when we are shut down.
+    let flatten_on_exit = strat_cfg
+        .members
+        .get("FlattenOnExit")
+        .unwrap_or(&"false".to_string())
+        .parse::<bool>()
+        .expect("FlattenOnExit should be true or false");
+
+    // Risk limits, all optional.
+    let risk_amount = |key: &str| -> Option<f64> {
+        strat_cfg.members.get(key).map(|v| {
+            let v = v
+                .to_string()
+                .parse::<f64>()
+                .unwrap_or_else(|_| panic!("{} is not a number", key));
+            if v <= 0.0 {
+                panic!("{} should be > 0.0", key);
+            }
+            v
+        })
+    };
+
+    let risk_limits = RiskLimits {
+        max_daily_loss: risk_amount("MaxDailyLoss"),
+        max_weekly_loss: risk_amount("MaxWeeklyLoss"),
+        max_drawdown_percent: risk_amount("MaxDrawdownPercent"),
+        max_open_positions: risk_amount("MaxOpenPositions").map(|v| v as usize),
+        max_symbol_notional: risk_amount("MaxSymbolNotional"),
+        max_orders_per_hour: risk_amount("MaxOrdersPerHour").map(|v| v as usize),
+        flatten: strat_cfg
+            .members
+            .get("FlattenOnHalt")
+            .unwrap_or(&"false".to_string())
+            .parse::<bool>()
+            .expect("FlattenOnHalt should be true or false"),
+    };
+
     let signal = {
         if signal.eq_ignore_ascii_case("trend") {
             TradeSignal::MaTrendReversal
@@ -771,6 +1875,8 @@ pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConf
             TradeSignal::MaCross
         } else if signal.eq_ignore_ascii_case("macd") {
             TradeSignal::MACD
+        } else if signal.eq_ignore_ascii_case("rsi") {
+            TradeSignal::RSI
         } else {
             panic!("Unsupported signal: {}", signal);
         }
@@ -786,8 +1892,8 @@ pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConf
                 panic!("ConfirmationCandles < 10");
             }
 
-            if signal != TradeSignal::MACD {
-                panic!("ConfirmationCandles is set but macd is not configured as a strategy")
+            if signal != TradeSignal::MACD && signal != TradeSignal::RSI {
+                panic!("ConfirmationCandles is set but neither macd nor rsi is configured as a strategy")
             }
 
             Some(confirmation_candles)
@@ -813,9 +1919,248 @@ pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConf
         None => None,
     };
 
-    // If have one set of symbols then we invest 100% in that, if we
-    // have 2 sets of symbols then each gets 50% and so on....
-    let asset_split_pct: u8 = (100 / pairs.len()) as u8;
+    let macd_length = |key: &str, default: u16| -> u16 {
+        match strat_cfg.members.get(key) {
+            Some(len) => {
+                if signal != TradeSignal::MACD {
+                    panic!("{} is set but macd is not configured as a strategy", key)
+                }
+                let len = len
+                    .parse::<u16>()
+                    .unwrap_or_else(|_| panic!("{} is not a number", key));
+                if len == 0 {
+                    panic!("{} must be > 0", key);
+                }
+                len
+            }
+            None => default,
+        }
+    };
+
+    let default_macd = ma::MacdParams::default();
+    let macd_params = ma::MacdParams {
+        fast: macd_length("MacdFast", default_macd.fast),
+        slow: macd_length("MacdSlow", default_macd.slow),
+        signal: macd_length("MacdSignalLength", default_macd.signal),
+        mode: match strat_cfg.members.get("MacdMode") {
+            Some(mode) => {
+                if mode.eq_ignore_ascii_case("signal") {
+                    ma::MacdMode::SignalCross
+                } else if mode.eq_ignore_ascii_case("zero") {
+                    ma::MacdMode::ZeroCross
+                } else if mode.eq_ignore_ascii_case("histogram") {
+                    ma::MacdMode::Histogram
+                } else if mode.eq_ignore_ascii_case("divergence") {
+                    ma::MacdMode::Divergence
+                } else {
+                    panic!("Unsupported MacdMode: {}", mode);
+                }
+            }
+            None => default_macd.mode,
+        },
+        divergence_lookback: macd_length(
+            "MacdDivergenceLookback",
+            default_macd.divergence_lookback,
+        ),
+    };
+    if macd_params.fast >= macd_params.slow {
+        panic!("MacdFast must be less than MacdSlow");
+    }
+    if macd_params.divergence_lookback < ma::MacdParams::min_divergence_lookback() {
+        panic!(
+            "MacdDivergenceLookback must be at least {}",
+            ma::MacdParams::min_divergence_lookback()
+        );
+    }
+
+    let rsi_setting = |key: &str| -> Option<f64> {
+        strat_cfg.members.get(key).map(|v| {
+            if signal != TradeSignal::RSI {
+                panic!("{} is set but rsi is not configured as a strategy", key)
+            }
+            v.parse::<f64>()
+                .unwrap_or_else(|_| panic!("{} is not a number", key))
+        })
+    };
+
+    let default_rsi = rsi::RsiParams::default();
+    let rsi_params = rsi::RsiParams {
+        length: match rsi_setting("RsiLength") {
+            Some(len) if len >= 2.0 && len <= 500.0 && len.fract() == 0.0 => len as u16,
+            Some(len) => panic!("RsiLength {} should be a whole number from 2 to 500", len),
+            None => default_rsi.length,
+        },
+        oversold: rsi_setting("RsiOversold").unwrap_or(default_rsi.oversold),
+        overbought: rsi_setting("RsiOverbought").unwrap_or(default_rsi.overbought),
+        exit: match strat_cfg.members.get("RsiExit") {
+            Some(exit) => {
+                if signal != TradeSignal::RSI {
+                    panic!("RsiExit is set but rsi is not configured as a strategy")
+                }
+                if exit.eq_ignore_ascii_case("overbought") {
+                    rsi::RsiExit::Overbought
+                } else if exit.eq_ignore_ascii_case("middle") {
+                    rsi::RsiExit::Middle
+                } else {
+                    panic!("Unsupported RsiExit: {}", exit);
+                }
+            }
+            None => default_rsi.exit,
+        },
+    };
+    if !(0.0 < rsi_params.oversold
+        && rsi_params.oversold < 50.0
+        && 50.0 < rsi_params.overbought
+        && rsi_params.overbought < 100.0)
+    {
+        panic!("RsiOversold must be between 0 and 50 and RsiOverbought between 50 and 100");
+    }
+
+    // What to do with holdings of our pairs that we find at startup.
+    let existing_policy = match strat_cfg.members.get("ExistingPositionPolicy") {
+        Some(p) => {
+            if p.eq_ignore_ascii_case("adopt") {
+                ExistingPositionPolicy::Adopt
+            } else if p.eq_ignore_ascii_case("ignore") {
+                ExistingPositionPolicy::Ignore
+            } else if p.eq_ignore_ascii_case("flatten") {
+                ExistingPositionPolicy::Flatten
+            } else {
+                panic!(
+                    "Unexpected ExistingPositionPolicy {:?}, use one of adopt, ignore (default) or flatten",
+                    p
+                );
+            }
+        }
+
+        None => ExistingPositionPolicy::Ignore,
+    };
+
+    // How capital is split between the pairs.
+    let allocation = match strat_cfg.members.get("Allocation") {
+        Some(a) => {
+            if a.eq_ignore_ascii_case("equal") {
+                Allocation::Equal
+            } else if a.eq_ignore_ascii_case("weights") {
+                // AllocationWeights=ADA/USDT:2,BTC/USDT:1
+                let weights = strat_cfg
+                    .members
+                    .get("AllocationWeights")
+                    .expect("Allocation=weights requires \"AllocationWeights\"");
+                let mut w = HashMap::new();
+                for entry in weights.split(",") {
+                    let (name, weight) = entry
+                        .rsplit_once(':')
+                        .expect("AllocationWeights entries look like PAIR:WEIGHT");
+                    let weight = weight
+                        .parse::<f64>()
+                        .expect("AllocationWeights weight is not a number");
+                    if weight < 0.0 {
+                        panic!("AllocationWeights weight should be >= 0.0");
+                    }
+                    w.insert(name.trim().to_string(), weight);
+                }
+                Allocation::Weights(w)
+            } else if a.eq_ignore_ascii_case("volatility") {
+                Allocation::Volatility
+            } else {
+                panic!(
+                    "Unexpected Allocation {:?}, use one of equal (default), weights or volatility",
+                    a
+                );
+            }
+        }
+
+        None => Allocation::Equal,
+    };
+
+    // Number of candles used to measure volatility for Allocation=volatility.
+    let allocation_lookback = match strat_cfg.members.get("AllocationLookback") {
+        Some(l) => {
+            let l = l
+                .to_string()
+                .parse::<u16>()
+                .expect("AllocationLookback is not valid");
+            if l < 3 || l > 1000 {
+                panic!("AllocationLookback should be >= 3 <= 1000");
+            }
+            l
+        }
+
+        None => 30,
+    };
+
+    // Look up each pair, or BASE/UP/DOWN set of pairs.
+    let bex = Binance::new(ec.clone());
+    let pair_sets: Vec<Vec<TradingPair>> = pairs
+        .iter()
+        .map(|p| p.split(":").map(|s| TradingPair::new(&bex, s)).collect())
+        .collect();
+
+    // Each set is known by its first pair, the one we analyse.
+    let names: Vec<(String, String)> = pairs
+        .iter()
+        .zip(pair_sets.iter())
+        .map(|(p, set)| {
+            (
+                p.split(":").next().unwrap().to_string(),
+                set[0].symbol().to_string(),
+            )
+        })
+        .collect();
+    let shares = allocation::shares(&bex, &allocation, &names, time_frame, allocation_lookback);
+
+    // All pairs of a set trade from the share of the set.
+    let mut budgets: HashMap<String, f64> = HashMap::new();
+    for ((name, _), set) in names.iter().zip(pair_sets.iter()) {
+        info!(
+            "{} is allocated {:.1}% of capital",
+            name,
+            shares[name] * 100.0
+        );
+        for tp in set {
+            budgets.insert(tp.symbol().to_string(), shares[name]);
+        }
+    }
+
+    // Every worker thread is watched and restarted if it fails.
+    let supervisor = Supervisor::new(state_dir, max_restarts);
+
+    // Market data for all pairs is received over a few shared connections.
+    let hub = StreamHub::new(&supervisor);
+
+    // Live order books for the pairs we trade, if wanted or needed to price orders.
+    let books = OrderBooks::new();
+    if order_book || limit_price.map(|lp| lp.needs_book()).unwrap_or(false) {
+        for tp in pair_sets.iter().flatten() {
+            books.track(ec, &hub, &supervisor, tp.symbol());
+        }
+    }
+
+    // Loss limits and the like, equity is measured in the quote asset of the first pair.
+    let risk = Arc::new(RiskManager::new(
+        risk_limits,
+        pair_sets[0][0].buy_currency(),
+        state_dir,
+    ));
+
+    // One account manager, and so one user data stream, serves every pair.
+    let am = AccountManager::new(
+        ec.clone(),
+        false,
+        log_dir.to_string(),
+        state_dir.to_string(),
+        pair_sets.iter().flatten().cloned().collect(),
+        exit_params,
+        existing_policy,
+        budgets,
+        &supervisor,
+        books.clone(),
+        fill_policy,
+        exec_algo,
+        hub.clone(),
+        risk.closed_trades(),
+    );
 
     // Create a thread per BASE/UP/DOWN tuple. For example if we wanted to
     // run MA_BVLT on ADA/USDT and BTC/USDT then our config would look
@@ -826,52 +2171,85 @@ pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConf
     // From this we would create a thread for handling ADA and a thread for
     // handling BTC. In turn those threads create yet more threads for computing
     // MAs for each trading pair.
-    let nthreads = pairs.len();
+    let nthreads = pair_sets.len();
     let mut handles = Vec::with_capacity(nthreads);
-    for pair in pairs {
+    for ((name, _), set) in names.into_iter().zip(pair_sets.into_iter()) {
         let time_frame = time_frame.to_string();
         let ec = ec.clone();
-        let symbol = pair.to_owned();
-        let log_dir = log_dir.to_string();
+        let supervisor = supervisor.clone();
+        let hub = hub.clone();
+        let books = books.clone();
+        let am = am.clone();
+        let risk = Arc::clone(&risk);
+        let sizing = sizing.clone();
+        let scale_out = scale_out.clone();
+        let higher_time_frames = higher_time_frames.clone();
+        let share = shares[&name];
         let h = if bvlt_mode {
-            let symset = pair.to_string();
             thread::spawn(move || {
                 md_bvlt_process_thread(
                     ec,
-                    log_dir,
-                    symset,
+                    supervisor,
+                    hub,
+                    books,
+                    am,
+                    risk,
+                    set,
                     time_frame,
                     slow_ma,
                     fast_ma,
-                    asset_split_pct,
-                    stop_percent,
-                    tp_percent,
-                    ema,
+                    share,
+                    exit_params,
+                    sizing,
+                    scale_in,
+                    scale_out,
+                    ma_type,
                     signal,
                     order_type,
-                    limit_range,
+                    limit_price,
+                    max_slippage_bps,
                     confirmation_candles,
                     macd_trend_ma,
+                    macd_params,
+                    rsi_params,
+                    watchdog_limits,
+                    bars,
+                    intrabar_hold,
+                    higher_time_frames,
                 );
             })
         } else {
+            let tp = set.into_iter().next().unwrap();
             thread::spawn(move || {
                 md_process_thread(
                     ec,
-                    log_dir.to_string(),
-                    symbol,
+                    supervisor,
+                    hub,
+                    books,
+                    am,
+                    risk,
+                    tp,
                     time_frame,
                     slow_ma,
                     fast_ma,
-                    asset_split_pct,
-                    stop_percent,
-                    tp_percent,
-                    ema,
+                    share,
+                    exit_params,
+                    sizing,
+                    scale_in,
+                    scale_out,
+                    ma_type,
                     signal,
                     order_type,
-                    limit_range,
+                    limit_price,
+                    max_slippage_bps,
                     confirmation_candles,
                     macd_trend_ma,
+                    macd_params,
+                    rsi_params,
+                    watchdog_limits,
+                    bars,
+                    intrabar_hold,
+                    higher_time_frames,
                 );
             })
         };
@@ -879,10 +2257,13 @@ pub fn run_strategy(strat_cfg: &StrategyConfig, log_dir: &str, ec: &ExchangeConf
         handles.push(h);
     }
 
-    // Sleep untill all of those threads exit.
+    // Sleep untill all of those threads exit, which they do on shutdown.
     for h in handles {
         h.join().unwrap();
     }
+
+    am.exit(flatten_on_exit);
+    supervisor.report();
 }
 
 #[cfg(test)]
@@ -891,6 +2272,15 @@ mod tests {
 
     use crate::utils;
 
+    use websocket::{ClientBuilder, OwnedMessage};
+
+    #[test]
+    fn gap_in_candles() {
+        // 1m candles, the last one processed opened at 0 and closed at 59999.
+        assert_eq!(missing_candles(59_999, 60_000), None);
+        assert_eq!(missing_candles(59_999, 180_000), Some((60_000, 179_999)));
+    }
+
     #[test]
     fn ws_market_data_stream() {
         utils::init_logging("testlogs/ma/ws_market_data_stream", "info");
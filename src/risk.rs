@@ -0,0 +1,330 @@
+// Risk limits (risk.rs).
+//
+// Every order goes through the risk manager before it is handed to the account
+// manager. When a loss limit is breached trading is halted, new entries are blocked
+// and, if configured, every position is sold. A halt is saved to the state
+// directory and stays in place, even across a restart, until it is reset by hand by
+// creating the file <StateDir>/risk_reset. Recently closed trades are saved with
+// it, so the daily and weekly limits still count losses made before a restart.
+use crate::account_manager;
+use crate::position;
+use crate::supervisor::LockExt;
+use crate::tradingpair;
+
+use chrono::{Datelike, TimeZone, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use account_manager::{AccountManager, ClosedTrade};
+use position::PositionType;
+use tradingpair::TradingPair;
+
+#[derive(Debug, Clone, Default)]
+pub struct RiskLimits {
+    // Largest loss allowed since the start of the UTC day / week (Monday), in the
+    // quote asset, realised losses only.
+    pub max_daily_loss: Option<f64>,
+    pub max_weekly_loss: Option<f64>,
+    // Largest % fall in equity from its peak.
+    pub max_drawdown_percent: Option<f64>,
+    // Entries are blocked, but trading is not halted, while we are at these limits.
+    pub max_open_positions: Option<usize>,
+    pub max_symbol_notional: Option<f64>,
+    pub max_orders_per_hour: Option<usize>,
+    // Sell everything when halted.
+    pub flatten: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RiskDecision {
+    // Go ahead, buys may not take the symbol over this notional.
+    Allow(Option<f64>),
+    Block,
+    // Halted, sell the position at market.
+    Flatten,
+}
+
+// Saved so that a halt survives a restart.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RiskState {
+    halted: Option<String>,
+    peak_equity: f64,
+    #[serde(default)]
+    closed_trades: Vec<ClosedTrade>,
+}
+
+pub struct RiskManager {
+    limits: RiskLimits,
+    // Asset equity is measured in.
+    quote_asset: String,
+    state_dir: String,
+    state: Mutex<RiskState>,
+    // Times orders were sent, unix seconds.
+    orders: Mutex<Vec<i64>>,
+    // Latest close of each symbol, open positions are valued at it.
+    marks: Mutex<HashMap<String, f64>>,
+    // Symbols we have already sold at market since halting.
+    flattened: Mutex<HashSet<String>>,
+}
+
+// Start of the UTC day and week (Monday) containing 'now'.
+fn period_starts(now: i64) -> (i64, i64) {
+    let dt = Utc.timestamp(now, 0);
+    let day_start = Utc
+        .ymd(dt.year(), dt.month(), dt.day())
+        .and_hms(0, 0, 0)
+        .timestamp();
+    let week_start = day_start - (dt.weekday().num_days_from_monday() as i64 * 24 * 60 * 60);
+    (day_start, week_start)
+}
+
+impl RiskManager {
+    pub fn new(limits: RiskLimits, quote_asset: &str, state_dir: &str) -> RiskManager {
+        let state = match fs::read_to_string(Self::state_file(state_dir)) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_default(),
+            Err(_) => RiskState::default(),
+        };
+
+        if let Some(reason) = &state.halted {
+            error!("trading is halted: {}", reason);
+        }
+
+        RiskManager {
+            limits: limits,
+            quote_asset: quote_asset.to_string(),
+            state_dir: state_dir.to_string(),
+            state: Mutex::new(state),
+            orders: Mutex::new(Vec::new()),
+            marks: Mutex::new(HashMap::new()),
+            flattened: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Trades closed before we were last stopped, to seed the account manager with.
+    pub fn closed_trades(&self) -> Vec<ClosedTrade> {
+        self.state.locked().closed_trades.clone()
+    }
+
+    fn state_file(state_dir: &str) -> PathBuf {
+        let mut pb = PathBuf::from(state_dir);
+        pb.push("risk.json");
+        pb
+    }
+
+    fn save(&self, state: &RiskState) {
+        if let Err(e) = fs::create_dir_all(&self.state_dir) {
+            error!("failed to create state directory {}: {}", self.state_dir, e);
+            return;
+        }
+
+        let pb = Self::state_file(&self.state_dir);
+        if let Err(e) = fs::write(pb.as_path(), serde_json::to_string_pretty(state).unwrap()) {
+            error!("failed to save risk state {}: {}", pb.display(), e);
+        }
+    }
+
+    // Clear a halt if the reset file has been created.
+    fn check_reset(&self, state: &mut RiskState) {
+        let mut pb = PathBuf::from(&self.state_dir);
+        pb.push("risk_reset");
+        if state.halted.is_some() && pb.exists() {
+            info!("risk reset, trading resumed");
+            state.halted = None;
+            state.peak_equity = 0.0;
+            self.flattened.locked().clear();
+            self.save(state);
+        }
+        let _ = fs::remove_file(pb.as_path());
+    }
+
+    // Check the loss limits, halting trading if any have been breached.
+    fn check_limits(&self, am: &AccountManager, now: i64, state: &mut RiskState) {
+        // Save trades closed since we last looked.
+        let trades = am.closed_trades();
+        if trades != state.closed_trades {
+            state.closed_trades = trades;
+            self.save(state);
+        }
+
+        if state.halted.is_some() {
+            return;
+        }
+
+        let (day_start, week_start) = period_starts(now);
+        let mut breach = None;
+        let marks = self.marks.locked();
+
+        if let Some(max_loss) = self.limits.max_daily_loss {
+            let pnl = am.realised_pnl_since(day_start);
+            if -pnl >= max_loss {
+                breach = Some(format!("daily loss {:.2} >= {:.2}", -pnl, max_loss));
+            }
+        }
+
+        if let Some(max_loss) = self.limits.max_weekly_loss {
+            let pnl = am.realised_pnl_since(week_start);
+            if -pnl >= max_loss {
+                breach = Some(format!("weekly loss {:.2} >= {:.2}", -pnl, max_loss));
+            }
+        }
+
+        let equity = am.equity(&self.quote_asset, &marks);
+        if equity > state.peak_equity {
+            state.peak_equity = equity;
+            self.save(state);
+        }
+
+        if let Some(max_dd) = self.limits.max_drawdown_percent {
+            if state.peak_equity > 0.0 {
+                let dd = ((state.peak_equity - equity) / state.peak_equity) * 100.0;
+                if dd >= max_dd {
+                    breach = Some(format!("drawdown {:.2}% >= {:.2}%", dd, max_dd));
+                }
+            }
+        }
+
+        if let Some(reason) = breach {
+            error!("risk limit breached, halting trading: {}", reason);
+            state.halted = Some(reason);
+            self.save(state);
+        }
+    }
+
+    // Decide if an order for the pair may go ahead, called on every candle close so
+    // that the limits are checked even when there is nothing to do.
+    pub fn review(
+        &self,
+        am: &AccountManager,
+        tp: &TradingPair,
+        decision: PositionType,
+        closing_price: f64,
+    ) -> RiskDecision {
+        let now = Utc::now().timestamp();
+        self.marks
+            .locked()
+            .insert(tp.symbol().to_string(), closing_price);
+        let mut state = self.state.locked();
+        self.check_reset(&mut state);
+        self.check_limits(am, now, &mut state);
+
+        let held = am.get_position(tp.symbol());
+
+        if state.halted.is_some() {
+            // Sold once, the position is gone when the sell fills.
+            if self.limits.flatten
+                && held.is_some()
+                && self.flattened.locked().insert(tp.symbol().to_string())
+            {
+                info!("{} trading halted, flattening position", tp.symbol());
+                self.orders.locked().push(now);
+                return RiskDecision::Flatten;
+            }
+
+            if decision == PositionType::None {
+                return RiskDecision::Block;
+            }
+
+            // Exits are always allowed.
+            if decision != PositionType::Short {
+                info!("{} trading halted, {:?} blocked", tp.symbol(), decision);
+                return RiskDecision::Block;
+            }
+        }
+
+        if decision == PositionType::Long {
+            if let Some(max) = self.limits.max_open_positions {
+                if held.is_none() && am.open_positions() >= max {
+                    info!(
+                        "{} entry blocked, {} positions already open",
+                        tp.symbol(),
+                        max
+                    );
+                    return RiskDecision::Block;
+                }
+            }
+
+            if let Some(max) = self.limits.max_symbol_notional {
+                if let Some((_, qty, _)) = held {
+                    if qty * closing_price >= max {
+                        info!(
+                            "{} entry blocked, {:.2} held >= {:.2}",
+                            tp.symbol(),
+                            qty * closing_price,
+                            max
+                        );
+                        return RiskDecision::Block;
+                    }
+                }
+            }
+
+            if let Some(max) = self.limits.max_orders_per_hour {
+                let mut orders = self.orders.locked();
+                orders.retain(|t| *t > now - (60 * 60));
+                if orders.len() >= max {
+                    info!(
+                        "{} entry blocked, {} orders in the last hour",
+                        tp.symbol(),
+                        orders.len()
+                    );
+                    return RiskDecision::Block;
+                }
+            }
+        }
+
+        if decision != PositionType::None {
+            self.orders.locked().push(now);
+        }
+        RiskDecision::Allow(self.limits.max_symbol_notional)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn day_and_week_starts() {
+        // Wednesday 2021-06-16 13:20:00 UTC.
+        let now = Utc.ymd(2021, 6, 16).and_hms(13, 20, 0).timestamp();
+        let (day, week) = period_starts(now);
+        assert_eq!(day, Utc.ymd(2021, 6, 16).and_hms(0, 0, 0).timestamp());
+        assert_eq!(week, Utc.ymd(2021, 6, 14).and_hms(0, 0, 0).timestamp());
+    }
+
+    #[test]
+    fn closed_trades_survive_restart() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("ct_risk_test_{}", std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+
+        // Saved before closed trades were kept.
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            RiskManager::state_file(&dir),
+            r#"{"halted": null, "peak_equity": 100.0}"#,
+        )
+        .unwrap();
+        let rm = RiskManager::new(RiskLimits::default(), "USDT", &dir);
+        assert!(rm.closed_trades().is_empty());
+
+        let trade = ClosedTrade {
+            time: 1623849600,
+            pnl: -12.5,
+            ret: -0.05,
+        };
+        {
+            let mut state = rm.state.locked();
+            state.closed_trades.push(trade.clone());
+            rm.save(&state);
+        }
+
+        let rm = RiskManager::new(RiskLimits::default(), "USDT", &dir);
+        assert_eq!(rm.closed_trades(), vec![trade]);
+        assert_eq!(rm.state.locked().peak_equity, 100.0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
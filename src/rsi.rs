@@ -0,0 +1,201 @@
+// Relative strength index (rsi.rs).
+//
+// Wilder's RSI, 100 - (100 / (1 + average gain / average loss)) with the gains and
+// losses smoothed by his moving average. Traded for mean reversion: buy as the RSI
+// climbs back out of oversold, sell once it reaches overbought or the middle line.
+use crate::ma;
+use crate::position::PositionType;
+use crate::process_md;
+use crate::tradingpair::TradingPair;
+
+use log::{debug, log};
+
+// Wilder's averages carry every candle they have seen, warm up on enough candles for
+// the start not to matter.
+static WARMUP_CANDLES: u16 = 250;
+
+static MIDDLE: f64 = 50.0;
+
+// When to sell a long.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RsiExit {
+    // On crossing above the overbought level.
+    Overbought,
+    // On crossing above the middle line.
+    Middle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RsiParams {
+    pub length: u16,
+    pub oversold: f64,
+    pub overbought: f64,
+    pub exit: RsiExit,
+}
+
+impl Default for RsiParams {
+    fn default() -> Self {
+        RsiParams {
+            length: 14,
+            oversold: 30.0,
+            overbought: 70.0,
+            exit: RsiExit::Overbought,
+        }
+    }
+}
+
+impl RsiParams {
+    pub fn candles_required(&self) -> u16 {
+        (self.length + 1).max(WARMUP_CANDLES)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RSI {
+    pub params: RsiParams,
+    gains: ma::MAData,
+    losses: ma::MAData,
+    prev_close: Option<f64>,
+    pub latest: Option<f64>,
+    pub previous: Option<f64>,
+}
+
+impl RSI {
+    pub fn new(params: RsiParams) -> Self {
+        RSI {
+            params: params,
+            gains: ma::MAData::new(params.length, ma::MAType::Rma),
+            losses: ma::MAData::new(params.length, ma::MAType::Rma),
+            prev_close: None,
+            latest: None,
+            previous: None,
+        }
+    }
+
+    pub fn compute(&mut self, close_price: f64) {
+        if let Some(prev_close) = self.prev_close {
+            let change = close_price - prev_close;
+            self.gains.compute(change.max(0.0));
+            self.losses.compute((-change).max(0.0));
+
+            if let (Some(gain), Some(loss)) = (self.gains.latest(), self.losses.latest()) {
+                let rsi = if loss == 0.0 {
+                    if gain == 0.0 {
+                        MIDDLE
+                    } else {
+                        100.0
+                    }
+                } else {
+                    100.0 - (100.0 / (1.0 + (gain / loss)))
+                };
+                self.previous = self.latest;
+                self.latest = Some(rsi);
+            }
+        }
+        self.prev_close = Some(close_price);
+    }
+
+    // Long on crossing back above oversold, Short on crossing above the exit level.
+    pub fn signal(&self) -> PositionType {
+        let (rsi, prev) = match (self.latest, self.previous) {
+            (Some(rsi), Some(prev)) => (rsi, prev),
+            _ => return PositionType::None,
+        };
+
+        let exit_level = match self.params.exit {
+            RsiExit::Overbought => self.params.overbought,
+            RsiExit::Middle => MIDDLE,
+        };
+
+        if rsi > self.params.oversold && prev <= self.params.oversold {
+            PositionType::Long
+        } else if rsi >= exit_level && prev < exit_level {
+            PositionType::Short
+        } else {
+            PositionType::None
+        }
+    }
+}
+
+pub fn trading_decision_rsi(
+    tp: &TradingPair,
+    mt: &process_md::MarketDataTracker,
+    closing_price: f64,
+) -> PositionType {
+    debug!(
+        "[RSI] {}, CLOSE: {}, RSI: {:?}, RSI_PREV: {:?}",
+        tp.symbol(),
+        closing_price,
+        mt.rsi.latest,
+        mt.rsi.previous,
+    );
+
+    let decision = mt.rsi.signal();
+    match decision {
+        PositionType::Long => log!(
+            mt.signal_log_level(),
+            "[BUY][RSI] {}, close: {}, signal: RSI({}) > OVERSOLD({}) >= RSI_PREV({})",
+            tp.symbol(),
+            closing_price,
+            mt.rsi.latest.unwrap(),
+            mt.rsi.params.oversold,
+            mt.rsi.previous.unwrap(),
+        ),
+        PositionType::Short => log!(
+            mt.signal_log_level(),
+            "[SELL][RSI] {}, close: {}, signal: RSI({}) crossed {:?} RSI_PREV({})",
+            tp.symbol(),
+            closing_price,
+            mt.rsi.latest.unwrap(),
+            mt.rsi.params.exit,
+            mt.rsi.previous.unwrap(),
+        ),
+        PositionType::None => {}
+    }
+
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wilder_rsi() {
+        let params = RsiParams {
+            length: 3,
+            ..Default::default()
+        };
+        let mut rsi = RSI::new(params);
+        for close in &[10.0, 11.0, 10.0, 12.0] {
+            rsi.compute(*close);
+        }
+        // Gains 1, 0, 2 and losses 0, 1, 0 over 3 candles.
+        assert!((rsi.latest.unwrap() - 75.0).abs() < 1e-9);
+
+        // Wilder smoothed, avg gain (1 * 2 + 0) / 3, avg loss (1/3 * 2 + 2) / 3.
+        rsi.compute(10.0);
+        let rs = (2.0 / 3.0) / (8.0 / 9.0);
+        assert!((rsi.latest.unwrap() - (100.0 - (100.0 / (1.0 + rs)))).abs() < 1e-9);
+    }
+
+    #[test]
+    fn signals() {
+        let mut rsi = RSI::new(RsiParams::default());
+        rsi.previous = Some(25.0);
+        rsi.latest = Some(31.0);
+        assert_eq!(rsi.signal(), PositionType::Long);
+
+        rsi.previous = Some(45.0);
+        rsi.latest = Some(55.0);
+        assert_eq!(rsi.signal(), PositionType::None);
+
+        rsi.params.exit = RsiExit::Middle;
+        assert_eq!(rsi.signal(), PositionType::Short);
+
+        rsi.params.exit = RsiExit::Overbought;
+        rsi.previous = Some(65.0);
+        rsi.latest = Some(72.0);
+        assert_eq!(rsi.signal(), PositionType::Short);
+    }
+}
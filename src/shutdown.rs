@@ -0,0 +1,34 @@
+// Shutdown on SIGINT/SIGTERM (shutdown.rs).
+//
+// The signal handler only sets a flag, the market data threads poll it and
+// exit, after which the account manager is shut down. A second signal exits
+// straight away.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        unsafe {
+            libc::_exit(130);
+        }
+    }
+}
+
+pub fn install_handlers() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+// Has a shutdown been asked for.
+pub fn requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+// Shut down as though we had been sent a signal.
+pub fn request() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
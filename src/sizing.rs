@@ -0,0 +1,173 @@
+// Position sizing (sizing.rs).
+//
+// Work out how much of the quote asset to spend when entering a position. Equity is
+// the free quote asset plus what is tied up in positions bought with it.
+use crate::binance::Binance;
+
+use log::{debug, error};
+use std::collections::HashMap;
+
+// Kelly sizing needs this many closed trades before it is trusted, until then this
+// fraction of equity is used.
+static KELLY_MIN_TRADES: usize = 5;
+static KELLY_DEFAULT_FRACTION: f64 = 0.05;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PositionSizing {
+    // Spend everything we are allowed to.
+    All,
+    // Spend this amount of the quote asset.
+    FixedQuote(f64),
+    // Spend this % of equity.
+    FixedFraction(f64),
+    // Risk losing this % of equity if the stop loss is hit.
+    FixedRisk(f64),
+    // Size so a move of one ATR is this % of equity.
+    Atr {
+        percent: f64,
+        period: u16,
+        time_frame: String,
+    },
+    // Spend this fraction of the Kelly criterion, from the results of the last
+    // 'lookback' trades.
+    Kelly {
+        fraction: f64,
+        lookback: usize,
+    },
+}
+
+// Mean true range over the candles, each is (high, low, close), oldest first.
+pub fn average_true_range(candles: &[(f64, f64, f64)]) -> Option<f64> {
+    if candles.len() < 2 {
+        return None;
+    }
+
+    let ranges: Vec<f64> = candles
+        .windows(2)
+        .map(|w| {
+            let prev_close = w[0].2;
+            let (high, low, _) = w[1];
+            (high - low)
+                .max((high - prev_close).abs())
+                .max((low - prev_close).abs())
+        })
+        .collect();
+
+    Some(ranges.iter().sum::<f64>() / ranges.len() as f64)
+}
+
+// Kelly fraction, W - (1 - W) / R, from a list of trade returns where W is the
+// proportion of winners and R the average win over the average loss.
+pub fn kelly_fraction(returns: &[f64]) -> f64 {
+    let wins: Vec<f64> = returns.iter().cloned().filter(|r| *r > 0.0).collect();
+    let losses: Vec<f64> = returns.iter().cloned().filter(|r| *r <= 0.0).collect();
+    if wins.is_empty() {
+        return 0.0;
+    }
+
+    let w = wins.len() as f64 / returns.len() as f64;
+    let ave_win = wins.iter().sum::<f64>() / wins.len() as f64;
+    let ave_loss = losses.iter().map(|l| l.abs()).sum::<f64>() / losses.len().max(1) as f64;
+    if ave_loss == 0.0 {
+        return w;
+    }
+
+    (w - ((1.0 - w) / (ave_win / ave_loss))).max(0.0).min(1.0)
+}
+
+fn get_atr(bex: &Binance, symbol: &str, period: u16, time_frame: &str) -> Option<f64> {
+    let limit = (period + 1).to_string();
+    let mut req_params: HashMap<&str, &str> = HashMap::with_capacity(3);
+    req_params.insert("symbol", symbol);
+    req_params.insert("interval", time_frame);
+    req_params.insert("limit", &limit);
+
+    match bex.get_cstick_data(&req_params) {
+        Ok(cd) => {
+            let candles: Vec<(f64, f64, f64)> = cd
+                .iter()
+                .filter_map(|c| {
+                    Some((
+                        c.high_price.parse::<f64>().ok()?,
+                        c.low_price.parse::<f64>().ok()?,
+                        c.close_price.parse::<f64>().ok()?,
+                    ))
+                })
+                .collect();
+            average_true_range(&candles)
+        }
+        Err(code) => {
+            error!("failed to get cstick data for {}: {}", symbol, code);
+            None
+        }
+    }
+}
+
+// Quote asset amount to spend, None if it can't be worked out. 'returns' are the
+// fractional returns of our most recent trades, oldest first.
+pub fn quote_amount(
+    bex: &Binance,
+    sizing: &PositionSizing,
+    symbol: &str,
+    equity: f64,
+    price: f64,
+    stop_percent: Option<f64>,
+    returns: &[f64],
+) -> Option<f64> {
+    let amount = match sizing {
+        PositionSizing::All => equity,
+        PositionSizing::FixedQuote(q) => *q,
+        PositionSizing::FixedFraction(pct) => equity * (pct / 100.0),
+        PositionSizing::FixedRisk(pct) => {
+            // Losing stop_percent of the position should lose pct of equity.
+            let stop_percent = stop_percent?;
+            equity * (pct / stop_percent)
+        }
+        PositionSizing::Atr {
+            percent,
+            period,
+            time_frame,
+        } => {
+            let atr = get_atr(bex, symbol, *period, time_frame)?;
+            if atr <= 0.0 {
+                return None;
+            }
+            let qty = (equity * (percent / 100.0)) / atr;
+            debug!("{} atr: {}, qty: {}", symbol, atr, qty);
+            qty * price
+        }
+        PositionSizing::Kelly { fraction, lookback } => {
+            let recent = &returns[returns.len().saturating_sub(*lookback)..];
+            if recent.len() < KELLY_MIN_TRADES {
+                equity * KELLY_DEFAULT_FRACTION
+            } else {
+                let kelly = kelly_fraction(recent);
+                debug!("kelly fraction over {} trades: {}", recent.len(), kelly);
+                equity * kelly * fraction
+            }
+        }
+    };
+
+    Some(amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn true_range_includes_gaps() {
+        // Second candle gaps up from the previous close.
+        let candles = vec![(10.0, 9.0, 9.5), (12.0, 11.0, 11.5), (12.0, 11.0, 11.0)];
+        assert_eq!(average_true_range(&candles), Some(1.75));
+        assert_eq!(average_true_range(&candles[..1]), None);
+    }
+
+    #[test]
+    fn kelly() {
+        // 60% winners making twice what the losers lose.
+        let returns = vec![0.02, 0.02, 0.02, -0.01, -0.01];
+        assert!((kelly_fraction(&returns) - 0.4).abs() < 1e-9);
+        assert_eq!(kelly_fraction(&[-0.01, -0.02]), 0.0);
+    }
+}
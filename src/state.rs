@@ -0,0 +1,78 @@
+// Trading state which survives a restart (state.rs).
+//
+// One json file is kept per symbol in the state directory, it is rewritten
+// whenever the position, the orders protecting it or the running totals change.
+use crate::account_manager;
+use crate::position;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use account_manager::ExitParams;
+use position::Position;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SymbolState {
+    // Position we currently hold, if any.
+    pub position: Option<Position>,
+    // Price decimal places of the symbol, needed to re-create exit orders.
+    pub price_dps: Option<u8>,
+    // Exit orders resting on the exchange which protect the position.
+    pub open_orders: Vec<u64>,
+    // Exit order settings in use when the position was opened.
+    pub exit_params: ExitParams,
+    // Id of the last trade we have accounted for, trades after this were
+    // made while we were not running.
+    pub last_trade_id: Option<u64>,
+    // Commission paid buying the position we hold, charged to its PnL when sold.
+    #[serde(default)]
+    pub entry_commission: f64,
+    pub cuml_pnl: f64,
+    pub cuml_commission: f64,
+}
+
+fn state_file(state_dir: &str, symbol: &str) -> PathBuf {
+    let mut pb = PathBuf::from(state_dir);
+    pb.push(format!("{}.json", symbol));
+    pb
+}
+
+// Load the saved state for the symbol, None if we have never traded it.
+pub fn load(state_dir: &str, symbol: &str) -> Option<SymbolState> {
+    let pb = state_file(state_dir, symbol);
+    let data = match fs::read_to_string(pb.as_path()) {
+        Ok(data) => data,
+        Err(_) => return None,
+    };
+
+    match serde_json::from_str(&data) {
+        Ok(state) => Some(state),
+        Err(e) => {
+            error!("failed to parse saved state {}: {}", pb.display(), e);
+            None
+        }
+    }
+}
+
+// Save the state for the symbol, written to a temporary file first so that we
+// never leave a half written file behind.
+pub fn save(state_dir: &str, symbol: &str, state: &SymbolState) {
+    if let Err(e) = fs::create_dir_all(state_dir) {
+        error!("failed to create state directory {}: {}", state_dir, e);
+        return;
+    }
+
+    let pb = state_file(state_dir, symbol);
+    let tmp = pb.with_extension("json.tmp");
+    let data = serde_json::to_string_pretty(state).unwrap();
+    if let Err(e) = fs::write(tmp.as_path(), data) {
+        error!("failed to write state {}: {}", tmp.display(), e);
+        return;
+    }
+
+    if let Err(e) = fs::rename(tmp.as_path(), pb.as_path()) {
+        error!("failed to save state {}: {}", pb.display(), e);
+    }
+}
@@ -0,0 +1,360 @@
+// Market data stream hub (stream_hub.rs).
+//
+// Market data for every pair is received over a few Binance combined stream
+// connections, wss://.../stream?streams=<a>/<b>/..., rather than a connection per
+// pair. Threads subscribe to a stream, for example adausdt@kline_1m, and are sent
+// the data of each event for it over a channel. Streams can be subscribed to and
+// unsubscribed from while connected, a stream nobody is listening to any more is
+// unsubscribed from on its next event.
+//
+// Each connection is run by the supervisor, when it is restarted it reconnects
+// with the streams it carried before. Binance drops a connection which sends more
+// than 5 messages a second, so requests are paced and streams already in the URL
+// we connected with are not subscribed to again.
+use crate::shutdown;
+use crate::supervisor;
+
+use log::{debug, error, info};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use websocket::{stream::sync::NetworkStream, sync::Client, ClientBuilder, OwnedMessage};
+
+use supervisor::{LockExt, Supervisor};
+
+static STREAM_ENDPOINT: &str = "wss://stream.binance.com:9443/stream";
+// Binance allows 1024, keep well clear so a reconnect URL stays a sane length.
+static MAX_STREAMS_PER_CONNECTION: usize = 100;
+// Time between requests, under 5 a second to leave room for pongs.
+static REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+enum Command {
+    Subscribe(String),
+    Unsubscribe(String),
+    Reconnect,
+}
+
+struct Connection {
+    streams: HashSet<String>,
+    commands: mpsc::Sender<Command>,
+}
+
+#[derive(Default)]
+struct HubState {
+    connections: Vec<Connection>,
+    subscribers: HashMap<String, Vec<mpsc::Sender<serde_json::Value>>>,
+}
+
+#[derive(Clone)]
+pub struct StreamHub {
+    state: Arc<Mutex<HubState>>,
+    supervisor: Supervisor,
+}
+
+// URL of a combined stream carrying 'streams'.
+fn combined_url(streams: &HashSet<String>) -> String {
+    if streams.is_empty() {
+        return STREAM_ENDPOINT.to_string();
+    }
+
+    let mut names: Vec<&str> = streams.iter().map(|s| s.as_str()).collect();
+    names.sort();
+    format!("{}?streams={}", STREAM_ENDPOINT, names.join("/"))
+}
+
+// Request to change the streams on a connection.
+fn request(method: &str, stream: &str, id: u64) -> String {
+    serde_json::json!({"method": method, "params": [stream], "id": id}).to_string()
+}
+
+// Wait until we may send the next request.
+fn pace(last_request: &mut Option<Instant>) {
+    if let Some(t) = last_request {
+        let elapsed = t.elapsed();
+        if elapsed < REQUEST_INTERVAL {
+            thread::sleep(REQUEST_INTERVAL - elapsed);
+        }
+    }
+    *last_request = Some(Instant::now());
+}
+
+fn connect(url: &str) -> Option<Client<Box<dyn NetworkStream + std::marker::Send>>> {
+    let mut cur_try = 0;
+    while cur_try < 5 {
+        cur_try += 1;
+        match ClientBuilder::new(url).unwrap().connect(None) {
+            Ok(c) => {
+                // Short timeout so that commands are not held up by a quiet stream.
+                c.stream_ref()
+                    .as_tcp()
+                    .set_read_timeout(Some(Duration::from_secs(1)))
+                    .expect("failed to set read timeout");
+                info!("connected to {}", url);
+                return Some(c);
+            }
+            Err(e) => {
+                error!("failed to connect to {}: {}", url, e);
+                thread::sleep(Duration::from_millis(5000 * cur_try));
+            }
+        }
+    }
+
+    None
+}
+
+impl StreamHub {
+    pub fn new(supervisor: &Supervisor) -> StreamHub {
+        StreamHub {
+            state: Arc::new(Mutex::new(HubState::default())),
+            supervisor: supervisor.clone(),
+        }
+    }
+
+    // Receive the data of every event on 'stream'.
+    pub fn subscribe(&self, stream: &str) -> mpsc::Receiver<serde_json::Value> {
+        let (tx, rx) = mpsc::channel();
+        let mut state = self.state.locked();
+
+        if let Some(subs) = state.subscribers.get_mut(stream) {
+            subs.push(tx);
+            return rx;
+        }
+        state.subscribers.insert(stream.to_string(), vec![tx]);
+
+        match state
+            .connections
+            .iter_mut()
+            .find(|c| c.streams.len() < MAX_STREAMS_PER_CONNECTION)
+        {
+            Some(conn) => {
+                conn.streams.insert(stream.to_string());
+                let _ = conn.commands.send(Command::Subscribe(stream.to_string()));
+            }
+            None => {
+                let (cmd_tx, cmd_rx) = mpsc::channel();
+                let id = state.connections.len();
+                let mut streams = HashSet::new();
+                streams.insert(stream.to_string());
+                state.connections.push(Connection {
+                    streams: streams,
+                    commands: cmd_tx,
+                });
+
+                let hub = self.clone();
+                let cmd_rx = Arc::new(Mutex::new(cmd_rx));
+                self.supervisor
+                    .spawn(&format!("market data stream {}", id), move || {
+                        hub.connection_thread(id, &cmd_rx)
+                    });
+            }
+        }
+
+        rx
+    }
+
+    // Stop receiving 'stream', for everyone subscribed to it.
+    #[allow(dead_code)]
+    pub fn unsubscribe(&self, stream: &str) {
+        let mut state = self.state.locked();
+        state.subscribers.remove(stream);
+        for conn in state.connections.iter_mut() {
+            if conn.streams.remove(stream) {
+                let _ = conn.commands.send(Command::Unsubscribe(stream.to_string()));
+            }
+        }
+    }
+
+    // Reconnect the connection carrying 'stream', for when it has gone quiet.
+    pub fn reconnect(&self, stream: &str) {
+        let state = self.state.locked();
+        for conn in state.connections.iter() {
+            if conn.streams.contains(stream) {
+                let _ = conn.commands.send(Command::Reconnect);
+            }
+        }
+    }
+
+    // Hand the event to everyone subscribed to its stream. Returns false if there is
+    // nobody left listening, after which the stream is unsubscribed from.
+    fn dispatch(&self, stream: &str, data: serde_json::Value) -> bool {
+        let mut state = self.state.locked();
+        let subs = match state.subscribers.get_mut(stream) {
+            Some(subs) => subs,
+            None => return false,
+        };
+
+        subs.retain(|tx| tx.send(data.clone()).is_ok());
+        if subs.is_empty() {
+            state.subscribers.remove(stream);
+            for conn in state.connections.iter_mut() {
+                conn.streams.remove(stream);
+            }
+            return false;
+        }
+        true
+    }
+
+    fn connection_thread(
+        &self,
+        id: usize,
+        commands: &Mutex<mpsc::Receiver<Command>>,
+    ) -> Result<(), String> {
+        let commands = commands.locked();
+        let streams = || self.state.locked().connections[id].streams.clone();
+        // Streams we are receiving, those in the URL we connected with plus any
+        // subscribed to since.
+        let mut carried = streams();
+        let mut conn = match connect(&combined_url(&carried)) {
+            Some(c) => c,
+            None => return Err(String::from("failed to connect to market data stream")),
+        };
+        let mut request_id: u64 = 0;
+        let mut last_request = None;
+
+        while !shutdown::requested() {
+            // Apply any changes to our streams first.
+            while let Ok(cmd) = commands.try_recv() {
+                let msg = match cmd {
+                    Command::Subscribe(stream) => {
+                        if !carried.insert(stream.clone()) {
+                            debug!("market data stream {} already carries {}", id, stream);
+                            continue;
+                        }
+                        request("SUBSCRIBE", &stream, request_id)
+                    }
+                    Command::Unsubscribe(stream) => {
+                        carried.remove(&stream);
+                        request("UNSUBSCRIBE", &stream, request_id)
+                    }
+                    Command::Reconnect => {
+                        info!("reconnecting market data stream {}", id);
+                        let _ = conn.shutdown();
+                        carried = streams();
+                        conn = match connect(&combined_url(&carried)) {
+                            Some(c) => c,
+                            None => {
+                                return Err(String::from(
+                                    "failed to reconnect to market data stream",
+                                ))
+                            }
+                        };
+                        continue;
+                    }
+                };
+                request_id += 1;
+
+                pace(&mut last_request);
+                debug!("market data stream {} request: {}", id, msg);
+                if let Err(e) = conn.send_message(&OwnedMessage::Text(msg)) {
+                    error!("failed to send request to market data stream: {}", e);
+                }
+            }
+
+            match conn.recv_message() {
+                Ok(OwnedMessage::Text(s)) => {
+                    let mut msg: serde_json::Value = match serde_json::from_str(&s) {
+                        Ok(msg) => msg,
+                        Err(_) => {
+                            error!("failed to deserialize market data: {}", s);
+                            continue;
+                        }
+                    };
+
+                    match msg["stream"].as_str().map(|s| s.to_string()) {
+                        Some(stream) => {
+                            if !self.dispatch(&stream, msg["data"].take()) {
+                                info!("nobody listening to {}, unsubscribing", stream);
+                                carried.remove(&stream);
+                                let req = request("UNSUBSCRIBE", &stream, request_id);
+                                request_id += 1;
+                                pace(&mut last_request);
+                                let _ = conn.send_message(&OwnedMessage::Text(req));
+                            }
+                        }
+                        None => {
+                            // Response to a SUBSCRIBE or UNSUBSCRIBE.
+                            if msg["error"].is_null() {
+                                debug!("market data stream {} response: {}", id, s);
+                            } else {
+                                error!("market data stream {} request failed: {}", id, s);
+                            }
+                        }
+                    }
+                }
+
+                Ok(OwnedMessage::Ping(m)) => {
+                    if let Err(e) = conn.send_message(&OwnedMessage::Pong(m)) {
+                        error!("failed to reply to ping message: {}", e);
+                    }
+                }
+
+                Ok(OwnedMessage::Pong(_)) | Ok(OwnedMessage::Binary(_)) => {}
+
+                Ok(OwnedMessage::Close(e)) => {
+                    info!("disconnected from market data stream {}: {:?}", id, e);
+                    carried = streams();
+                    conn = match connect(&combined_url(&carried)) {
+                        Some(c) => c,
+                        None => {
+                            return Err(String::from("failed to reconnect to market data stream"))
+                        }
+                    };
+                }
+
+                Err(websocket::WebSocketError::IoError(ref e))
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut => {}
+
+                Err(e) => {
+                    error!(
+                        "failed to receive data from market data stream {}: {}",
+                        id, e
+                    );
+                    carried = streams();
+                    conn = match connect(&combined_url(&carried)) {
+                        Some(c) => c,
+                        None => {
+                            return Err(String::from("failed to reconnect to market data stream"))
+                        }
+                    };
+                }
+            }
+        }
+
+        let _ = conn.shutdown();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combined_stream_url() {
+        let mut streams = HashSet::new();
+        streams.insert(String::from("btcusdt@kline_1m"));
+        streams.insert(String::from("adausdt@kline_1m"));
+        assert_eq!(
+            combined_url(&streams),
+            "wss://stream.binance.com:9443/stream?streams=adausdt@kline_1m/btcusdt@kline_1m"
+        );
+        assert_eq!(
+            request("SUBSCRIBE", "adausdt@depth", 3),
+            r#"{"id":3,"method":"SUBSCRIBE","params":["adausdt@depth"]}"#
+        );
+    }
+
+    #[test]
+    fn paced_requests() {
+        let start = Instant::now();
+        let mut last_request = None;
+        for _ in 0..3 {
+            pace(&mut last_request);
+        }
+        assert!(start.elapsed() >= REQUEST_INTERVAL * 2);
+    }
+}
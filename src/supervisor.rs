@@ -0,0 +1,269 @@
+// Worker thread supervision (supervisor.rs).
+//
+// Every long running thread is run by the supervisor. A worker which panics, or
+// returns an error, is started again after a back off; when it starts again it
+// picks up its state from the shared account data, the state directory or the
+// exchange. A worker which fails more than MaxRestarts times in an hour is given
+// up on and ct is shut down as though it had been sent SIGTERM.
+//
+// The status of each worker is written to <StateDir>/status.json whenever it
+// changes and a summary is logged every STATUS_INTERVAL seconds.
+use crate::shutdown;
+
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+static STATUS_INTERVAL: i64 = 15 * 60;
+// Longest wait before a restart, seconds.
+static MAX_BACKOFF: u64 = 5 * 60;
+
+// A worker which panics while holding a lock poisons it. What is behind the lock is
+// as the worker left it, which the restarted worker can carry on with, so state shared
+// between workers is locked with locked() rather than lock().unwrap().
+pub trait LockExt<T> {
+    fn locked(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn locked(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum WorkerState {
+    Running,
+    Restarting,
+    Stopped,
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct WorkerStatus {
+    pub state: WorkerState,
+    pub restarts: usize,
+    // Unix time in seconds the worker was last started.
+    pub started: i64,
+    pub last_failure: Option<String>,
+    // Times of the failures in the last hour.
+    #[serde(skip)]
+    failures: Vec<i64>,
+}
+
+#[derive(Clone)]
+pub struct Supervisor {
+    workers: Arc<Mutex<BTreeMap<String, WorkerStatus>>>,
+    max_restarts: usize,
+    // Wait before the first restart, doubled on each failure after.
+    backoff: Duration,
+    state_dir: String,
+}
+
+// Best effort at getting the message a worker panicked with.
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("panicked")
+    }
+}
+
+impl Supervisor {
+    pub fn new(state_dir: &str, max_restarts: usize) -> Supervisor {
+        let sv = Supervisor {
+            workers: Arc::new(Mutex::new(BTreeMap::new())),
+            max_restarts: max_restarts,
+            backoff: Duration::from_secs(5),
+            state_dir: state_dir.to_string(),
+        };
+
+        let reporter = sv.clone();
+        thread::spawn(move || {
+            let mut last_report = Utc::now().timestamp();
+            while !shutdown::requested() {
+                thread::sleep(Duration::from_secs(1));
+                if Utc::now().timestamp() - last_report >= STATUS_INTERVAL {
+                    reporter.report();
+                    last_report = Utc::now().timestamp();
+                }
+            }
+        });
+
+        sv
+    }
+
+    // Run 'worker' on its own thread under supervision. The worker should return
+    // Ok when it has finished, for example on shutdown, anything else is a failure.
+    pub fn spawn<F>(&self, name: &str, worker: F) -> thread::JoinHandle<()>
+    where
+        F: FnMut() -> Result<(), String> + Send + 'static,
+    {
+        let sv = self.clone();
+        let name = name.to_string();
+        thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || sv.supervise(&name, worker))
+            .expect("failed to spawn worker thread")
+    }
+
+    fn supervise<F>(&self, name: &str, mut worker: F)
+    where
+        F: FnMut() -> Result<(), String>,
+    {
+        loop {
+            self.update(name, |ws| {
+                ws.state = WorkerState::Running;
+                ws.started = Utc::now().timestamp();
+            });
+
+            let reason = match panic::catch_unwind(AssertUnwindSafe(|| worker())) {
+                Ok(Ok(())) => {
+                    self.update(name, |ws| ws.state = WorkerState::Stopped);
+                    return;
+                }
+                Ok(Err(reason)) => reason,
+                Err(payload) => format!("panic: {}", panic_message(&payload)),
+            };
+
+            if shutdown::requested() {
+                self.update(name, |ws| ws.state = WorkerState::Stopped);
+                return;
+            }
+
+            error!("worker {} failed: {}", name, reason);
+            let now = Utc::now().timestamp();
+            let mut failures = 0;
+            self.update(name, |ws| {
+                ws.failures.retain(|t| *t > now - (60 * 60));
+                ws.failures.push(now);
+                ws.last_failure = Some(reason.clone());
+                failures = ws.failures.len();
+                ws.state = if failures > self.max_restarts {
+                    WorkerState::Failed
+                } else {
+                    WorkerState::Restarting
+                };
+            });
+
+            if failures > self.max_restarts {
+                error!(
+                    "worker {} failed {} times in the last hour, shutting down",
+                    name, failures
+                );
+                shutdown::request();
+                return;
+            }
+
+            let backoff = (self.backoff * 2u32.pow(failures as u32 - 1))
+                .min(Duration::from_secs(MAX_BACKOFF));
+            warn!("restarting worker {} in {:?}", name, backoff);
+            let mut waited = Duration::from_secs(0);
+            while waited < backoff {
+                if shutdown::requested() {
+                    self.update(name, |ws| ws.state = WorkerState::Stopped);
+                    return;
+                }
+                let step = Duration::from_millis(250).min(backoff - waited);
+                thread::sleep(step);
+                waited += step;
+            }
+
+            self.update(name, |ws| ws.restarts += 1);
+        }
+    }
+
+    // Change the status of a worker and save the status of them all.
+    fn update<F>(&self, name: &str, f: F)
+    where
+        F: FnOnce(&mut WorkerStatus),
+    {
+        let mut workers = self.workers.locked();
+        let ws = workers
+            .entry(name.to_string())
+            .or_insert_with(|| WorkerStatus {
+                state: WorkerState::Running,
+                restarts: 0,
+                started: Utc::now().timestamp(),
+                last_failure: None,
+                failures: Vec::new(),
+            });
+        f(ws);
+        self.save(&workers);
+    }
+
+    fn save(&self, workers: &BTreeMap<String, WorkerStatus>) {
+        if let Err(e) = fs::create_dir_all(&self.state_dir) {
+            error!("failed to create state directory {}: {}", self.state_dir, e);
+            return;
+        }
+
+        let mut pb = PathBuf::from(&self.state_dir);
+        pb.push("status.json");
+        if let Err(e) = fs::write(pb.as_path(), serde_json::to_string_pretty(workers).unwrap()) {
+            error!("failed to save status {}: {}", pb.display(), e);
+        }
+    }
+
+    pub fn status(&self) -> BTreeMap<String, WorkerStatus> {
+        self.workers.locked().clone()
+    }
+
+    // Log a line per worker.
+    pub fn report(&self) {
+        for (name, ws) in self.status() {
+            info!(
+                "worker {}: {:?}, restarts: {}, last failure: {}",
+                name,
+                ws.state,
+                ws.restarts,
+                ws.last_failure.as_deref().unwrap_or("none")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_until_worker_finishes() {
+        let mut pb = std::env::temp_dir();
+        pb.push("ct_supervisor_test");
+        let sv = Supervisor {
+            workers: Arc::new(Mutex::new(BTreeMap::new())),
+            max_restarts: 5,
+            backoff: Duration::from_millis(1),
+            state_dir: pb.to_string_lossy().to_string(),
+        };
+
+        // Panic once, fail once, then finish.
+        let mut runs = 0;
+        sv.spawn("test", move || {
+            runs += 1;
+            match runs {
+                1 => panic!("bad payload"),
+                2 => Err(String::from("stream closed")),
+                _ => Ok(()),
+            }
+        })
+        .join()
+        .unwrap();
+
+        let ws = &sv.status()["test"];
+        assert_eq!(ws.state, WorkerState::Stopped);
+        assert_eq!(ws.restarts, 2);
+        assert_eq!(ws.last_failure.as_deref(), Some("stream closed"));
+    }
+}
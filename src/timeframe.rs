@@ -0,0 +1,160 @@
+// Higher time frames (timeframe.rs).
+//
+// Besides the time frame we trade on, a symbol can follow any number of higher time
+// frames, for example a 4h trend filter for 15m entries. Each has its own history
+// and moving average. Only closed candles are used, the candle forming on a higher
+// time frame is never looked at, so a decision only sees what was known at the time.
+use crate::binance::Binance;
+use crate::ma;
+use crate::position::PositionType;
+
+use log::error;
+
+#[derive(Debug, Clone)]
+pub struct HigherTimeFrame {
+    pub interval: String,
+    pub ma: ma::MAData,
+    // Close price and close time of the last closed candle.
+    pub close: Option<f64>,
+    pub close_time: Option<u64>,
+}
+
+impl HigherTimeFrame {
+    pub fn new(interval: &str, ma_len: u16, ma_type: ma::MAType) -> HigherTimeFrame {
+        HigherTimeFrame {
+            interval: interval.to_string(),
+            ma: ma::MAData::new(ma_len, ma_type),
+            close: None,
+            close_time: None,
+        }
+    }
+
+    // Fill the history from the exchange, starting afresh.
+    pub fn warm_up(&mut self, bex: &Binance, symbol: &str) -> Result<(), i64> {
+        let server_time = bex.get_server_time()?;
+        let count = self.ma.candles_required() as u64 + 3;
+
+        self.ma = ma::MAData::new(self.ma.num_candles, self.ma.ma_type);
+        self.close = None;
+        self.close_time = None;
+        for stick in bex.get_recent_cstick_data(symbol, &self.interval, count)? {
+            // The last candle is usually still forming.
+            if stick.close_time > server_time {
+                continue;
+            }
+            match stick.close_price.parse::<f64>() {
+                Ok(close) => self.add_close(close, stick.close_time),
+                Err(_) => error!("failed to parse closing price {:?}", stick.close_price),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_close(&mut self, close: f64, close_time: u64) {
+        self.ma.compute(close);
+        self.close = Some(close);
+        self.close_time = Some(close_time);
+    }
+
+    // Apply a kline stream event, only closed candles are used. Returns true if
+    // candles were missed while the stream was down, we need warming up again.
+    pub fn on_kline(&mut self, k: &serde_json::Value) -> bool {
+        if k["x"] != true {
+            return false;
+        }
+        let open_time = k["t"].as_u64().unwrap_or(0);
+        let close_time = k["T"].as_u64().unwrap_or(0);
+        let close = match k["c"].as_str().and_then(|c| c.parse::<f64>().ok()) {
+            Some(close) => close,
+            None => {
+                error!("failed to parse closing price: {}", k);
+                return false;
+            }
+        };
+
+        match self.close_time {
+            // Already seen.
+            Some(last) if close_time <= last => false,
+            Some(last) if open_time > last + 1 => true,
+            _ => {
+                self.add_close(close, close_time);
+                false
+            }
+        }
+    }
+
+    // Long when the close is above a rising moving average, Short when below a falling
+    // one, otherwise None.
+    pub fn trend(&self) -> PositionType {
+        trend(self.close, self.ma.latest(), self.ma.penultimate())
+    }
+}
+
+fn trend(close: Option<f64>, ma: Option<f64>, prev_ma: Option<f64>) -> PositionType {
+    match (close, ma, prev_ma) {
+        (Some(close), Some(ma), Some(prev_ma)) => {
+            if close > ma && ma >= prev_ma {
+                PositionType::Long
+            } else if close < ma && ma <= prev_ma {
+                PositionType::Short
+            } else {
+                PositionType::None
+            }
+        }
+        _ => PositionType::None,
+    }
+}
+
+// Parse 4h or 4h:50, a time frame and the length of its moving average.
+pub fn parse(s: &str, default_ma: u16) -> Option<(String, u16)> {
+    let mut parts = s.trim().split(':');
+    let interval = parts.next().filter(|i| !i.is_empty())?;
+    let ma_len = match parts.next() {
+        Some(len) => len.parse::<u16>().ok().filter(|l| *l > 1)?,
+        None => default_ma,
+    };
+    Some((interval.to_string(), ma_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trends() {
+        assert_eq!(trend(Some(11.0), Some(10.0), Some(9.0)), PositionType::Long);
+        assert_eq!(
+            trend(Some(9.0), Some(10.0), Some(11.0)),
+            PositionType::Short
+        );
+        // Above a falling average.
+        assert_eq!(
+            trend(Some(11.0), Some(10.0), Some(10.5)),
+            PositionType::None
+        );
+        assert_eq!(trend(Some(11.0), None, None), PositionType::None);
+
+        assert_eq!(parse("4h", 50), Some(("4h".to_string(), 50)));
+        assert_eq!(parse(" 1d:20", 50), Some(("1d".to_string(), 20)));
+        assert_eq!(parse("1d:x", 50), None);
+    }
+
+    #[test]
+    fn closed_candles_only() {
+        let mut htf = HigherTimeFrame::new("1h", 2, ma::MAType::Sma);
+        let kline = |t: u64, c: &str, x: bool| serde_json::json!({"t": t, "T": t + 3_599_999, "c": c, "x": x});
+
+        htf.on_kline(&kline(0, "10.0", true));
+        htf.on_kline(&kline(3_600_000, "30.0", false));
+        assert_eq!(htf.close, Some(10.0));
+
+        assert!(!htf.on_kline(&kline(3_600_000, "12.0", true)));
+        assert!(!htf.on_kline(&kline(3_600_000, "12.0", true)));
+        assert_eq!(htf.close, Some(12.0));
+        assert_eq!(htf.ma.latest(), Some(11.0));
+
+        // A candle was missed.
+        assert!(htf.on_kline(&kline(3 * 3_600_000, "13.0", true)));
+        assert_eq!(htf.close, Some(12.0));
+    }
+}
@@ -13,11 +13,12 @@ pub struct TradingPair {
     sell_currency: String,
     buy_currency: String,
     bvlt_type: Option<BvltType>,
-    price_dps: i8,     // Price decimal places.
-    qty_dps: i8,       // Trade quantity decimal places.
-    min_order: f64,    // Smallest amount we can buy/sell.
-    tick_size: f64,    // Min price increment.
-    min_notional: f64, // Min qty*price allowed.
+    price_dps: i8,      // Price decimal places.
+    qty_dps: i8,        // Trade quantity decimal places.
+    min_order: f64,     // Smallest amount we can buy/sell.
+    tick_size: f64,     // Min price increment.
+    min_notional: f64,  // Min qty*price allowed.
+    iceberg_parts: u32, // Most parts an iceberg order may have.
 }
 
 impl TradingPair {
@@ -27,6 +28,7 @@ impl TradingPair {
         let lot_size_filter = bex.get_lot_size_filter(&symbol).unwrap();
         let price_filter = bex.get_price_filter(&symbol).unwrap();
         let min_notional = bex.get_min_notional_filter(&symbol).unwrap();
+        let iceberg_parts = bex.get_iceberg_parts_filter(&symbol).unwrap();
 
         TradingPair {
             // EXAMPLE.
@@ -49,6 +51,7 @@ impl TradingPair {
             min_order: lot_size_filter.min_qty,
             tick_size: price_filter.tick_size,
             min_notional: min_notional,
+            iceberg_parts: iceberg_parts,
         }
     }
 
@@ -93,6 +96,10 @@ impl TradingPair {
     pub fn get_min_notional(&self) -> f64 {
         self.min_notional
     }
+
+    pub fn get_iceberg_parts(&self) -> u32 {
+        self.iceberg_parts
+    }
 }
 
 #[cfg(test)]
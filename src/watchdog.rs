@@ -0,0 +1,207 @@
+// Stale market data and clock drift detection (watchdog.rs).
+//
+// One watchdog runs in each market data thread. Trading on the pair is paused, and
+// an error logged, when:
+//
+// No closed candle has arrived within the candle interval plus a grace period.
+// The event time of a candle lags the server time by too much.
+// Our clock has drifted from the server clock by too much.
+//
+// While paused the indicators are still updated, trading resumes with the first
+// closed candle which arrives on time once the clock is back in line.
+use crate::bars::BarType;
+use crate::binance::Binance;
+
+use chrono::Utc;
+use log::{error, info};
+use std::time::{Duration, Instant};
+
+// How often our clock is checked against the server.
+static CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchdogLimits {
+    // Allowed lateness of a closed candle.
+    pub grace_ms: u64,
+    pub max_event_lag_ms: u64,
+    pub max_clock_drift_ms: u64,
+}
+
+impl Default for WatchdogLimits {
+    fn default() -> Self {
+        WatchdogLimits {
+            grace_ms: 30 * 1000,
+            max_event_lag_ms: 5 * 1000,
+            max_clock_drift_ms: 1000,
+        }
+    }
+}
+
+// Is the candle after the one which closed at 'last_close_time' overdue at 'now'.
+pub fn candle_overdue(now: u64, last_close_time: u64, interval_ms: u64, grace_ms: u64) -> bool {
+    now > last_close_time + interval_ms + grace_ms
+}
+
+// Length of a kline interval such as 1m, 4h, 1w or 1M, 0 if not known. A month is
+// taken as the longest one.
+pub fn kline_interval_ms(time_frame: &str) -> u64 {
+    let day_ms = 24 * 60 * 60 * 1000;
+    match time_frame.split_at(time_frame.len().saturating_sub(1)) {
+        (n, "w") => n.parse::<u64>().map(|n| n * 7 * day_ms).unwrap_or(0),
+        (n, "M") => n.parse::<u64>().map(|n| n * 31 * day_ms).unwrap_or(0),
+        _ => match BarType::parse(time_frame) {
+            Some(BarType::Time(interval)) => interval,
+            _ => 0,
+        },
+    }
+}
+
+// Offset of our clock from the server's, in ms, given our time before and after
+// asking the server for its time. The server time is taken to be from half way
+// through the round trip.
+pub fn clock_offset(local_before: u64, local_after: u64, server: u64) -> i64 {
+    let local_mid = local_before + ((local_after - local_before) / 2);
+    local_mid as i64 - server as i64
+}
+
+pub struct DataWatchdog {
+    symbol: String,
+    limits: WatchdogLimits,
+    interval_ms: u64,
+    // Our clock minus the server clock, ms.
+    offset_ms: i64,
+    last_clock_check: Option<Instant>,
+    clock_drifted: bool,
+    // Why trading is paused.
+    paused: Option<String>,
+}
+
+impl DataWatchdog {
+    pub fn new(symbol: &str, limits: WatchdogLimits, interval_ms: u64) -> DataWatchdog {
+        DataWatchdog {
+            symbol: symbol.to_string(),
+            limits: limits,
+            interval_ms: interval_ms,
+            offset_ms: 0,
+            last_clock_check: None,
+            clock_drifted: false,
+            paused: None,
+        }
+    }
+
+    fn local_ms() -> u64 {
+        Utc::now().timestamp_millis() as u64
+    }
+
+    // Our best estimate of the server time.
+    fn server_ms(&self) -> u64 {
+        (Self::local_ms() as i64 - self.offset_ms) as u64
+    }
+
+    fn pause(&mut self, reason: String) {
+        if self.paused.is_none() {
+            error!("{} trading paused: {}", self.symbol, reason);
+        }
+        self.paused = Some(reason);
+    }
+
+    pub fn trading_allowed(&self) -> bool {
+        self.paused.is_none()
+    }
+
+    // Compare our clock with the server's, at most every CLOCK_CHECK_INTERVAL.
+    pub fn check_clock(&mut self, bex: &Binance) {
+        if let Some(last) = self.last_clock_check {
+            if last.elapsed() < CLOCK_CHECK_INTERVAL {
+                return;
+            }
+        }
+        self.last_clock_check = Some(Instant::now());
+
+        let before = Self::local_ms();
+        let server = match bex.get_server_time() {
+            Ok(st) => st,
+            Err(code) => {
+                error!("{} failed to get server time: {}", self.symbol, code);
+                return;
+            }
+        };
+        self.offset_ms = clock_offset(before, Self::local_ms(), server);
+
+        self.clock_drifted = self.offset_ms.unsigned_abs() > self.limits.max_clock_drift_ms;
+        if self.clock_drifted {
+            self.pause(format!("clock is {}ms out from the server", self.offset_ms));
+        }
+    }
+
+    // Check a closed candle has arrived in time, true if one is overdue. Candles which
+    // close on activity rather than time, with an interval of 0, are never overdue, a
+    // quiet market is not a stalled stream, only the lag of their trades is watched.
+    pub fn check_candle_due(&mut self, last_close_time: u64) -> bool {
+        if self.interval_ms == 0 {
+            return false;
+        }
+        let now = self.server_ms();
+        if candle_overdue(now, last_close_time, self.interval_ms, self.limits.grace_ms) {
+            self.pause(format!(
+                "no closed candle for {}s",
+                (now - last_close_time) / 1000
+            ));
+            return true;
+        }
+        false
+    }
+
+    // A closed candle has arrived, 'event_time' is when the exchange sent it.
+    pub fn candle_received(&mut self, event_time: u64) {
+        let lag = self.server_ms().saturating_sub(event_time);
+        if lag > self.limits.max_event_lag_ms {
+            self.pause(format!("candle arrived {}ms late", lag));
+            return;
+        }
+
+        if !self.clock_drifted {
+            if let Some(reason) = self.paused.take() {
+                info!("{} trading resumed after: {}", self.symbol, reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overdue_candles_and_offsets() {
+        // 1m candles with a 30s grace.
+        assert!(!candle_overdue(59_999 + 90_000, 59_999, 60_000, 30_000));
+        assert!(candle_overdue(59_999 + 90_001, 59_999, 60_000, 30_000));
+
+        // 200ms round trip, our clock 1s fast.
+        assert_eq!(clock_offset(10_000, 10_200, 9_100), 1000);
+        assert_eq!(clock_offset(10_000, 10_200, 11_100), -1000);
+    }
+
+    #[test]
+    fn kline_intervals() {
+        assert_eq!(kline_interval_ms("1m"), 60_000);
+        assert_eq!(kline_interval_ms("4h"), 4 * 60 * 60 * 1000);
+        assert_eq!(kline_interval_ms("1d"), 24 * 60 * 60 * 1000);
+        assert_eq!(kline_interval_ms("1w"), 7 * 24 * 60 * 60 * 1000);
+        assert_eq!(kline_interval_ms("1M"), 31 * 24 * 60 * 60 * 1000);
+        assert_eq!(kline_interval_ms("tick:100"), 0);
+        assert_eq!(kline_interval_ms(""), 0);
+    }
+
+    #[test]
+    fn activity_candles_never_overdue() {
+        let mut wd = DataWatchdog::new("BTCUSDT", WatchdogLimits::default(), 0);
+        assert!(!wd.check_candle_due(0));
+        assert!(wd.trading_allowed());
+
+        let mut wd = DataWatchdog::new("BTCUSDT", WatchdogLimits::default(), 60_000);
+        assert!(wd.check_candle_due(0));
+        assert!(!wd.trading_allowed());
+    }
+}